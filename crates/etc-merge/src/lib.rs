@@ -1,21 +1,30 @@
 //! Lib for /etc merge
+//!
+//! With the `serde` feature enabled, [`Diff`] can be serialized, e.g. as JSON for other tools
+//! to consume.
 
 #![allow(dead_code)]
 
 use fn_error_context::context;
 use std::cell::RefCell;
-use std::collections::BTreeMap;
-use std::ffi::OsStr;
-use std::io::BufReader;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
 use std::io::Write;
+use std::io::{BufReader, Read};
+use std::num::NonZeroUsize;
 use std::os::fd::{AsFd, AsRawFd};
 use std::os::unix::ffi::OsStrExt;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::thread::ScopedJoinHandle;
 
 use anyhow::Context;
 use cap_std_ext::cap_std;
-use cap_std_ext::cap_std::fs::{Dir as CapStdDir, MetadataExt, Permissions, PermissionsExt};
+use cap_std_ext::cap_std::fs::{
+    Dir as CapStdDir, DirEntry, File, FileTypeExt, MetadataExt, Permissions, PermissionsExt,
+};
 use cap_std_ext::dirext::CapStdExtDirExt;
 use composefs::fsverity::{FsVerityHashValue, Sha256HashValue, Sha512HashValue};
 use composefs::generic_tree::{Directory, Inode, Leaf, LeafContent, Stat};
@@ -25,23 +34,312 @@ use rustix::fs::{
 };
 
 /// Metadata associated with a file, directory, or symlink entry.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct CustomMetadata {
-    /// A SHA256 sum representing the file contents.
+    /// A digest of the file contents, as computed by the [`ContentHasher`] used for the scan.
     content_hash: String,
     /// Optional verity for the file
     verity: Option<String>,
+    /// Set if the file could not be read, in which case there is no digest
+    unreadable: Option<std::io::ErrorKind>,
+    /// The device and inode number, only recorded for files with more than one hardlink
+    inode: Option<InodeKey>,
 }
 
+/// The `(st_dev, st_ino)` pair identifying an inode
+type InodeKey = (u64, u64);
+
 impl CustomMetadata {
     fn new(content_hash: String, verity: Option<String>) -> Self {
         Self {
             content_hash,
             verity,
+            unreadable: None,
+            inode: None,
+        }
+    }
+
+    fn unreadable(kind: std::io::ErrorKind) -> Self {
+        Self {
+            content_hash: String::new(),
+            verity: None,
+            unreadable: Some(kind),
+            inode: None,
+        }
+    }
+
+    /// Whether the file had fsverity enabled when it was scanned
+    pub fn is_verity_enabled(&self) -> bool {
+        self.verity.is_some()
+    }
+
+    /// The hex encoded fsverity digest of the file, if it had fsverity enabled
+    pub fn verity(&self) -> Option<&str> {
+        self.verity.as_deref()
+    }
+}
+
+/// Computes the content digest of regular files without fsverity enabled.
+///
+/// Digests are only ever compared against other digests computed by the same hasher, so any
+/// algorithm works as long as the same one is used for all the trees being compared.
+pub trait ContentHasher: Sync {
+    /// Returns a string representation of the digest of everything read from `reader`
+    fn hash(&self, reader: &mut dyn Read) -> anyhow::Result<String>;
+}
+
+impl<F> ContentHasher for F
+where
+    F: Fn(&mut dyn Read) -> anyhow::Result<String> + Sync,
+{
+    fn hash(&self, reader: &mut dyn Read) -> anyhow::Result<String> {
+        self(reader)
+    }
+}
+
+/// The default [`ContentHasher`], hex encoded SHA256
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Sha256ContentHasher;
+
+impl ContentHasher for Sha256ContentHasher {
+    fn hash(&self, reader: &mut dyn Read) -> anyhow::Result<String> {
+        let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())?;
+        std::io::copy(reader, &mut hasher)?;
+
+        Ok(hex::encode(hasher.finish()?))
+    }
+}
+
+/// Options controlling how [`traverse_etc_with`] scans the `/etc` trees
+#[derive(Clone, Copy)]
+pub struct TraverseOptions<'a> {
+    hasher: &'a dyn ContentHasher,
+    measure_verity: Option<MeasureVerityFn>,
+    exclude: Option<&'a ExcludeFn>,
+    jobs: Option<NonZeroUsize>,
+    progress: Option<&'a ProgressFn>,
+    cache: Option<&'a HashCache>,
+}
+
+/// A predicate deciding whether a path, relative to the root of `/etc`, should be skipped
+pub type ExcludeFn = dyn Fn(&Path) -> bool + Sync;
+
+/// Called with the path, relative to the root of `/etc`, of each entry once it's scanned
+pub type ProgressFn = dyn Fn(&Path) + Sync;
+
+impl std::fmt::Debug for TraverseOptions<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TraverseOptions")
+            .field("verity_algorithm_pinned", &self.measure_verity.is_some())
+            .field("has_exclude", &self.exclude.is_some())
+            .field("jobs", &self.jobs)
+            .field("has_progress", &self.progress.is_some())
+            .field("has_cache", &self.cache.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for TraverseOptions<'_> {
+    fn default() -> Self {
+        Self {
+            hasher: &Sha256ContentHasher,
+            measure_verity: None,
+            exclude: None,
+            jobs: None,
+            progress: None,
+            cache: None,
+        }
+    }
+}
+
+impl<'a> TraverseOptions<'a> {
+    /// Options matching [`traverse_etc`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Hash the contents of files without fsverity using `hasher` instead of SHA256
+    pub fn hasher(mut self, hasher: &'a dyn ContentHasher) -> Self {
+        self.hasher = hasher;
+        self
+    }
+
+    /// Only measure fsverity digests using the algorithm `H`, which should match the one the
+    /// composefs repository was configured with.
+    ///
+    /// By default the algorithm is detected from the files themselves. Once pinned, finding a
+    /// file with verity enabled using a different algorithm is an error.
+    pub fn verity_algorithm<H: FsVerityHashValue>(mut self) -> Self {
+        self.measure_verity = Some(measure_verity_as::<H>);
+        self
+    }
+
+    /// Skip every path for which `exclude` returns true in all three trees, e.g. lock files or
+    /// caches written at runtime.
+    ///
+    /// Excluded paths are neither reported in the [`Diff`] nor merged. If a directory is
+    /// excluded, nothing below it is scanned.
+    pub fn exclude(mut self, exclude: &'a ExcludeFn) -> Self {
+        self.exclude = Some(exclude);
+        self
+    }
+
+    /// Measure and hash the files using at most `jobs` threads, shared between all the trees
+    /// being scanned.
+    ///
+    /// Defaults to the available parallelism. The result does not depend on this.
+    pub fn jobs(mut self, jobs: NonZeroUsize) -> Self {
+        self.jobs = Some(jobs);
+        self
+    }
+
+    /// Calls `progress` for every entry of the three trees once it has been scanned, which for
+    /// regular files includes hashing them. Excluded entries are not reported.
+    ///
+    /// The trees are scanned concurrently, so `progress` can be called from multiple threads at
+    /// once, and the order of the calls is unspecified. It has no effect on the result.
+    pub fn progress(mut self, progress: &'a ProgressFn) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Reuse the digests in `cache` for files which didn't change since they were last scanned,
+    /// and record the digests of the others in it.
+    ///
+    /// The cache must only be shared between scans using the same hasher and verity algorithm.
+    pub fn cache(mut self, cache: &'a HashCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    fn report_progress(&self, path: &Path) {
+        if let Some(progress) = self.progress {
+            progress(path);
+        }
+    }
+
+    fn effective_jobs(&self) -> usize {
+        self.jobs
+            .or_else(|| std::thread::available_parallelism().ok())
+            .map_or(1, NonZeroUsize::get)
+    }
+}
+
+type MeasureVerityFn = fn(&File) -> anyhow::Result<Option<String>>;
+
+/// Digests of previously scanned files, for repeated scans to skip measuring or hashing files
+/// which didn't change, see [`TraverseOptions::cache`].
+///
+/// Files are identified by their path relative to the root of the tree, device and inode
+/// number. A cached digest is only used if the size, modification and change times of the file
+/// are still the same as when it was computed. Files which couldn't be read are not cached, and
+/// neither are files modified or changed at or after the start of the scan, as these could still
+/// be changed again without their timestamps changing.
+#[derive(Debug, Default)]
+pub struct HashCache {
+    entries: Mutex<HashMap<(PathBuf, InodeKey), CachedDigest>>,
+}
+
+#[derive(Debug)]
+struct CachedDigest {
+    stamp: FileStamp,
+    content_hash: String,
+    verity: Option<String>,
+}
+
+/// What has to stay the same for a file to be considered unchanged
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FileStamp {
+    size: u64,
+    mtime: (i64, i64),
+    ctime: (i64, i64),
+}
+
+impl FileStamp {
+    fn of(meta: &cap_std::fs::Metadata) -> Self {
+        Self {
+            size: meta.size(),
+            mtime: (meta.mtime(), meta.mtime_nsec()),
+            ctime: (meta.ctime(), meta.ctime_nsec()),
+        }
+    }
+
+    /// Whether both the modification and change times are strictly before `time`, as seconds
+    /// and nanoseconds since the epoch
+    fn is_older_than(&self, time: (i64, i64)) -> bool {
+        self.mtime < time && self.ctime < time
+    }
+}
+
+/// `time` as seconds and nanoseconds since the epoch, to compare with a [`FileStamp`]
+fn epoch_timestamp(time: std::time::SystemTime) -> (i64, i64) {
+    let since_epoch = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default();
+
+    (
+        since_epoch.as_secs() as i64,
+        since_epoch.subsec_nanos().into(),
+    )
+}
+
+impl HashCache {
+    /// An empty cache
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of files with a cached digest
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    /// Whether no digest is cached
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Forgets every cached digest, e.g. to drop those of files which no longer exist
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    fn get(&self, file: &PendingFile) -> Option<CustomMetadata> {
+        let entries = self.entries.lock().unwrap();
+        let cached = entries.get(&(file.path.clone(), file.id))?;
+
+        (cached.stamp == file.stamp)
+            .then(|| CustomMetadata::new(cached.content_hash.clone(), cached.verity.clone()))
+    }
+
+    /// Caches the digest of `file`, unless it might have been modified during the scan which
+    /// started at `scan_start`
+    fn insert(&self, file: &PendingFile, metadata: &CustomMetadata, scan_start: (i64, i64)) {
+        // A file modified within the timestamp granularity of the scan could be changed again
+        // without its stamp changing, so the cached digest could be stale. This is the same
+        // "racy" rule git uses for its index.
+        if metadata.unreadable.is_some() || !file.stamp.is_older_than(scan_start) {
+            return;
         }
+
+        self.entries.lock().unwrap().insert(
+            (file.path.clone(), file.id),
+            CachedDigest {
+                stamp: file.stamp,
+                content_hash: metadata.content_hash.clone(),
+                verity: metadata.verity.clone(),
+            },
+        );
     }
 }
 
+/// Measures the fsverity digest of `file` using the algorithm `H`, if it has verity enabled
+fn measure_verity_as<H: FsVerityHashValue>(file: &File) -> anyhow::Result<Option<String>> {
+    let verity = composefs::fsverity::measure_verity_opt::<H>(file)?;
+
+    Ok(verity.map(|v| v.to_hex()))
+}
+
 type Xattrs = RefCell<BTreeMap<Box<OsStr>, Box<[u8]>>>;
 
 struct MyStat(Stat);
@@ -58,6 +356,25 @@ impl From<(&cap_std::fs::Metadata, Xattrs)> for MyStat {
     }
 }
 
+/// xattrs which overlayfs uses to mark a directory as opaque, i.e. as replacing rather than
+/// merging with the same directory in lower layers
+const OVERLAY_OPAQUE_XATTRS: &[&str] = &["trusted.overlay.opaque", "user.overlay.opaque"];
+
+/// Whether `stat` belongs to an overlayfs opaque directory
+fn is_opaque(stat: &Stat) -> bool {
+    let xattrs = stat.xattrs.borrow();
+
+    OVERLAY_OPAQUE_XATTRS
+        .iter()
+        .any(|attr| xattrs.get(OsStr::new(attr)).is_some_and(|v| **v == *b"y"))
+}
+
+/// Whether `content` is an overlayfs whiteout, i.e. a character device with device number 0/0,
+/// which marks the deletion of the same path in lower layers
+fn is_whiteout(content: &LeafContent<CustomMetadata>) -> bool {
+    matches!(content, LeafContent::CharacterDevice(0))
+}
+
 fn stat_eq_ignore_mtime(this: &Stat, other: &Stat) -> bool {
     if this.st_uid != other.st_uid {
         return false;
@@ -78,214 +395,787 @@ fn stat_eq_ignore_mtime(this: &Stat, other: &Stat) -> bool {
     return true;
 }
 
+fn metadata_changed(old: &Stat, new: &Stat, opts: &DiffOptions) -> bool {
+    !stat_eq_ignore_mtime(old, new) || (opts.compare_mtime && old.st_mtim_sec != new.st_mtim_sec)
+}
+
 /// Represents the differences between two directory trees.
-#[derive(Debug)]
+///
+/// Every list is sorted by path, which puts directories right before their contents.
+///
+/// When serialized, every path is a UTF-8 string, or an array of its raw bytes if it isn't
+/// valid UTF-8.
+#[derive(Debug, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 pub struct Diff {
     /// Paths that exist in the current /etc but not in the pristine
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::paths"))]
     added: Vec<PathBuf>,
     /// Paths that exist in both pristine and current /etc but differ in metadata
     /// (e.g., file contents, permissions, symlink targets)
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::paths"))]
     modified: Vec<PathBuf>,
+    /// Subset of `modified` whose contents (file data, symlink target) changed
+    /// while the metadata stayed the same
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::paths"))]
+    content_changed: Vec<PathBuf>,
+    /// Subset of `modified` whose metadata (mode, ownership, xattrs) changed
+    /// while the contents stayed the same
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::paths"))]
+    metadata_changed: Vec<PathBuf>,
+    /// Subset of `modified` where both contents and metadata changed
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::paths"))]
+    both_changed: Vec<PathBuf>,
     /// Paths that exist in the pristine /etc but not in the current one
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::paths"))]
     removed: Vec<PathBuf>,
+    /// Subset of `modified` which changed between kinds of non-directory entries, e.g. a regular
+    /// file which is now a symlink.  Changes to or from a directory are in `replaced` instead.
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::paths"))]
+    type_changed: Vec<PathBuf>,
+    /// Subset of `modified` which are symlinks in both trees, but point somewhere else now
+    retargeted: Vec<SymlinkChange>,
+    /// Subset of `modified` which are regular files in both trees, with fsverity enabled in only
+    /// one of them
+    verity_changed: Vec<VerityChange>,
+    /// Files which could not be read, so whether they changed is unknown. These are not in any
+    /// of the other lists, and are left alone when merging.
+    skipped: Vec<SkippedPath>,
+    /// Groups of paths which are hardlinks to the same file in the current /etc
+    hardlinks: Vec<Vec<PathBuf>>,
+    /// Groups of paths which were hardlinks to the same file in the pristine /etc, but aren't
+    /// anymore in the current one
+    diverged_hardlinks: Vec<Vec<PathBuf>>,
+    /// Paths which changed between being a directory and not being one
+    replaced: Vec<TreeReplacement>,
+    /// The root every path was prefixed with for display, see [`compute_diff_with_root`]
+    #[cfg_attr(feature = "serde", serde(skip))]
+    display_root: Option<PathBuf>,
 }
 
-fn collect_all_files(
-    root: &Directory<CustomMetadata>,
-    current_path: PathBuf,
-    files: &mut Vec<PathBuf>,
-) {
-    fn collect(
-        root: &Directory<CustomMetadata>,
-        mut current_path: PathBuf,
-        files: &mut Vec<PathBuf>,
-    ) {
-        for (path, inode) in root.sorted_entries() {
-            current_path.push(path);
-
-            files.push(current_path.clone());
-
-            if let Inode::Directory(dir) = inode {
-                collect(dir, current_path.clone(), files);
-            }
+/// The kind of a filesystem entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum EntryKind {
+    /// A directory
+    Directory,
+    /// A regular file
+    File,
+    /// A symbolic link
+    Symlink,
+    /// Anything else, e.g. a device node
+    Other,
+}
 
-            current_path.pop();
+impl EntryKind {
+    fn of(inode: &Inode<CustomMetadata>) -> Self {
+        match inode {
+            Inode::Directory(..) => EntryKind::Directory,
+            Inode::Leaf(leaf) => Self::of_leaf(&leaf.content),
         }
     }
 
-    collect(root, current_path, files);
+    fn of_leaf(content: &LeafContent<CustomMetadata>) -> Self {
+        match content {
+            LeafContent::Regular(..) => EntryKind::File,
+            LeafContent::Symlink(..) => EntryKind::Symlink,
+            _ => EntryKind::Other,
+        }
+    }
 }
 
-#[context("Getting deletions")]
-fn get_deletions(
-    pristine: &Directory<CustomMetadata>,
-    current: &Directory<CustomMetadata>,
-    mut current_path: PathBuf,
-    diff: &mut Diff,
-) -> anyhow::Result<()> {
-    for (file_name, inode) in pristine.sorted_entries() {
-        current_path.push(file_name);
+/// A path which was turned from a directory into a file or symlink, or vice versa.
+///
+/// Merging this means replacing the whole subtree at `path`, rather than handling each file
+/// inside the directory on its own.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TreeReplacement {
+    /// The path, relative to /etc
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::path"))]
+    pub path: PathBuf,
+    /// What the path was in the pristine /etc
+    pub from_kind: EntryKind,
+    /// What the path is in the current /etc
+    pub to_kind: EntryKind,
+}
 
-        match inode {
-            Inode::Directory(pristine_dir) => {
-                match current.get_directory(file_name) {
-                    Ok(curr_dir) => {
-                        get_deletions(pristine_dir, curr_dir, current_path.clone(), diff)?
-                    }
+/// A symlink whose target changed between the pristine and current /etc
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SymlinkChange {
+    /// The path of the symlink, relative to /etc
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::path"))]
+    pub path: PathBuf,
+    /// What the symlink pointed to in the pristine /etc
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::path"))]
+    pub old_target: PathBuf,
+    /// What the symlink points to in the current /etc
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::path"))]
+    pub new_target: PathBuf,
+}
 
-                    Err(ImageError::NotFound(..)) => {
-                        // Directory was deleted
-                        diff.removed.push(current_path.clone());
-                    }
+/// A regular file which had fsverity enabled in only one of the pristine and current /etc
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct VerityChange {
+    /// The path, relative to /etc
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::path"))]
+    pub path: PathBuf,
+    /// The fsverity digest of the file in the pristine /etc, if it had fsverity enabled
+    pub old_verity: Option<String>,
+    /// The fsverity digest of the file in the current /etc, if it has fsverity enabled
+    pub new_verity: Option<String>,
+}
 
-                    Err(ImageError::NotADirectory(..)) => {
-                        // Already tracked in modifications
-                    }
+/// How a single path changed in a [`Diff`], see [`Diff::compose`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PathChange {
+    Added,
+    Modified {
+        content: bool,
+        metadata: bool,
+        type_changed: bool,
+    },
+    Removed,
+    Replaced {
+        from_kind: EntryKind,
+        to_kind: EntryKind,
+    },
+}
 
-                    Err(e) => Err(e)?,
-                }
-            }
+/// How a single path changed, see [`Diff::changes`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Exists in the current /etc but not in the pristine one
+    Added,
+    /// Exists in the pristine /etc but not in the current one
+    Removed,
+    /// The contents changed but the metadata didn't. For a directory, this means something inside
+    /// it changed and it is missing from the new /etc.
+    ContentModified,
+    /// The metadata (mode, ownership, xattrs) changed but the contents didn't
+    MetadataModified,
+    /// Both the contents and the metadata changed
+    ContentAndMetadataModified,
+    /// Changed between kinds of non-directory entries, e.g. a regular file which is now a symlink
+    TypeChanged,
+    /// A regular file with fsverity enabled in only one of the trees
+    VerityChanged,
+    /// Changed between being a directory and not being one, see [`TreeReplacement`]
+    Replaced {
+        /// What the path was in the pristine /etc
+        from_kind: EntryKind,
+        /// What the path is in the current /etc
+        to_kind: EntryKind,
+    },
+}
 
-            Inode::Leaf(..) => match current.ref_leaf(file_name) {
-                Ok(..) => {
-                    // Empty as all additions/modifications are tracked earlier in `get_modifications`
-                }
+/// A file which could not be read while scanning /etc
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct SkippedPath {
+    /// The path, relative to /etc
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::path"))]
+    pub path: PathBuf,
+    /// Why reading it failed
+    #[cfg_attr(feature = "serde", serde(serialize_with = "ser::error_kind"))]
+    pub kind: std::io::ErrorKind,
+}
 
-                Err(ImageError::NotFound(..)) => {
-                    // File was deleted
-                    diff.removed.push(current_path.clone());
-                }
+impl Diff {
+    /// Records `path` as modified, classifying it by what changed
+    fn record_modification(&mut self, path: PathBuf, content: bool, metadata: bool) {
+        match (content, metadata) {
+            (false, false) => return,
+            (true, false) => self.content_changed.push(path.clone()),
+            (false, true) => self.metadata_changed.push(path.clone()),
+            (true, true) => self.both_changed.push(path.clone()),
+        }
 
-                Err(ImageError::IsADirectory(..)) => {
-                    // Already tracked in modifications
-                }
+        self.modified.push(path);
+    }
 
-                Err(e) => Err(e).context(format!("{file_name:?}"))?,
-            },
+    /// Records `path` as skipped, if `content` is a file which could not be read
+    fn record_skipped(&mut self, path: PathBuf, content: &LeafContent<CustomMetadata>) {
+        if let Some(kind) = unreadable(content) {
+            self.skipped.push(SkippedPath { path, kind });
         }
+    }
 
-        current_path.pop();
+    /// Paths that exist in the current /etc but not in the pristine one
+    pub fn added(&self) -> &[PathBuf] {
+        &self.added
     }
 
-    Ok(())
-}
+    /// Paths that exist in both the pristine and current /etc but differ
+    pub fn modified(&self) -> &[PathBuf] {
+        &self.modified
+    }
 
-// 1. Files in the currently booted deployment’s /etc which were modified from the default /usr/etc (of the same deployment) are retained.
-//
-// 2. Files in the currently booted deployment’s /etc which were not modified from the default /usr/etc (of the same deployment)
-// are upgraded to the new defaults from the new deployment’s /usr/etc.
+    /// The subset of [`Self::modified`] where only the contents changed
+    pub fn content_changed(&self) -> &[PathBuf] {
+        &self.content_changed
+    }
 
-// Modifications
-// 1. File deleted from new /etc
-// 2. File added in new /etc
-//
-// 3. File modified in new /etc
-//    a. Content added/deleted
-//    b. Permissions/ownership changed
-//    c. Was a file but changed to directory/symlink etc or vice versa
-//    d. xattrs changed - we don't include this right now
-#[context("Getting modifications")]
-fn get_modifications(
-    pristine: &Directory<CustomMetadata>,
-    current: &Directory<CustomMetadata>,
-    new: &Directory<CustomMetadata>,
-    mut current_path: PathBuf,
-    diff: &mut Diff,
-) -> anyhow::Result<()> {
-    use composefs::generic_tree::LeafContent::*;
+    /// The subset of [`Self::modified`] where only the metadata changed
+    pub fn metadata_changed(&self) -> &[PathBuf] {
+        &self.metadata_changed
+    }
 
-    for (path, inode) in current.sorted_entries() {
-        current_path.push(path);
+    /// The subset of [`Self::modified`] where both contents and metadata changed
+    pub fn both_changed(&self) -> &[PathBuf] {
+        &self.both_changed
+    }
 
-        match inode {
-            Inode::Directory(curr_dir) => {
-                match pristine.get_directory(path) {
-                    Ok(old_dir) => {
-                        if !stat_eq_ignore_mtime(&curr_dir.stat, &old_dir.stat) {
-                            // Directory permissions/owner modified
-                            diff.modified.push(current_path.clone());
-                        }
+    /// Paths that exist in the pristine /etc but not in the current one
+    pub fn removed(&self) -> &[PathBuf] {
+        &self.removed
+    }
 
-                        let total_added = diff.added.len();
-                        let total_modified = diff.modified.len();
+    /// The subset of [`Self::modified`] which changed between kinds of non-directory entries
+    pub fn type_changed(&self) -> &[PathBuf] {
+        &self.type_changed
+    }
 
-                        get_modifications(old_dir, &curr_dir, new, current_path.clone(), diff)?;
+    /// The subset of [`Self::modified`] which are symlinks pointing somewhere else now, along
+    /// with their old and new targets
+    pub fn retargeted(&self) -> &[SymlinkChange] {
+        &self.retargeted
+    }
 
-                        // This directory or its contents were modified/added
-                        // Check if the new directory was deleted from new_etc
-                        // If it was, we want to add the directory back
-                        if new.get_directory_opt(&current_path.as_os_str())?.is_none() {
-                            if diff.added.len() != total_added {
-                                diff.added.insert(total_added, current_path.clone());
-                            } else if diff.modified.len() != total_modified {
-                                diff.modified.insert(total_modified, current_path.clone());
-                            }
-                        }
-                    }
+    /// The subset of [`Self::modified`] which had fsverity enabled or disabled, along with
+    /// their old and new digests
+    pub fn verity_changed(&self) -> &[VerityChange] {
+        &self.verity_changed
+    }
 
-                    Err(ImageError::NotFound(..)) => {
-                        // Dir not found in original /etc, dir was added
-                        diff.added.push(current_path.clone());
+    /// Files which could not be read in the pristine or current /etc, and were left out of
+    /// the diff
+    pub fn skipped(&self) -> &[SkippedPath] {
+        &self.skipped
+    }
 
-                        // Also add every file inside that dir
-                        collect_all_files(&curr_dir, current_path.clone(), &mut diff.added);
-                    }
+    /// Groups of paths which are hardlinks to the same file in the current /etc. Merging
+    /// recreates the links between the members of a group which are copied over.
+    pub fn hardlinks(&self) -> &[Vec<PathBuf>] {
+        &self.hardlinks
+    }
 
-                    Err(ImageError::NotADirectory(..)) => {
-                        // Some directory was changed to a file/symlink
-                        // This should be counted in the diff, but we don't really merge this
-                        diff.modified.push(current_path.clone());
-                    }
+    /// Groups of paths which were hardlinks to the same file in the pristine /etc, but no longer
+    /// all are in the current one. Only paths which still exist are included.
+    pub fn diverged_hardlinks(&self) -> &[Vec<PathBuf>] {
+        &self.diverged_hardlinks
+    }
 
-                    Err(e) => Err(e)?,
-                }
-            }
+    /// Paths which changed between being a directory and not being one
+    pub fn replaced(&self) -> &[TreeReplacement] {
+        &self.replaced
+    }
 
-            Inode::Leaf(leaf) => match pristine.ref_leaf(path) {
-                Ok(old_leaf) => {
-                    if !stat_eq_ignore_mtime(&old_leaf.stat, &leaf.stat) {
-                        diff.modified.push(current_path.clone());
-                        current_path.pop();
-                        continue;
-                    }
+    /// The root every path in this diff was prefixed with, see [`compute_diff_with_root`]
+    pub fn display_root(&self) -> Option<&Path> {
+        self.display_root.as_deref()
+    }
 
-                    match (&old_leaf.content, &leaf.content) {
-                        (Regular(old_meta), Regular(current_meta)) => {
-                            if old_meta.content_hash != current_meta.content_hash {
-                                // File modified in some way
-                                diff.modified.push(current_path.clone());
-                            }
-                        }
+    /// Prefixes every path in this diff with `root`
+    fn prefix_paths(&mut self, root: &Path) {
+        let prefix = |path: &mut PathBuf| *path = root.join(&*path);
+
+        for paths in [
+            &mut self.added,
+            &mut self.modified,
+            &mut self.content_changed,
+            &mut self.metadata_changed,
+            &mut self.both_changed,
+            &mut self.removed,
+            &mut self.type_changed,
+        ] {
+            paths.iter_mut().for_each(prefix);
+        }
 
-                        (Symlink(old_link), Symlink(current_link)) => {
-                            if old_link != current_link {
-                                // Symlink modified in some way
-                                diff.modified.push(current_path.clone());
-                            }
-                        }
+        for group in self
+            .hardlinks
+            .iter_mut()
+            .chain(&mut self.diverged_hardlinks)
+        {
+            group.iter_mut().for_each(prefix);
+        }
 
-                        (Symlink(..), Regular(..)) | (Regular(..), Symlink(..)) => {
-                            // File changed to symlink or vice-versa
-                            diff.modified.push(current_path.clone());
-                        }
+        self.retargeted.iter_mut().for_each(|c| prefix(&mut c.path));
+        self.verity_changed
+            .iter_mut()
+            .for_each(|c| prefix(&mut c.path));
+        self.skipped.iter_mut().for_each(|s| prefix(&mut s.path));
+        self.replaced.iter_mut().for_each(|r| prefix(&mut r.path));
+
+        self.display_root = Some(root.to_owned());
+    }
+
+    /// Sorts every list by path
+    fn sort(&mut self) {
+        for paths in [
+            &mut self.added,
+            &mut self.modified,
+            &mut self.content_changed,
+            &mut self.metadata_changed,
+            &mut self.both_changed,
+            &mut self.removed,
+            &mut self.type_changed,
+        ] {
+            paths.sort();
+        }
+
+        self.retargeted.sort_by(|a, b| a.path.cmp(&b.path));
+        self.verity_changed.sort_by(|a, b| a.path.cmp(&b.path));
+        self.skipped.sort_by(|a, b| a.path.cmp(&b.path));
+        self.replaced.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    /// Whether nothing changed between the pristine and current /etc, not counting the
+    /// [skipped](Self::skipped) files
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.modified.is_empty()
+            && self.removed.is_empty()
+            && self.replaced.is_empty()
+            && self.diverged_hardlinks.is_empty()
+    }
+
+    /// How each path in this diff changed
+    fn path_changes(&self) -> BTreeMap<&Path, PathChange> {
+        let type_changed: HashSet<&Path> = self.type_changed.iter().map(PathBuf::as_path).collect();
+        let content_changed: HashSet<&Path> =
+            self.content_changed.iter().map(PathBuf::as_path).collect();
+        let metadata_changed: HashSet<&Path> =
+            self.metadata_changed.iter().map(PathBuf::as_path).collect();
+        let both_changed: HashSet<&Path> = self.both_changed.iter().map(PathBuf::as_path).collect();
+        let mut changes = BTreeMap::new();
+
+        for path in &self.added {
+            changes.insert(path.as_path(), PathChange::Added);
+        }
+
+        for path in &self.removed {
+            changes.insert(path.as_path(), PathChange::Removed);
+        }
+
+        for path in &self.modified {
+            let path = path.as_path();
+
+            changes.insert(
+                path,
+                PathChange::Modified {
+                    content: content_changed.contains(path) || both_changed.contains(path),
+                    metadata: metadata_changed.contains(path) || both_changed.contains(path),
+                    type_changed: type_changed.contains(path),
+                },
+            );
+        }
+
+        for replacement in &self.replaced {
+            changes.insert(
+                replacement.path.as_path(),
+                PathChange::Replaced {
+                    from_kind: replacement.from_kind,
+                    to_kind: replacement.to_kind,
+                },
+            );
+        }
+
+        changes
+    }
+
+    /// How each path in this diff changed, as a single map rather than the separate lists
+    ///
+    /// Every path in [`Self::added`], [`Self::modified`], [`Self::removed`] and
+    /// [`Self::replaced`] has exactly one entry. A type change takes precedence over a verity
+    /// change, which takes precedence over the contents and metadata changing.
+    /// [Skipped](Self::skipped) files and hardlink changes are not included.
+    pub fn changes(&self) -> BTreeMap<PathBuf, ChangeKind> {
+        let verity_changed: HashSet<&Path> = self
+            .verity_changed
+            .iter()
+            .map(|c| c.path.as_path())
+            .collect();
+
+        self.path_changes()
+            .into_iter()
+            .map(|(path, change)| {
+                let kind = match change {
+                    PathChange::Added => ChangeKind::Added,
+                    PathChange::Removed => ChangeKind::Removed,
+                    PathChange::Replaced { from_kind, to_kind } => {
+                        ChangeKind::Replaced { from_kind, to_kind }
+                    }
+                    PathChange::Modified {
+                        type_changed: true, ..
+                    } => ChangeKind::TypeChanged,
+                    PathChange::Modified { .. } if verity_changed.contains(path) => {
+                        ChangeKind::VerityChanged
+                    }
+                    PathChange::Modified {
+                        content: true,
+                        metadata: true,
+                        ..
+                    } => ChangeKind::ContentAndMetadataModified,
+                    PathChange::Modified { metadata: true, .. } => ChangeKind::MetadataModified,
+                    PathChange::Modified { .. } => ChangeKind::ContentModified,
+                };
+
+                (path.to_owned(), kind)
+            })
+            .collect()
+    }
+
+    /// Combines this diff with `later`, a diff computed against the current /etc of this one,
+    /// into a single diff from the pristine /etc of this one to the current /etc of `later`.
+    ///
+    /// For each path:
+    ///
+    /// - Added, then removed cancels out
+    /// - Added, then modified or replaced stays added
+    /// - Modified, then modified collapses into one modification of everything that changed in
+    ///   either diff, even if it was changed back
+    /// - Removed, then added becomes modified, as the new contents are unknown to be the same
+    /// - Anything, then removed becomes removed
+    /// - Replaced, then replaced back is modified
+    ///
+    /// Changes only in one of the diffs are kept as is, as are [skipped](Self::skipped) files,
+    /// which are left out of every other change. Hardlinks are those of `later`, and fsverity
+    /// digests are those recorded when fsverity was enabled or disabled.
+    ///
+    /// This is not named `merge` so as not to be confused with [`merge`], which applies a diff.
+    pub fn compose(&self, later: &Diff) -> anyhow::Result<Diff> {
+        use PathChange::*;
+
+        if self.display_root != later.display_root {
+            anyhow::bail!(
+                "Cannot compose diffs prefixed with {:?} and {:?}",
+                self.display_root,
+                later.display_root
+            );
+        }
+
+        let mut diff = Diff {
+            display_root: self.display_root.clone(),
+            ..Default::default()
+        };
 
-                        (a, b) => {
-                            unreachable!("{a:?} modified to {b:?}")
+        let mut seen = HashSet::new();
+        diff.skipped = self
+            .skipped
+            .iter()
+            .chain(&later.skipped)
+            .filter(|s| seen.insert(s.path.as_path()))
+            .cloned()
+            .collect();
+
+        let earlier_changes = self.path_changes();
+        let later_changes = later.path_changes();
+
+        let paths: BTreeSet<&Path> = earlier_changes
+            .keys()
+            .chain(later_changes.keys())
+            .filter(|path| !seen.contains(*path))
+            .copied()
+            .collect();
+
+        for path in paths {
+            let change = match (earlier_changes.get(path), later_changes.get(path)) {
+                (Some(&change), None) | (None, Some(&change)) => change,
+                (None, None) => unreachable!("Every path is from one of the diffs"),
+
+                (Some(&a), Some(&b)) => match (a, b) {
+                    (Added, Removed) => continue,
+                    (Added, _) => Added,
+                    (_, Removed) => Removed,
+
+                    (Removed, Added) => Modified {
+                        content: true,
+                        metadata: true,
+                        type_changed: false,
+                    },
+
+                    (
+                        Modified {
+                            content: c1,
+                            metadata: m1,
+                            type_changed: t1,
+                        },
+                        Modified {
+                            content: c2,
+                            metadata: m2,
+                            type_changed: t2,
+                        },
+                    ) => Modified {
+                        content: c1 || c2,
+                        metadata: m1 || m2,
+                        type_changed: t1 || t2,
+                    },
+
+                    (replaced @ Replaced { .. }, Modified { .. }) => replaced,
+
+                    (Replaced { from_kind, .. }, Replaced { to_kind, .. })
+                        if (from_kind == EntryKind::Directory)
+                            == (to_kind == EntryKind::Directory) =>
+                    {
+                        Modified {
+                            content: true,
+                            metadata: true,
+                            type_changed: from_kind != to_kind,
                         }
                     }
+
+                    (Replaced { from_kind, .. }, Replaced { to_kind, .. }) => {
+                        Replaced { from_kind, to_kind }
+                    }
+
+                    // The diffs disagree on what the path was in between, trust the later one
+                    (_, b) => b,
+                },
+            };
+
+            diff.record_change(path, change, self, later);
+        }
+
+        let removed: HashSet<&Path> = diff.removed.iter().map(PathBuf::as_path).collect();
+
+        diff.diverged_hardlinks = self
+            .diverged_hardlinks
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .filter(|path| !removed.contains(path.as_path()))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .filter(|group| group.len() > 1)
+            .chain(later.diverged_hardlinks.iter().cloned())
+            .collect();
+        diff.diverged_hardlinks.sort();
+        diff.diverged_hardlinks.dedup();
+
+        diff.hardlinks = later.hardlinks.clone();
+
+        Ok(diff)
+    }
+
+    /// Records the composed `change` of `path`, see [`Self::compose`]
+    fn record_change(&mut self, path: &Path, change: PathChange, earlier: &Diff, later: &Diff) {
+        let path = path.to_owned();
+
+        match change {
+            PathChange::Added => self.added.push(path),
+            PathChange::Removed => self.removed.push(path),
+
+            PathChange::Replaced { from_kind, to_kind } => self.replaced.push(TreeReplacement {
+                path,
+                from_kind,
+                to_kind,
+            }),
+
+            PathChange::Modified {
+                content,
+                metadata,
+                type_changed,
+            } => {
+                let retargeted = |d: &Diff| d.retargeted.iter().find(|c| c.path == path).cloned();
+
+                match (retargeted(earlier), retargeted(later)) {
+                    (Some(a), Some(b)) if a.old_target != b.new_target => {
+                        self.retargeted.push(SymlinkChange {
+                            new_target: b.new_target,
+                            ..a
+                        })
+                    }
+                    (Some(change), None) | (None, Some(change)) => self.retargeted.push(change),
+                    _ => {}
                 }
 
-                Err(ImageError::IsADirectory(..)) => {
-                    // A directory was changed to a file
-                    diff.modified.push(current_path.clone());
+                let verity_changed =
+                    |d: &Diff| d.verity_changed.iter().find(|c| c.path == path).cloned();
+
+                match (verity_changed(earlier), verity_changed(later)) {
+                    (Some(a), Some(b)) if a.old_verity.is_some() != b.new_verity.is_some() => {
+                        self.verity_changed.push(VerityChange {
+                            new_verity: b.new_verity,
+                            ..a
+                        })
+                    }
+                    (Some(change), None) | (None, Some(change)) => self.verity_changed.push(change),
+                    _ => {}
+                }
+
+                if type_changed {
+                    self.type_changed.push(path.clone());
+                }
+
+                // Directories can be modified without either changing, when they are kept for
+                // their modified contents
+                match (content, metadata) {
+                    (false, false) => {}
+                    (true, false) => self.content_changed.push(path.clone()),
+                    (false, true) => self.metadata_changed.push(path.clone()),
+                    (true, true) => self.both_changed.push(path.clone()),
+                }
+
+                self.modified.push(path);
+            }
+        }
+    }
+
+    /// Counts the changes, e.g. for logging
+    pub fn summary(&self) -> DiffSummary {
+        DiffSummary {
+            added: self.added.len(),
+            modified: self.modified.len(),
+            removed: self.removed.len(),
+            replaced: self.replaced.len(),
+        }
+    }
+}
+
+/// The number of changes of each kind in a [`Diff`]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct DiffSummary {
+    /// See [`Diff::added`]
+    pub added: usize,
+    /// See [`Diff::modified`]
+    pub modified: usize,
+    /// See [`Diff::removed`]
+    pub removed: usize,
+    /// See [`Diff::replaced`]
+    pub replaced: usize,
+}
+
+impl std::fmt::Display for DiffSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} modified, {} removed, {} replaced",
+            self.added, self.modified, self.removed, self.replaced
+        )
+    }
+}
+
+#[cfg(feature = "serde")]
+mod ser {
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+
+    use serde::{Serialize, Serializer};
+
+    /// Serializes `path` as a string, falling back to an array of bytes if it isn't UTF-8
+    pub(super) fn path<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        match path.to_str() {
+            Some(path) => serializer.serialize_str(path),
+            None => serializer.collect_seq(path.as_os_str().as_bytes()),
+        }
+    }
+
+    pub(super) fn paths<S: Serializer>(
+        paths: &[PathBuf],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        struct SerializedPath<'a>(&'a Path);
+
+        impl Serialize for SerializedPath<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                path(self.0, serializer)
+            }
+        }
+
+        serializer.collect_seq(paths.iter().map(|p| SerializedPath(p)))
+    }
+
+    pub(super) fn error_kind<S: Serializer>(
+        kind: &std::io::ErrorKind,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(kind)
+    }
+}
+
+fn collect_all_files(
+    root: &Directory<CustomMetadata>,
+    current_path: PathBuf,
+    files: &mut Vec<PathBuf>,
+) {
+    fn collect(
+        root: &Directory<CustomMetadata>,
+        mut current_path: PathBuf,
+        files: &mut Vec<PathBuf>,
+    ) {
+        for (path, inode) in root.sorted_entries() {
+            if matches!(inode, Inode::Leaf(leaf) if is_whiteout(&leaf.content)) {
+                // Nothing to delete inside a newly added directory
+                continue;
+            }
+
+            current_path.push(path);
+
+            files.push(current_path.clone());
+
+            if let Inode::Directory(dir) = inode {
+                collect(dir, current_path.clone(), files);
+            }
+
+            current_path.pop();
+        }
+    }
+
+    collect(root, current_path, files);
+}
+
+#[context("Getting deletions")]
+fn get_deletions(
+    pristine: &Directory<CustomMetadata>,
+    current: &Directory<CustomMetadata>,
+    mut current_path: PathBuf,
+    diff: &mut Diff,
+) -> anyhow::Result<()> {
+    for (file_name, inode) in pristine.sorted_entries() {
+        current_path.push(file_name);
+
+        match inode {
+            Inode::Directory(pristine_dir) => {
+                match current.get_directory(file_name) {
+                    Ok(curr_dir) => {
+                        get_deletions(pristine_dir, curr_dir, current_path.clone(), diff)?
+                    }
+
+                    Err(ImageError::NotFound(..)) => {
+                        // Directory was deleted
+                        diff.removed.push(current_path.clone());
+                    }
+
+                    Err(ImageError::NotADirectory(..)) => {
+                        // Already tracked in modifications
+                    }
+
+                    Err(e) => Err(e)?,
+                }
+            }
+
+            Inode::Leaf(..) => match current.ref_leaf(file_name) {
+                Ok(..) => {
+                    // Empty as all additions/modifications are tracked earlier in `get_modifications`
                 }
 
                 Err(ImageError::NotFound(..)) => {
-                    // File not found in original /etc, file was added
-                    diff.added.push(current_path.clone());
+                    // File was deleted
+                    diff.removed.push(current_path.clone());
                 }
 
-                Err(e) => Err(e).context(format!("{path:?}"))?,
+                Err(ImageError::IsADirectory(..)) => {
+                    // Already tracked in modifications
+                }
+
+                Err(e) => Err(e).context(format!("{file_name:?}"))?,
             },
         }
 
@@ -295,595 +1185,3208 @@ fn get_modifications(
     Ok(())
 }
 
-/// Traverses and collects directory trees for three etc states.
-///
-/// Recursively walks through the given *pristine*, *current*, and *new* etc directories,
-/// building filesystem trees that capture files, directories, and symlinks.
-/// Device files, sockets, pipes etc are ignored
-///
-/// It is primarily used to prepare inputs for later diff computations and
-/// comparisons between different etc states.
-///
-/// # Arguments
-///
-/// * `pristine_etc` - The reference directory representing the unmodified version or current /etc.
-/// Usually this will be obtained by remounting the EROFS image to a temporary location
-///
-/// * `current_etc` - The current `/etc` directory
-///
-/// * `new_etc` - The directory representing the `/etc` directory for a new deployment. This will
-/// again be usually obtained by mounting the new EROFS image to a temporary location. If merging
-/// it will be necessary to make the `/etc` for the deployment writeable
-///
-/// # Returns
-///
-/// [`anyhow::Result`] containing a tuple of directory trees in the order:
-///
-/// 1. `pristine_etc_files` – Dirtree of the pristine etc state
-/// 2. `current_etc_files`  – Dirtree of the current etc state
-/// 3. `new_etc_files`      – Dirtree of the new etc state (if new_etc directory is passed)
-pub fn traverse_etc(
-    pristine_etc: &CapStdDir,
-    current_etc: &CapStdDir,
-    new_etc: Option<&CapStdDir>,
-) -> anyhow::Result<(
-    Directory<CustomMetadata>,
-    Directory<CustomMetadata>,
-    Option<Directory<CustomMetadata>>,
-)> {
-    let mut pristine_etc_files = Directory::new(Stat::uninitialized());
-    recurse_dir(pristine_etc, &mut pristine_etc_files)
-        .context(format!("Recursing {pristine_etc:?}"))?;
+// 1. Files in the currently booted deployment’s /etc which were modified from the default /usr/etc (of the same deployment) are retained.
+//
+// 2. Files in the currently booted deployment’s /etc which were not modified from the default /usr/etc (of the same deployment)
+// are upgraded to the new defaults from the new deployment’s /usr/etc.
 
-    let mut current_etc_files = Directory::new(Stat::uninitialized());
-    recurse_dir(current_etc, &mut current_etc_files)
-        .context(format!("Recursing {current_etc:?}"))?;
+// Modifications
+// 1. File deleted from new /etc
+// 2. File added in new /etc
+//
+// 3. File modified in new /etc
+//    a. Content added/deleted
+//    b. Permissions/ownership changed
+//    c. Was a file but changed to directory/symlink etc or vice versa
+//    d. xattrs changed, e.g. an SELinux relabel
+//
+// If the current /etc is the upper dir of an overlay, overlayfs whiteouts are treated as deletions
+// and opaque directories as replacing the pristine directory.
+#[context("Getting modifications")]
+fn get_modifications(
+    pristine: &Directory<CustomMetadata>,
+    current: &Directory<CustomMetadata>,
+    new: &Directory<CustomMetadata>,
+    mut current_path: PathBuf,
+    diff: &mut Diff,
+    opts: &DiffOptions,
+) -> anyhow::Result<()> {
+    use composefs::generic_tree::LeafContent::*;
 
-    let new_etc_files = match new_etc {
-        Some(new_etc) => {
-            let mut new_etc_files = Directory::new(Stat::uninitialized());
-            recurse_dir(new_etc, &mut new_etc_files).context(format!("Recursing {new_etc:?}"))?;
+    for (path, inode) in current.sorted_entries() {
+        current_path.push(path);
+
+        match inode {
+            Inode::Directory(curr_dir) => {
+                match pristine.get_directory(path) {
+                    Ok(old_dir) if is_opaque(&curr_dir.stat) => {
+                        // Directory replaces the pristine one. Anything which is only in the
+                        // pristine directory is picked up as removed by `get_deletions`
+                        diff.record_modification(current_path.clone(), true, true);
+
+                        get_modifications(
+                            old_dir,
+                            &curr_dir,
+                            new,
+                            current_path.clone(),
+                            diff,
+                            opts,
+                        )?;
+                    }
+
+                    Ok(old_dir) => {
+                        // Directory permissions/owner modified
+                        diff.record_modification(
+                            current_path.clone(),
+                            false,
+                            metadata_changed(&old_dir.stat, &curr_dir.stat, opts),
+                        );
+
+                        let total_added = diff.added.len();
+                        let total_modified = diff.modified.len();
+                        let total_replaced = diff.replaced.len();
+
+                        get_modifications(
+                            old_dir,
+                            &curr_dir,
+                            new,
+                            current_path.clone(),
+                            diff,
+                            opts,
+                        )?;
+
+                        // This directory or its contents were modified/added
+                        // Check if the new directory was deleted from new_etc
+                        // If it was, we want to add the directory back
+                        if new.get_directory_opt(&current_path.as_os_str())?.is_none() {
+                            if diff.added.len() != total_added {
+                                diff.added.insert(total_added, current_path.clone());
+                            } else if diff.modified.len() != total_modified
+                                || diff.replaced.len() != total_replaced
+                            {
+                                diff.modified.insert(total_modified, current_path.clone());
+                            }
+                        }
+                    }
+
+                    Err(ImageError::NotFound(..)) => {
+                        // Dir not found in original /etc, dir was added
+                        diff.added.push(current_path.clone());
+
+                        // Also add every file inside that dir
+                        collect_all_files(&curr_dir, current_path.clone(), &mut diff.added);
+                    }
+
+                    Err(ImageError::NotADirectory(..)) => {
+                        // Some file/symlink was changed to a directory
+                        let from_kind = pristine
+                            .lookup(path)
+                            .map(EntryKind::of)
+                            .unwrap_or(EntryKind::Other);
+
+                        diff.replaced.push(TreeReplacement {
+                            path: current_path.clone(),
+                            from_kind,
+                            to_kind: EntryKind::Directory,
+                        });
+
+                        // Everything inside the directory is new
+                        collect_all_files(&curr_dir, current_path.clone(), &mut diff.added);
+                    }
+
+                    Err(e) => Err(e)?,
+                }
+            }
+
+            Inode::Leaf(leaf) if is_whiteout(&leaf.content) => {
+                // overlayfs whiteout, the file or directory was deleted
+                if pristine.lookup(path).is_some() {
+                    diff.removed.push(current_path.clone());
+                }
+            }
+
+            Inode::Leaf(leaf) if unreadable(&leaf.content).is_some() => {
+                // We can't tell if it changed, nor copy it over
+                diff.record_skipped(current_path.clone(), &leaf.content);
+            }
+
+            Inode::Leaf(leaf) => match pristine.ref_leaf(path) {
+                Ok(old_leaf) if unreadable(&old_leaf.content).is_some() => {
+                    diff.record_skipped(current_path.clone(), &old_leaf.content);
+                }
+
+                Ok(old_leaf) => {
+                    let metadata_changed = metadata_changed(&old_leaf.stat, &leaf.stat, opts);
+
+                    // File changed to symlink or vice-versa
+                    let type_changed =
+                        EntryKind::of_leaf(&old_leaf.content) != EntryKind::of_leaf(&leaf.content);
+
+                    let content_changed = match (&old_leaf.content, &leaf.content) {
+                        _ if type_changed => true,
+
+                        // File modified in some way
+                        (Regular(old_meta), Regular(current_meta)) => {
+                            if old_meta.is_verity_enabled() != current_meta.is_verity_enabled() {
+                                diff.verity_changed.push(VerityChange {
+                                    path: current_path.clone(),
+                                    old_verity: old_meta.verity.clone(),
+                                    new_verity: current_meta.verity.clone(),
+                                });
+                            }
+
+                            old_meta.content_hash != current_meta.content_hash
+                                || old_meta.verity != current_meta.verity
+                        }
+
+                        // Symlink modified in some way
+                        (Symlink(old_link), Symlink(current_link)) => {
+                            let retargeted = old_link != current_link;
+
+                            if retargeted {
+                                diff.retargeted.push(SymlinkChange {
+                                    path: current_path.clone(),
+                                    old_target: PathBuf::from(&**old_link),
+                                    new_target: PathBuf::from(&**current_link),
+                                });
+                            }
+
+                            retargeted
+                        }
+
+                        (CharacterDevice(old_rdev), CharacterDevice(current_rdev))
+                        | (BlockDevice(old_rdev), BlockDevice(current_rdev)) => {
+                            old_rdev != current_rdev
+                        }
+
+                        // Any other change between device nodes, fifos and sockets
+                        (a, b) => std::mem::discriminant(a) != std::mem::discriminant(b),
+                    };
+
+                    if type_changed {
+                        diff.type_changed.push(current_path.clone());
+                    }
+
+                    diff.record_modification(
+                        current_path.clone(),
+                        content_changed,
+                        metadata_changed,
+                    );
+                }
+
+                Err(ImageError::IsADirectory(..)) => {
+                    // A directory was changed to a file/symlink
+                    diff.replaced.push(TreeReplacement {
+                        path: current_path.clone(),
+                        from_kind: EntryKind::Directory,
+                        to_kind: EntryKind::of(inode),
+                    });
+                }
+
+                Err(ImageError::NotFound(..)) => {
+                    // File not found in original /etc, file was added
+                    diff.added.push(current_path.clone());
+                }
+
+                Err(e) => Err(e).context(format!("{path:?}"))?,
+            },
+        }
+
+        current_path.pop();
+    }
+
+    Ok(())
+}
+
+/// Traverses and collects directory trees for three etc states.
+///
+/// Recursively walks through the given *pristine*, *current*, and *new* etc directories,
+/// building filesystem trees that capture files, directories, and symlinks.
+/// Device files, sockets, pipes etc are ignored
+///
+/// It is primarily used to prepare inputs for later diff computations and
+/// comparisons between different etc states.
+///
+/// # Arguments
+///
+/// * `pristine_etc` - The reference directory representing the unmodified version or current /etc.
+/// Usually this will be obtained by remounting the EROFS image to a temporary location
+///
+/// * `current_etc` - The current `/etc` directory
+///
+/// * `new_etc` - The directory representing the `/etc` directory for a new deployment. This will
+/// again be usually obtained by mounting the new EROFS image to a temporary location. If merging
+/// it will be necessary to make the `/etc` for the deployment writeable
+///
+/// # Returns
+///
+/// [`anyhow::Result`] containing a tuple of directory trees in the order:
+///
+/// 1. `pristine_etc_files` – Dirtree of the pristine etc state
+/// 2. `current_etc_files`  – Dirtree of the current etc state
+/// 3. `new_etc_files`      – Dirtree of the new etc state (if new_etc directory is passed)
+pub fn traverse_etc(
+    pristine_etc: &CapStdDir,
+    current_etc: &CapStdDir,
+    new_etc: Option<&CapStdDir>,
+) -> anyhow::Result<(
+    Directory<CustomMetadata>,
+    Directory<CustomMetadata>,
+    Option<Directory<CustomMetadata>>,
+)> {
+    traverse_etc_with_hasher(pristine_etc, current_etc, new_etc, &Sha256ContentHasher)
+}
+
+/// Same as [`traverse_etc`], but hashes the contents of files without fsverity using `hasher`
+/// instead of SHA256.
+pub fn traverse_etc_with_hasher(
+    pristine_etc: &CapStdDir,
+    current_etc: &CapStdDir,
+    new_etc: Option<&CapStdDir>,
+    hasher: &dyn ContentHasher,
+) -> anyhow::Result<(
+    Directory<CustomMetadata>,
+    Directory<CustomMetadata>,
+    Option<Directory<CustomMetadata>>,
+)> {
+    traverse_etc_with(
+        pristine_etc,
+        current_etc,
+        new_etc,
+        &TraverseOptions::new().hasher(hasher),
+    )
+}
+
+/// Same as [`traverse_etc`], but scans the trees as configured by `opts`
+pub fn traverse_etc_with(
+    pristine_etc: &CapStdDir,
+    current_etc: &CapStdDir,
+    new_etc: Option<&CapStdDir>,
+    opts: &TraverseOptions,
+) -> anyhow::Result<(
+    Directory<CustomMetadata>,
+    Directory<CustomMetadata>,
+    Option<Directory<CustomMetadata>>,
+)> {
+    // The three walks are independent of each other, so run them concurrently
+    let probe = &VerityProbe::new(opts.measure_verity);
+    let budget = &JobBudget::new(opts.effective_jobs());
+
+    let (pristine, current, new) = std::thread::scope(|s| {
+        let pristine = s.spawn(move || {
+            scan_tree(pristine_etc, probe, opts, budget)
+                .context(format!("Recursing {pristine_etc:?}"))
+        });
+
+        let current = s.spawn(move || {
+            scan_tree(current_etc, probe, opts, budget)
+                .context(format!("Recursing {current_etc:?}"))
+        });
+
+        let new = new_etc.map(|new_etc| {
+            s.spawn(move || {
+                scan_tree(new_etc, probe, opts, budget).context(format!("Recursing {new_etc:?}"))
+            })
+        });
+
+        (join(pristine), join(current), new.map(join))
+    });
+
+    let mut pristine_etc_files = Directory::new(Stat::uninitialized());
+    build_tree(pristine?, &mut pristine_etc_files);
+
+    let mut current_etc_files = Directory::new(Stat::uninitialized());
+    build_tree(current?, &mut current_etc_files);
+
+    let new_etc_files = match new {
+        Some(new) => {
+            let mut new_etc_files = Directory::new(Stat::uninitialized());
+            build_tree(new?, &mut new_etc_files);
+
+            Some(new_etc_files)
+        }
+
+        None => None,
+    };
+
+    return Ok((pristine_etc_files, current_etc_files, new_etc_files));
+}
+
+fn join<T>(handle: ScopedJoinHandle<'_, T>) -> T {
+    handle
+        .join()
+        .unwrap_or_else(|e| std::panic::resume_unwind(e))
+}
+
+/// Computes the differences between two directory snapshots.
+#[context("Computing diff")]
+pub fn compute_diff(
+    pristine_etc_files: &Directory<CustomMetadata>,
+    current_etc_files: &Directory<CustomMetadata>,
+    new_etc_files: &Directory<CustomMetadata>,
+) -> anyhow::Result<Diff> {
+    compute_diff_with_root(pristine_etc_files, current_etc_files, new_etc_files, None)
+}
+
+/// Same as [`compute_diff`], but prefixes every path in the diff with `display_root`, if any,
+/// e.g. `/etc` so that reported paths are self-describing.
+///
+/// This only affects the reported paths, not how the trees are compared.  As the paths are no
+/// longer relative to /etc, such a diff can't be passed to [`merge`].
+#[context("Computing diff")]
+pub fn compute_diff_with_root(
+    pristine_etc_files: &Directory<CustomMetadata>,
+    current_etc_files: &Directory<CustomMetadata>,
+    new_etc_files: &Directory<CustomMetadata>,
+    display_root: Option<&Path>,
+) -> anyhow::Result<Diff> {
+    let opts = DiffOptions {
+        display_root,
+        ..Default::default()
+    };
+
+    compute_diff_with(pristine_etc_files, current_etc_files, new_etc_files, &opts)
+}
+
+/// Options for [`compute_diff_with`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DiffOptions<'a> {
+    display_root: Option<&'a Path>,
+    compare_mtime: bool,
+}
+
+impl<'a> DiffOptions<'a> {
+    /// Options matching [`compute_diff`]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Prefix every path in the diff with `root`, see [`compute_diff_with_root`]
+    pub fn display_root(mut self, root: &'a Path) -> Self {
+        self.display_root = Some(root);
+        self
+    }
+
+    /// Also report files and directories whose modification time differs from the pristine
+    /// one as having their metadata changed, for strict comparisons.
+    ///
+    /// Off by default, as the mtime of a directory changes whenever an entry is added to or
+    /// removed from it, and plenty of tools rewrite files without changing their contents.
+    /// Only whole seconds are compared.
+    pub fn compare_mtime(mut self, compare_mtime: bool) -> Self {
+        self.compare_mtime = compare_mtime;
+        self
+    }
+}
+
+/// Same as [`compute_diff`], but returns how each path changed as a single map, see
+/// [`Diff::changes`]
+pub fn compute_diff_detailed(
+    pristine_etc_files: &Directory<CustomMetadata>,
+    current_etc_files: &Directory<CustomMetadata>,
+    new_etc_files: &Directory<CustomMetadata>,
+) -> anyhow::Result<BTreeMap<PathBuf, ChangeKind>> {
+    let diff = compute_diff(pristine_etc_files, current_etc_files, new_etc_files)?;
+
+    Ok(diff.changes())
+}
+
+/// Same as [`compute_diff`], but configurable through `opts`
+#[context("Computing diff")]
+pub fn compute_diff_with(
+    pristine_etc_files: &Directory<CustomMetadata>,
+    current_etc_files: &Directory<CustomMetadata>,
+    new_etc_files: &Directory<CustomMetadata>,
+    opts: &DiffOptions,
+) -> anyhow::Result<Diff> {
+    let mut diff = Diff::default();
+
+    get_modifications(
+        &pristine_etc_files,
+        &current_etc_files,
+        &new_etc_files,
+        PathBuf::new(),
+        &mut diff,
+        opts,
+    )?;
+
+    get_deletions(
+        &pristine_etc_files,
+        &current_etc_files,
+        PathBuf::new(),
+        &mut diff,
+    )?;
+
+    get_hardlink_changes(&pristine_etc_files, &current_etc_files, &mut diff);
+
+    // Deletions are found in two passes, overlayfs whiteouts along with the modifications
+    diff.sort();
+
+    if let Some(root) = opts.display_root {
+        diff.prefix_paths(root);
+    }
+
+    Ok(diff)
+}
+
+/// Groups the paths of all regular files in `root` which share an inode
+fn collect_hardlinks(root: &Directory<CustomMetadata>) -> BTreeMap<InodeKey, Vec<PathBuf>> {
+    fn collect(
+        root: &Directory<CustomMetadata>,
+        current_path: &mut PathBuf,
+        links: &mut BTreeMap<InodeKey, Vec<PathBuf>>,
+    ) {
+        for (path, inode) in root.sorted_entries() {
+            current_path.push(path);
+
+            match inode {
+                Inode::Directory(dir) => collect(dir, current_path, links),
+
+                Inode::Leaf(leaf) => {
+                    if let LeafContent::Regular(CustomMetadata {
+                        inode: Some(key), ..
+                    }) = &leaf.content
+                    {
+                        links.entry(*key).or_default().push(current_path.clone());
+                    }
+                }
+            }
+
+            current_path.pop();
+        }
+    }
+
+    let mut links = BTreeMap::new();
+    collect(root, &mut PathBuf::new(), &mut links);
+
+    // A file can have more links than we can see from /etc
+    links.retain(|_, paths| paths.len() > 1);
+    links
+}
+
+fn get_hardlink_changes(
+    pristine: &Directory<CustomMetadata>,
+    current: &Directory<CustomMetadata>,
+    diff: &mut Diff,
+) {
+    let current_links = collect_hardlinks(current);
+
+    let current_inodes: HashMap<&Path, InodeKey> = current_links
+        .iter()
+        .flat_map(|(key, paths)| paths.iter().map(|path| (path.as_path(), *key)))
+        .collect();
+
+    for paths in collect_hardlinks(pristine).into_values() {
+        let remaining: Vec<_> = paths
+            .into_iter()
+            .filter(|path| {
+                current
+                    .split(path.as_os_str())
+                    .is_ok_and(|(dir, name)| dir.lookup(name).is_some())
+            })
+            .collect();
+
+        let mut inodes = remaining
+            .iter()
+            .map(|path| current_inodes.get(path.as_path()));
+        let first = inodes.next().flatten();
+        let still_linked = first.is_some() && inodes.all(|inode| inode == first);
+
+        if remaining.len() > 1 && !still_linked {
+            diff.diverged_hardlinks.push(remaining);
+        }
+    }
+
+    diff.hardlinks = current_links.into_values().collect();
+    diff.hardlinks.sort();
+    diff.diverged_hardlinks.sort();
+}
+
+/// Scans the three `/etc` directories and computes the differences between them.
+///
+/// This is a shorthand for [`traverse_etc`] followed by [`compute_diff`].
+///
+/// ```no_run
+/// use cap_std_ext::cap_std::{ambient_authority, fs::Dir};
+///
+/// # fn main() -> anyhow::Result<()> {
+/// let pristine = Dir::open_ambient_dir("/run/pristine/etc", ambient_authority())?;
+/// let current = Dir::open_ambient_dir("/etc", ambient_authority())?;
+/// let new = Dir::open_ambient_dir("/run/new/etc", ambient_authority())?;
+///
+/// let diff = etc_merge::diff_etc(&pristine, &current, &new)?;
+/// for path in diff.modified() {
+///     println!("modified: {path:?}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub fn diff_etc(
+    pristine_etc: &CapStdDir,
+    current_etc: &CapStdDir,
+    new_etc: &CapStdDir,
+) -> anyhow::Result<Diff> {
+    diff_etc_with(pristine_etc, current_etc, new_etc, &TraverseOptions::new())
+}
+
+/// Same as [`diff_etc`], but scans the trees as configured by `opts`, e.g. to report progress
+pub fn diff_etc_with(
+    pristine_etc: &CapStdDir,
+    current_etc: &CapStdDir,
+    new_etc: &CapStdDir,
+    opts: &TraverseOptions,
+) -> anyhow::Result<Diff> {
+    let (pristine, current, new) =
+        traverse_etc_with(pristine_etc, current_etc, Some(new_etc), opts)?;
+    let new = new.ok_or_else(|| anyhow::anyhow!("Failed to get dirtree for new etc"))?;
+
+    compute_diff(&pristine, &current, &new)
+}
+
+/// Computes the differences between two directory snapshots, without a pristine baseline, e.g.
+/// the same /etc before and after a manual edit.
+///
+/// Paths only in `b` are added, paths only in `a` are removed and everything else is compared
+/// as with [`compute_diff`], including whether contents or metadata changed. As nothing is
+/// being merged, directories are only reported if they changed themselves.
+#[context("Computing two-way diff")]
+pub fn compute_diff_two(
+    a: &Directory<CustomMetadata>,
+    b: &Directory<CustomMetadata>,
+) -> anyhow::Result<Diff> {
+    // Directories are only reported as added back when missing from the new /etc. Every one of
+    // them is in `b`.
+    compute_diff(a, b, b)
+}
+
+/// Scans the two `/etc` directories and computes the differences between them.
+///
+/// This is a shorthand for [`traverse_etc`] followed by [`compute_diff_two`].
+pub fn diff_two(a: &CapStdDir, b: &CapStdDir) -> anyhow::Result<Diff> {
+    let (a, b, _) = traverse_etc(a, b, None)?;
+
+    compute_diff_two(&a, &b)
+}
+
+/// Prints a colorized summary of differences to standard output.
+pub fn print_diff(diff: &Diff, writer: &mut impl Write) {
+    use owo_colors::OwoColorize;
+
+    for added in &diff.added {
+        let _ = writeln!(writer, "{} {added:?}", ModificationType::Added.green());
+    }
+
+    for modified in &diff.modified {
+        let _ = writeln!(writer, "{} {modified:?}", ModificationType::Modified.cyan());
+    }
+
+    for removed in &diff.removed {
+        let _ = writeln!(writer, "{} {removed:?}", ModificationType::Removed.red());
+    }
+
+    for replaced in &diff.replaced {
+        let _ = writeln!(
+            writer,
+            "{} {:?} ({:?} -> {:?})",
+            ModificationType::Replaced.yellow(),
+            replaced.path,
+            replaced.from_kind,
+            replaced.to_kind
+        );
+    }
+
+    for skipped in &diff.skipped {
+        let _ = writeln!(
+            writer,
+            "{} {:?} ({})",
+            ModificationType::Skipped.magenta(),
+            skipped.path,
+            skipped.kind
+        );
+    }
+}
+
+/// Calls `read` with a buffer large enough for its output, growing it as needed
+fn read_xattr_buf(
+    mut read: impl FnMut(&mut [u8]) -> rustix::io::Result<usize>,
+) -> rustix::io::Result<Vec<u8>> {
+    const DEFAULT_SIZE: usize = 128;
+
+    // Start with a guess for size
+    let mut buf = vec![0; DEFAULT_SIZE];
+
+    loop {
+        match read(&mut buf) {
+            Ok(size) => {
+                buf.truncate(size);
+                return Ok(buf);
+            }
+
+            // Too small; ask for the actual size.  This is retried as the
+            // value may grow again in between.
+            Err(rustix::io::Errno::RANGE) => {
+                let size = read(&mut [])?;
+                buf.resize(size.max(buf.len() * 2), 0);
+            }
+
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads all extended attributes of `rel_path`, including `security.*` ones such as SELinux
+/// labels.  Filesystems without xattr support yield no xattrs.
+#[context("Collecting xattrs")]
+fn collect_xattrs(etc_fd: &CapStdDir, rel_path: impl AsRef<Path>) -> anyhow::Result<Xattrs> {
+    let link = format!("/proc/self/fd/{}", etc_fd.as_fd().as_raw_fd());
+    let path = Path::new(&link).join(rel_path);
+
+    let xattrs: Xattrs = RefCell::new(BTreeMap::new());
+
+    let names = match read_xattr_buf(|buf| llistxattr(&path, buf)) {
+        Ok(names) => names,
+        Err(rustix::io::Errno::OPNOTSUPP) => return Ok(xattrs),
+        Err(e) => return Err(e).context("llistxattr"),
+    };
+
+    for name_buf in names.split(|&b| b == 0).filter(|x| !x.is_empty()) {
+        let name = OsStr::from_bytes(name_buf);
+
+        let value = read_xattr_buf(|buf| lgetxattr(&path, name_buf, buf)).context("lgetxattr")?;
+
+        xattrs
+            .borrow_mut()
+            .insert(Box::<OsStr>::from(name), value.into_boxed_slice());
+    }
+
+    Ok(xattrs)
+}
+
+#[context("Copying xattrs")]
+fn copy_xattrs(xattrs: &Xattrs, new_etc_fd: &CapStdDir, path: &Path) -> anyhow::Result<()> {
+    for (attr, value) in xattrs.borrow().iter() {
+        if OVERLAY_OPAQUE_XATTRS
+            .iter()
+            .any(|x| OsStr::new(x) == &**attr)
+        {
+            // Internal to overlayfs, not something to carry over
+            continue;
+        }
+
+        let fdpath = &Path::new(&format!("/proc/self/fd/{}", new_etc_fd.as_raw_fd())).join(path);
+        lsetxattr(fdpath, attr.as_ref(), value, XattrFlags::empty())
+            .with_context(|| format!("setxattr {attr:?} for {fdpath:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Remembers which fsverity digest algorithm files were last found to be using.
+///
+/// This is shared between the concurrent tree walks, so that once we learn that files are
+/// using SHA512 we don't need to first try (and fail) measuring each of them with SHA256.
+///
+/// If the caller pinned an algorithm through [`TraverseOptions::verity_algorithm`], no
+/// detection is done at all.
+#[derive(Debug, Default)]
+struct VerityProbe {
+    sha512: AtomicBool,
+    pinned: Option<MeasureVerityFn>,
+}
+
+impl VerityProbe {
+    fn new(pinned: Option<MeasureVerityFn>) -> Self {
+        Self {
+            sha512: AtomicBool::new(false),
+            pinned,
+        }
+    }
+
+    /// Measures the fsverity digest of `entry`, if it has verity enabled
+    fn measure(&self, file: &File) -> anyhow::Result<Option<String>> {
+        use composefs::fsverity::{MeasureVerityError, measure_verity_opt};
+
+        if let Some(measure) = self.pinned {
+            return measure(file);
+        }
+
+        let first_sha512 = self.sha512.load(Ordering::Relaxed);
+
+        for sha512 in [first_sha512, !first_sha512] {
+            let measured_verity = if sha512 {
+                measure_verity_opt::<Sha512HashValue>(file).map(|v| v.map(|verity| verity.to_hex()))
+            } else {
+                measure_verity_opt::<Sha256HashValue>(file).map(|v| v.map(|verity| verity.to_hex()))
+            };
+
+            match measured_verity {
+                Ok(mv) => {
+                    self.sha512.store(sha512, Ordering::Relaxed);
+                    return Ok(mv);
+                }
+
+                Err(MeasureVerityError::InvalidDigestAlgorithm { .. }) => continue,
+
+                Err(e) => Err(e)?,
+            }
+        }
+
+        anyhow::bail!("Unsupported fsverity digest algorithm")
+    }
+}
+
+/// A directory entry read from disk
+///
+/// Unlike [`Inode`] this is `Send`, which lets us scan the pristine, current and new trees on
+/// separate threads and only build the `Rc` based [`Directory`] trees afterwards.
+enum ScannedEntry {
+    Directory(Stat, Vec<(OsString, ScannedEntry)>),
+    Leaf(Stat, LeafContent<CustomMetadata>),
+    /// A regular file, whose metadata is at this index of the [`PendingFile`]s of the walk
+    PendingFile(Stat, usize),
+}
+
+/// A regular file found while walking a tree, which still needs to be measured or hashed
+struct PendingFile {
+    path: PathBuf,
+    entry: DirEntry,
+    inode: Option<InodeKey>,
+    /// The device and inode number, regardless of the number of hardlinks
+    id: InodeKey,
+    stamp: FileStamp,
+}
+
+/// The regular files found while walking a tree
+#[derive(Default)]
+struct PendingFiles {
+    files: Vec<PendingFile>,
+    by_inode: HashMap<InodeKey, usize>,
+}
+
+impl PendingFiles {
+    /// Queues `file`, returning the index its metadata will be at.
+    ///
+    /// Hardlinks to an already queued file share its index, so every inode is only hashed once.
+    fn push(&mut self, file: PendingFile) -> usize {
+        if let Some(inode) = file.inode {
+            if let Some(&idx) = self.by_inode.get(&inode) {
+                return idx;
+            }
+
+            self.by_inode.insert(inode, self.files.len());
+        }
+
+        self.files.push(file);
+        self.files.len() - 1
+    }
+}
+
+/// Walks `dir`, then measures or hashes all the regular files found in it.
+///
+/// The walk itself is serial, but the files are processed on as many threads as `budget` has
+/// available, as that's where nearly all the time goes. Each result is put back in the position
+/// the file was found at, so the output is the same regardless of the scheduling.
+fn scan_tree(
+    dir: &CapStdDir,
+    probe: &VerityProbe,
+    opts: &TraverseOptions,
+    budget: &JobBudget,
+) -> anyhow::Result<Vec<(OsString, ScannedEntry)>> {
+    let scan_start = epoch_timestamp(std::time::SystemTime::now());
+
+    let mut pending = PendingFiles::default();
+    let entries = recurse_dir(dir, Path::new(""), opts, &mut pending)?;
+
+    let metadata = hash_files(pending.files, probe, opts, scan_start, budget)?;
+
+    Ok(resolve_pending(entries, &metadata))
+}
+
+/// The threads available to [`hash_files`], shared between the scans of all trees so that
+/// [`TraverseOptions::jobs`] bounds the total rather than the threads of each tree
+struct JobBudget {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl JobBudget {
+    fn new(jobs: usize) -> Self {
+        Self {
+            available: Mutex::new(jobs.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Takes a single thread, waiting for one to be given back if none is available
+    fn acquire(&self) -> JobPermit<'_> {
+        let mut available = self.available.lock().unwrap();
+
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+
+        *available -= 1;
+
+        JobPermit {
+            budget: self,
+            count: 1,
+        }
+    }
+
+    /// Takes up to `max` threads, without waiting
+    fn try_acquire(&self, max: usize) -> JobPermit<'_> {
+        let mut available = self.available.lock().unwrap();
+        let count = max.min(*available);
+        *available -= count;
+
+        JobPermit {
+            budget: self,
+            count,
+        }
+    }
+}
+
+/// Threads taken from a [`JobBudget`], given back once dropped
+struct JobPermit<'a> {
+    budget: &'a JobBudget,
+    count: usize,
+}
+
+impl Drop for JobPermit<'_> {
+    fn drop(&mut self) {
+        if self.count > 0 {
+            *self.budget.available.lock().unwrap() += self.count;
+            self.budget.released.notify_all();
+        }
+    }
+}
+
+fn hash_files(
+    files: Vec<PendingFile>,
+    probe: &VerityProbe,
+    opts: &TraverseOptions,
+    scan_start: (i64, i64),
+    budget: &JobBudget,
+) -> anyhow::Result<Vec<Option<CustomMetadata>>> {
+    if files.is_empty() {
+        return Ok(vec![]);
+    }
+
+    // Hash on at least one thread, and on as many more as are available right now
+    let _permit = budget.acquire();
+    let helpers = budget.try_acquire(files.len() - 1);
+    let jobs = 1 + helpers.count;
+
+    if jobs <= 1 {
+        return files
+            .iter()
+            .map(|file| hash_file(file, probe, opts, scan_start).map(Some))
+            .collect();
+    }
+
+    let count = files.len();
+    let queue = Mutex::new(files.into_iter().enumerate());
+    let failed = AtomicBool::new(false);
+
+    let mut hashed: Vec<_> = std::thread::scope(|s| {
+        let workers: Vec<_> = (0..jobs)
+            .map(|_| {
+                s.spawn(|| {
+                    let mut hashed = vec![];
+
+                    // No point in hashing the rest once a file failed
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some((idx, file)) = queue.lock().unwrap().next() else {
+                            break;
+                        };
+
+                        let result = hash_file(&file, probe, opts, scan_start);
+
+                        if result.is_err() {
+                            failed.store(true, Ordering::Relaxed);
+                        }
+
+                        hashed.push((idx, result));
+                    }
+
+                    hashed
+                })
+            })
+            .collect();
+
+        workers.into_iter().flat_map(join).collect()
+    });
+
+    // Files are handed out in order, so every file before the first failure was hashed, and
+    // reporting the failure with the lowest index gives the same error as a serial scan
+    hashed.sort_unstable_by_key(|(idx, _)| *idx);
+
+    let mut metadata: Vec<_> = std::iter::repeat_with(|| None).take(count).collect();
+
+    for (idx, result) in hashed {
+        metadata[idx] = Some(result?);
+    }
+
+    Ok(metadata)
+}
+
+fn hash_file(
+    file: &PendingFile,
+    probe: &VerityProbe,
+    opts: &TraverseOptions,
+    scan_start: (i64, i64),
+) -> anyhow::Result<CustomMetadata> {
+    let cached = opts.cache.and_then(|cache| cache.get(file));
+
+    let mut metadata = match cached {
+        Some(metadata) => metadata,
+
+        None => {
+            let metadata = measure_or_hash(file, probe, opts)?;
+
+            if let Some(cache) = opts.cache {
+                cache.insert(file, &metadata, scan_start);
+            }
+
+            metadata
+        }
+    };
+
+    metadata.inode = file.inode;
+
+    opts.report_progress(&file.path);
+
+    Ok(metadata)
+}
+
+/// Measures or hashes `file`.
+///
+/// Failing to open or read the file, e.g. due to its permissions, isn't fatal. The file is
+/// marked as unreadable instead, for [`compute_diff`] to report it as skipped.
+fn measure_or_hash(
+    file: &PendingFile,
+    probe: &VerityProbe,
+    opts: &TraverseOptions,
+) -> anyhow::Result<CustomMetadata> {
+    let reader = match file.entry.open() {
+        Ok(reader) => reader,
+        Err(e) => {
+            tracing::warn!("Skipping {:?}: {e}", file.path);
+            return Ok(CustomMetadata::unreadable(e.kind()));
+        }
+    };
+
+    let measured_verity = probe
+        .measure(&reader)
+        .context(format!("Measuring fsverity of {:?}", file.path))?;
+
+    if let Some(measured_verity) = measured_verity {
+        return Ok(CustomMetadata::new("".into(), Some(measured_verity)));
+    }
+
+    match opts.hasher.hash(&mut BufReader::new(reader)) {
+        Ok(content_digest) => Ok(CustomMetadata::new(content_digest, None)),
+
+        Err(e) => match e.downcast_ref::<std::io::Error>() {
+            Some(io_error) => {
+                tracing::warn!("Skipping {:?}: {io_error}", file.path);
+                Ok(CustomMetadata::unreadable(io_error.kind()))
+            }
+
+            None => Err(e).context(format!("Hashing {:?}", file.path)),
+        },
+    }
+}
+
+/// If `content` is a file which could not be read, returns why
+fn unreadable(content: &LeafContent<CustomMetadata>) -> Option<std::io::ErrorKind> {
+    match content {
+        LeafContent::Regular(meta) => meta.unreadable,
+        _ => None,
+    }
+}
+
+fn resolve_pending(
+    entries: Vec<(OsString, ScannedEntry)>,
+    metadata: &[Option<CustomMetadata>],
+) -> Vec<(OsString, ScannedEntry)> {
+    entries
+        .into_iter()
+        .map(|(entry_name, entry)| {
+            let entry = match entry {
+                ScannedEntry::Directory(stat, children) => {
+                    ScannedEntry::Directory(stat, resolve_pending(children, metadata))
+                }
+
+                ScannedEntry::PendingFile(stat, idx) => {
+                    // Hardlinks share the same metadata
+                    let meta = metadata[idx].clone().expect("all pending files are hashed");
+                    ScannedEntry::Leaf(stat, LeafContent::Regular(meta))
+                }
+
+                leaf @ ScannedEntry::Leaf(..) => leaf,
+            };
+
+            (entry_name, entry)
+        })
+        .collect()
+}
+
+fn build_tree(entries: Vec<(OsString, ScannedEntry)>, root: &mut Directory<CustomMetadata>) {
+    for (entry_name, entry) in entries {
+        match entry {
+            ScannedEntry::Directory(stat, children) => {
+                let mut directory = Directory::new(stat);
+                build_tree(children, &mut directory);
+                root.insert(&entry_name, Inode::Directory(Box::new(directory)));
+            }
+
+            ScannedEntry::Leaf(stat, content) => {
+                root.insert(&entry_name, Inode::Leaf(Rc::new(Leaf { stat, content })));
+            }
+
+            ScannedEntry::PendingFile(..) => {
+                unreachable!("{entry_name:?} should have been hashed by scan_tree")
+            }
+        }
+    }
+}
+
+/// Scans `dir`, which is at `path` relative to the root of the tree being walked.
+///
+/// Regular files are not read here, but added to `pending` for [`scan_tree`] to process.
+fn recurse_dir(
+    dir: &CapStdDir,
+    path: &Path,
+    opts: &TraverseOptions,
+    pending: &mut PendingFiles,
+) -> anyhow::Result<Vec<(OsString, ScannedEntry)>> {
+    let mut entries = vec![];
+
+    for entry in dir.entries()? {
+        let entry = entry.context(format!("Getting entry"))?;
+        let entry_name = entry.file_name();
+        let entry_path = path.join(&entry_name);
+
+        if opts.exclude.is_some_and(|exclude| exclude(&entry_path)) {
+            tracing::debug!("Excluding {entry_path:?}");
+            continue;
+        }
+
+        let entry_type = entry.file_type()?;
+
+        let entry_meta = match entry.metadata() {
+            Ok(entry_meta) => entry_meta,
+
+            // Removed while we were scanning, so treat it as if it was never there
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                tracing::debug!("{entry_path:?} disappeared while scanning");
+                continue;
+            }
+
+            Err(e) => Err(e).context(format!("Getting metadata for {entry_name:?}"))?,
+        };
+
+        let xattrs = collect_xattrs(&dir, &entry_name)?;
+
+        // Regular files are only done once they're hashed
+        if !entry_type.is_file() {
+            opts.report_progress(&entry_path);
+        }
+
+        // Do symlinks first as we don't want to follow back up any symlinks
+        if entry_type.is_symlink() {
+            let readlinkat_result = readlinkat(&dir, &entry_name, vec![])
+                .context(format!("readlinkat {entry_name:?}"))?;
+
+            let os_str = OsStr::from_bytes(readlinkat_result.as_bytes());
+
+            entries.push((
+                entry_name,
+                ScannedEntry::Leaf(
+                    MyStat::from((&entry_meta, xattrs)).0,
+                    LeafContent::Symlink(Box::from(os_str)),
+                ),
+            ));
+
+            continue;
+        }
+
+        if entry_type.is_dir() {
+            // Not following symlinks, in case the directory was swapped for one since we got
+            // its type, which could make us loop or scan outside of /etc
+            let dir = dir
+                .open_dir_nofollow(&entry_name)
+                .with_context(|| format!("Opening dir {entry_name:?} inside {dir:?}"))?;
+
+            let children = recurse_dir(&dir, &entry_path, opts, pending)?;
+
+            entries.push((
+                entry_name,
+                ScannedEntry::Directory(MyStat::from((&entry_meta, xattrs)).0, children),
+            ));
+
+            continue;
+        }
+
+        if entry_type.is_char_device() && entry_meta.rdev() == 0 {
+            // overlayfs whiteout
+            entries.push((
+                entry_name,
+                ScannedEntry::Leaf(
+                    MyStat::from((&entry_meta, xattrs)).0,
+                    LeafContent::CharacterDevice(0),
+                ),
+            ));
+
+            continue;
+        }
+
+        if !(entry_type.is_symlink() || entry_type.is_file()) {
+            // We cannot read any other device like socket, pipe, fifo.
+            // We shouldn't really find these in /etc in the first place
+            tracing::debug!("Ignoring non-regular/non-symlink file: {:?}", entry_name);
+            continue;
+        }
+
+        let inode = (entry_meta.nlink() > 1).then(|| (entry_meta.dev(), entry_meta.ino()));
+
+        if inode.is_some_and(|inode| pending.by_inode.contains_key(&inode)) {
+            // Another link to a file we're already going to hash, nothing more to do for this one
+            opts.report_progress(&entry_path);
+        }
+
+        let idx = pending.push(PendingFile {
+            path: entry_path,
+            entry,
+            inode,
+            id: (entry_meta.dev(), entry_meta.ino()),
+            stamp: FileStamp::of(&entry_meta),
+        });
+
+        entries.push((
+            entry_name,
+            ScannedEntry::PendingFile(MyStat::from((&entry_meta, xattrs)).0, idx),
+        ));
+    }
+
+    Ok(entries)
+}
+
+#[derive(Debug)]
+enum ModificationType {
+    Added,
+    Modified,
+    Removed,
+    Replaced,
+    Skipped,
+}
+
+impl std::fmt::Display for ModificationType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl ModificationType {
+    fn symbol(&self) -> &'static str {
+        match self {
+            ModificationType::Added => "+",
+            ModificationType::Modified => "~",
+            ModificationType::Removed => "-",
+            ModificationType::Replaced => "!",
+            ModificationType::Skipped => "?",
+        }
+    }
+}
+
+fn create_dir_with_perms(
+    new_etc_fd: &CapStdDir,
+    dir_name: &PathBuf,
+    stat: &Stat,
+    new_inode: Option<&Inode<CustomMetadata>>,
+) -> anyhow::Result<()> {
+    // The new directory is not present in the new_etc, so we create it, else we only copy the
+    // metadata
+    if new_inode.is_none() {
+        // Here we use `create_dir_all` to create every parent as we will set the permissions later
+        // on. Due to the fact that we have an ordered (sorted) list of directories and directory
+        // entries and we have a DFS traversal, we will always have directory creation starting from
+        // the parent anyway.
+        //
+        // The exception being, if a directory is modified in the current_etc, and a new directory
+        // is added inside the modified directory, say `dir/prems` has its permissions modified and
+        // `dir/prems/new` is the new directory created. Since we handle added files/directories first,
+        // we will create the directories `perms/new` with directory `new` also getting its
+        // permissions set, but `perms` will not. `perms` will have its permissions set up when we
+        // handle the modified directories.
+        new_etc_fd
+            .create_dir_all(&dir_name)
+            .context(format!("Failed to create dir {dir_name:?}"))?;
+    }
+
+    new_etc_fd
+        .set_permissions(&dir_name, Permissions::from_mode(stat.st_mode))
+        .context(format!("Changing permissions for dir {dir_name:?}"))?;
+
+    rustix::fs::chownat(
+        &new_etc_fd,
+        dir_name,
+        Some(Uid::from_raw(stat.st_uid)),
+        Some(Gid::from_raw(stat.st_gid)),
+        AtFlags::SYMLINK_NOFOLLOW,
+    )
+    .context(format!("chown {dir_name:?}"))?;
+
+    copy_xattrs(&stat.xattrs, new_etc_fd, dir_name)?;
+
+    Ok(())
+}
+
+/// Where files with multiple hardlinks were first copied to during a merge, so the other links
+/// to them can be recreated
+type CopiedInodes = HashMap<InodeKey, PathBuf>;
+
+fn merge_leaf(
+    current_etc_fd: &CapStdDir,
+    new_etc_fd: &CapStdDir,
+    leaf: &Rc<Leaf<CustomMetadata>>,
+    new_inode: Option<&Inode<CustomMetadata>>,
+    file: &PathBuf,
+    copied: &mut CopiedInodes,
+) -> anyhow::Result<()> {
+    let (symlink, inode) = match &leaf.content {
+        LeafContent::Regular(meta) => (None, meta.inode),
+        LeafContent::Symlink(target) => (Some(target), None),
+
+        _ => {
+            tracing::debug!("Found non file/symlink while merging. Ignoring");
+            return Ok(());
+        }
+    };
+
+    if matches!(new_inode, Some(Inode::Directory(..))) {
+        anyhow::bail!("Modified config file {file:?} newly defaults to directory. Cannot merge")
+    };
+
+    // If a new file with the same path exists, we delete it
+    new_etc_fd
+        .remove_all_optional(&file)
+        .context(format!("Deleting {file:?}"))?;
+
+    if let Some(first) = inode.and_then(|inode| copied.get(&inode)) {
+        // The ownership and xattrs are shared with the link we already copied
+        return new_etc_fd
+            .hard_link(first, new_etc_fd, file)
+            .with_context(|| format!("Linking {file:?} to {first:?}"));
+    }
+
+    if let Some(target) = symlink {
+        // Using rustix's symlinkat here as we might have absolute symlinks which clash with ambient_authority
+        symlinkat(&**target, new_etc_fd, file).context(format!("Creating symlink {file:?}"))?;
+    } else {
+        current_etc_fd
+            .copy(&file, new_etc_fd, &file)
+            .with_context(|| format!("Copying file {file:?}"))?;
+    };
+
+    if let Some(inode) = inode {
+        copied.insert(inode, file.clone());
+    }
+
+    rustix::fs::chownat(
+        &new_etc_fd,
+        file,
+        Some(Uid::from_raw(leaf.stat.st_uid)),
+        Some(Gid::from_raw(leaf.stat.st_gid)),
+        AtFlags::SYMLINK_NOFOLLOW,
+    )
+    .context(format!("chown {file:?}"))?;
+
+    copy_xattrs(&leaf.stat.xattrs, new_etc_fd, file)?;
+
+    Ok(())
+}
+
+/// Replaces whatever is at the replaced path in new_etc with the entry from current_etc
+///
+/// For directories this only creates the directory itself, its contents are part of the added
+/// files.
+#[context("Replacing {:?}", replacement.path)]
+fn merge_replacement(
+    current_etc_fd: &CapStdDir,
+    current_etc_dirtree: &Directory<CustomMetadata>,
+    new_etc_fd: &CapStdDir,
+    replacement: &TreeReplacement,
+    copied: &mut CopiedInodes,
+) -> anyhow::Result<()> {
+    let file = &replacement.path;
+
+    let (dir, filename) = current_etc_dirtree
+        .split(OsStr::new(file))
+        .context("Getting directory and file")?;
+
+    let current_inode = dir
+        .lookup(filename)
+        .ok_or_else(|| anyhow::anyhow!("{filename:?} not found"))?;
+
+    // Get rid of the entire subtree, so nothing is left over under what is now a symlink
+    new_etc_fd
+        .remove_all_optional(file)
+        .context(format!("Deleting {file:?}"))?;
+
+    if let Some(parent) = file.parent().filter(|p| !p.as_os_str().is_empty()) {
+        new_etc_fd
+            .create_dir_all(parent)
+            .context(format!("Creating {parent:?}"))?;
+    }
+
+    match current_inode {
+        Inode::Directory(..) => create_dir_with_perms(new_etc_fd, file, current_inode.stat(), None),
+
+        Inode::Leaf(leaf) => merge_leaf(current_etc_fd, new_etc_fd, leaf, None, file, copied),
+    }
+}
+
+fn merge_modified_files(
+    files: &Vec<PathBuf>,
+    current_etc_fd: &CapStdDir,
+    current_etc_dirtree: &Directory<CustomMetadata>,
+    new_etc_fd: &CapStdDir,
+    new_etc_dirtree: &Directory<CustomMetadata>,
+    copied: &mut CopiedInodes,
+) -> anyhow::Result<()> {
+    for file in files {
+        let (dir, filename) = current_etc_dirtree
+            .split(OsStr::new(&file))
+            .context("Getting directory and file")?;
+
+        let current_inode = dir
+            .lookup(filename)
+            .ok_or_else(|| anyhow::anyhow!("{filename:?} not found"))?;
+
+        // This will error out if some directory in a chain does not exist
+        let res = new_etc_dirtree.split(OsStr::new(&file));
+
+        match res {
+            Ok((new_dir, filename)) => {
+                let new_inode = new_dir.lookup(filename);
+
+                match current_inode {
+                    Inode::Directory(..) => {
+                        create_dir_with_perms(new_etc_fd, file, current_inode.stat(), new_inode)?;
+                    }
+
+                    Inode::Leaf(leaf) => {
+                        merge_leaf(current_etc_fd, new_etc_fd, leaf, new_inode, file, copied)?
+                    }
+                };
+            }
+
+            // Directory/File does not exist in the new /etc, or some parent of it is not a
+            // directory there
+            Err(ImageError::NotFound(..) | ImageError::NotADirectory(..)) => match current_inode {
+                Inode::Directory(..) => {
+                    create_dir_with_perms(new_etc_fd, file, current_inode.stat(), None)?
+                }
+
+                Inode::Leaf(leaf) => {
+                    merge_leaf(current_etc_fd, new_etc_fd, leaf, None, file, copied)?;
+                }
+            },
+
+            Err(e) => Err(e)?,
+        };
+    }
+
+    Ok(())
+}
+
+/// Goes through the added, modified, removed files and apply those changes to the new_etc
+/// This will overwrite, remove, modify files in new_etc
+/// Paths in `diff` are relative to `etc`
+#[context("Merging")]
+pub fn merge(
+    current_etc_fd: &CapStdDir,
+    current_etc_dirtree: &Directory<CustomMetadata>,
+    new_etc_fd: &CapStdDir,
+    new_etc_dirtree: &Directory<CustomMetadata>,
+    diff: &Diff,
+) -> anyhow::Result<()> {
+    if let Some(root) = diff.display_root() {
+        anyhow::bail!("Cannot merge a diff whose paths are prefixed with {root:?}");
+    }
+
+    let mut copied = CopiedInodes::new();
+
+    // Replacements go first, as files added inside a replaced directory need the directory
+    for replacement in &diff.replaced {
+        merge_replacement(
+            current_etc_fd,
+            current_etc_dirtree,
+            new_etc_fd,
+            replacement,
+            &mut copied,
+        )?;
+    }
+
+    merge_modified_files(
+        &diff.added,
+        current_etc_fd,
+        current_etc_dirtree,
+        new_etc_fd,
+        new_etc_dirtree,
+        &mut copied,
+    )
+    .context("Merging added files")?;
+
+    merge_modified_files(
+        &diff.modified,
+        current_etc_fd,
+        current_etc_dirtree,
+        new_etc_fd,
+        new_etc_dirtree,
+        &mut copied,
+    )
+    .context("Merging modified files")?;
+
+    for removed in &diff.removed {
+        // Don't follow symlinks, a dangling one would look like it doesn't exist and one pointing
+        // to a directory would have us delete the target's contents
+        let stat = new_etc_fd.symlink_metadata_optional(&removed)?;
+
+        let Some(stat) = stat else {
+            // File/dir doesn't exist in new_etc
+            // Basically a no-op
+            continue;
+        };
+
+        if stat.is_dir() {
+            // We only add the directory to the removed array, if the entire directory was deleted
+            // So `remove_dir_all` should be okay here
+            new_etc_fd.remove_dir_all(&removed)?;
+        } else {
+            new_etc_fd.remove_file(&removed)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the whole of `tree`, scanned from `src_fd`, into `dest_fd`
+fn copy_tree(
+    src_fd: &CapStdDir,
+    tree: &Directory<CustomMetadata>,
+    dest_fd: &CapStdDir,
+    mut current_path: PathBuf,
+    copied: &mut CopiedInodes,
+) -> anyhow::Result<()> {
+    for (path, inode) in tree.sorted_entries() {
+        current_path.push(path);
+
+        match inode {
+            Inode::Directory(dir) => {
+                dest_fd
+                    .create_dir(&current_path)
+                    .context(format!("Creating {current_path:?}"))?;
+
+                copy_tree(src_fd, dir, dest_fd, current_path.clone(), copied)?;
+
+                // Only set the permissions once the contents are in place, in case the directory
+                // is not writable
+                create_dir_with_perms(dest_fd, &current_path, inode.stat(), Some(inode))?;
+            }
+
+            Inode::Leaf(leaf) => merge_leaf(src_fd, dest_fd, leaf, None, &current_path, copied)?,
+        }
+
+        current_path.pop();
+    }
+
+    Ok(())
+}
+
+/// Performs the full three-way merge of `/etc`, writing the result to the empty directory
+/// `target` and returning the diff it was based on.
+///
+/// `target` is populated with the new defaults from `new_etc`, after which the changes made in
+/// `current_etc` relative to `pristine_etc` are applied on top of them:
+///
+/// - Files modified by the user are kept as they are in `current_etc`
+/// - Files the user did not modify are upgraded to their version in `new_etc`
+/// - Files added by the user are kept
+/// - Files deleted by the user stay deleted, even if `new_etc` still has them
+///
+/// None of the three source directories are modified.
+#[context("Applying merge")]
+pub fn apply_merge(
+    pristine_etc: &CapStdDir,
+    current_etc: &CapStdDir,
+    new_etc: &CapStdDir,
+    target: &CapStdDir,
+) -> anyhow::Result<Diff> {
+    if target.entries()?.next().is_some() {
+        anyhow::bail!("Merge target is not empty");
+    }
+
+    let (pristine_files, current_files, new_files) =
+        traverse_etc(pristine_etc, current_etc, Some(new_etc))?;
+    let new_files =
+        new_files.ok_or_else(|| anyhow::anyhow!("Failed to get dirtree for new etc"))?;
+
+    let diff = compute_diff(&pristine_files, &current_files, &new_files)?;
+
+    copy_tree(
+        new_etc,
+        &new_files,
+        target,
+        PathBuf::new(),
+        &mut CopiedInodes::new(),
+    )
+    .context("Copying new etc")?;
+    merge(current_etc, &current_files, target, &new_files, &diff)?;
+
+    Ok(diff)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std::fs::PermissionsExt;
+    use cap_std_ext::cap_std::fs::Metadata;
+    use cap_std_ext::cap_tempfile::TempDir;
+
+    use super::*;
+
+    /// Creates an empty pristine, current and new /etc in a temporary directory, which is
+    /// removed once the returned [`TempDir`] is dropped
+    fn etc_dirs() -> anyhow::Result<(TempDir, CapStdDir, CapStdDir, CapStdDir)> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+        tempdir.create_dir("new_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+        let n = tempdir.open_dir("new_etc")?;
+
+        Ok((tempdir, p, c, n))
+    }
+
+    /// Traverses the trees created by [`etc_dirs`] and diffs them, also returning the
+    /// traversed trees to merge the diff
+    fn traverse_and_diff(
+        p: &CapStdDir,
+        c: &CapStdDir,
+        n: &CapStdDir,
+    ) -> anyhow::Result<(
+        Directory<CustomMetadata>,
+        Directory<CustomMetadata>,
+        Directory<CustomMetadata>,
+        Diff,
+    )> {
+        let (pristine_etc_files, current_etc_files, new_etc_files) = traverse_etc(p, c, Some(n))?;
+        let new_etc_files = new_etc_files.unwrap();
+
+        let diff = compute_diff(&pristine_etc_files, &current_etc_files, &new_etc_files)?;
+
+        Ok((pristine_etc_files, current_etc_files, new_etc_files, diff))
+    }
+
+    const FILES: &[(&str, &str)] = &[
+        ("a/file1", "a-file1"),
+        ("a/file2", "a-file2"),
+        ("a/b/file1", "ab-file1"),
+        ("a/b/file2", "ab-file2"),
+        ("a/b/c/fileabc", "abc-file1"),
+        ("a/b/c/modify-perms", "modify-perms"),
+        ("a/b/c/to-be-removed", "remove this"),
+        ("to-be-removed", "remove this 2"),
+    ];
+
+    #[test]
+    fn test_etc_diff_plus_merge() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+        tempdir.create_dir("new_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+        let n = tempdir.open_dir("new_etc")?;
+
+        p.create_dir_all("a/b/c")?;
+        c.create_dir_all("a/b/c")?;
+
+        for (file, content) in FILES {
+            p.write(file, content.as_bytes())?;
+            c.write(file, content.as_bytes())?;
+        }
+
+        let new_files = ["new_file", "a/new_file", "a/b/c/new_file"];
+
+        // Add some new files
+        for file in new_files {
+            c.write(file, b"hello")?;
+        }
+
+        let overwritten_files = [FILES[1].0, FILES[4].0];
+        let perm_changed_files = [FILES[5].0];
+
+        // Modify some files
+        c.write(overwritten_files[0], b"some new content")?;
+        c.write(overwritten_files[1], b"some newer content")?;
+
+        // Modify permissions
+        let file = c.open(perm_changed_files[0])?;
+        // This should be enough as the usual files have permission 644
+        file.set_permissions(cap_std::fs::Permissions::from_mode(0o400))?;
+
+        // Remove some files
+        let deleted_files = [FILES[6].0, FILES[7].0];
+        c.remove_file(deleted_files[0])?;
+        c.remove_file(deleted_files[1])?;
+
+        let (pristine_etc_files, current_etc_files, new_etc_files) =
+            traverse_etc(&p, &c, Some(&n))?;
+
+        let res = compute_diff(
+            &pristine_etc_files,
+            &current_etc_files,
+            new_etc_files.as_ref().unwrap(),
+        )?;
+
+        merge(
+            &c,
+            &current_etc_files,
+            &n,
+            new_etc_files.as_ref().unwrap(),
+            &res,
+        )
+        .expect("Merge failed");
+
+        let added_dirs = ["a", "a/b", "a/b/c"];
+
+        // 3 for the files, and 3 for the directories
+        assert_eq!(res.added.len(), new_files.len() + added_dirs.len());
+
+        // Test modified files
+        let all_modified_files = overwritten_files
+            .iter()
+            .chain(&perm_changed_files)
+            .collect::<Vec<_>>();
+
+        assert_eq!(res.modified.len(), all_modified_files.len());
+        assert!(res.modified.iter().all(|file| {
+            all_modified_files
+                .iter()
+                .find(|x| PathBuf::from(*x) == *file)
+                .is_some()
+        }));
+
+        // Test removed files
+        assert_eq!(res.removed.len(), deleted_files.len());
+        assert!(res.removed.iter().all(|file| {
+            deleted_files
+                .iter()
+                .find(|x| PathBuf::from(*x) == *file)
+                .is_some()
+        }));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_two() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        tempdir.create_dir("before")?;
+        tempdir.create_dir("after")?;
+
+        let a = tempdir.open_dir("before")?;
+        let b = tempdir.open_dir("after")?;
+
+        a.create_dir_all("a/b/c")?;
+        b.create_dir_all("a/b/c")?;
+
+        for (file, content) in FILES {
+            a.write(file, content.as_bytes())?;
+            b.write(file, content.as_bytes())?;
+        }
+
+        let new_files = ["a/b/c/new_file", "a/new_file", "new_file"];
+
+        for file in new_files {
+            b.write(file, b"hello")?;
+        }
+
+        // Modify some files
+        b.write(FILES[1].0, b"some new content")?;
+        b.write(FILES[4].0, b"some newer content")?;
+
+        // Modify permissions
+        b.open(FILES[5].0)?
+            .set_permissions(cap_std::fs::Permissions::from_mode(0o400))?;
+
+        // Remove some files
+        b.remove_file(FILES[6].0)?;
+        b.remove_file(FILES[7].0)?;
+
+        let diff = diff_two(&a, &b)?;
+
+        // Unlike with a three-way diff, the unchanged parent directories aren't added
+        assert_eq!(diff.added, new_files.map(PathBuf::from));
+        assert_eq!(
+            diff.modified,
+            [FILES[4].0, FILES[5].0, FILES[1].0].map(PathBuf::from)
+        );
+        assert_eq!(
+            diff.content_changed,
+            [FILES[4].0, FILES[1].0].map(PathBuf::from)
+        );
+        assert_eq!(diff.metadata_changed, [PathBuf::from(FILES[5].0)]);
+        assert_eq!(diff.removed, [FILES[6].0, FILES[7].0].map(PathBuf::from));
+
+        // Swapping the trees swaps additions and removals
+        let reverse = diff_two(&b, &a)?;
+        assert_eq!(reverse.added, diff.removed);
+        assert_eq!(reverse.removed, diff.added);
+        assert_eq!(reverse.modified, diff.modified);
+
+        assert!(diff_two(&a, &a)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_concurrent_traverse_matches_serial() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for dir in [&p, &c, &n] {
+            dir.create_dir_all("a/b/c")?;
+        }
+
+        for (file, content) in FILES {
+            p.write(file, content.as_bytes())?;
+            c.write(file, content.as_bytes())?;
+        }
+
+        c.write("a/new_file", "new")?;
+        c.symlink("a/b/file1", "a/link")?;
+        n.write("a/b/file1", "new-ab-file1")?;
+
+        let parallel = TraverseOptions::new().jobs(NonZeroUsize::new(4).unwrap());
+        let (pristine_etc_files, current_etc_files, new_etc_files) =
+            traverse_etc_with(&p, &c, Some(&n), &parallel)?;
+
+        let trees = [
+            (&p, &pristine_etc_files),
+            (&c, &current_etc_files),
+            (&n, new_etc_files.as_ref().unwrap()),
+        ];
+
+        let serial_opts = TraverseOptions::new().jobs(NonZeroUsize::MIN);
+
+        for (dir, concurrent) in trees {
+            let mut serial = Directory::new(Stat::uninitialized());
+            build_tree(
+                scan_tree(
+                    dir,
+                    &VerityProbe::default(),
+                    &serial_opts,
+                    &JobBudget::new(1),
+                )?,
+                &mut serial,
+            );
+
+            let diff = compute_diff(&serial, concurrent, &Directory::new(Stat::uninitialized()))?;
+
+            assert!(diff.added.is_empty());
+            assert!(diff.modified.is_empty());
+            assert!(diff.removed.is_empty());
+        }
+
+        Ok(())
+    }
+
+    /// Compares the wall-clock time of scanning the three trees one after the other on a single
+    /// thread with [`traverse_etc_with`], which walks them concurrently. Run with
+    /// `cargo test --release -p etc-merge -- --ignored --nocapture test_traverse_timing`,
+    /// optionally with `TMPDIR` on the storage to measure.
+    #[test]
+    #[ignore = "Only run manually to compare serial and concurrent traversal timings"]
+    fn test_traverse_timing() -> anyhow::Result<()> {
+        use std::time::Instant;
+
+        const ROUNDS: u32 = 5;
+
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for dir in [&p, &c, &n] {
+            for i in 0..5000 {
+                let subdir = format!("dir{}/sub{}", i % 50, i % 7);
+                dir.create_dir_all(&subdir)?;
+                dir.write(format!("{subdir}/file{i}"), vec![b'x'; 4096 + i])?;
+            }
+        }
+
+        let serial_opts = TraverseOptions::new().jobs(NonZeroUsize::MIN);
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            for dir in [&p, &c, &n] {
+                scan_tree(
+                    dir,
+                    &VerityProbe::default(),
+                    &serial_opts,
+                    &JobBudget::new(1),
+                )?;
+            }
+        }
+        let serial = start.elapsed() / ROUNDS;
+
+        let opts = TraverseOptions::new();
+        let start = Instant::now();
+        for _ in 0..ROUNDS {
+            traverse_etc_with(&p, &c, Some(&n), &opts)?;
+        }
+        let concurrent = start.elapsed() / ROUNDS;
+
+        println!(
+            "serial: {serial:?}, concurrent: {concurrent:?} with {} job(s), speedup: {:.2}x",
+            opts.effective_jobs(),
+            serial.as_secs_f64() / concurrent.as_secs_f64()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_job_budget() {
+        let budget = JobBudget::new(3);
+
+        let permit = budget.acquire();
+        assert_eq!(budget.try_acquire(5).count, 2);
+
+        // The helpers above were given back once dropped
+        let helpers = budget.try_acquire(5);
+        assert_eq!(helpers.count, 2);
+        assert_eq!(budget.try_acquire(5).count, 0);
+
+        drop((permit, helpers));
+        assert_eq!(budget.try_acquire(5).count, 3);
+    }
+
+    #[test]
+    fn test_parallel_hashing_first_error() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        for i in 0..100 {
+            let content = if i % 10 == 3 { "fail" } else { "ok" };
+            tempdir.write(format!("file{i}"), content)?;
+        }
+
+        let hasher = |reader: &mut dyn Read| {
+            let mut content = String::new();
+            reader.read_to_string(&mut content)?;
+            anyhow::ensure!(content != "fail", "bad content");
+            Sha256ContentHasher.hash(&mut content.as_bytes())
+        };
+
+        let opts = TraverseOptions::new().hasher(&hasher);
+        let scan = |jobs| {
+            scan_tree(
+                &tempdir,
+                &VerityProbe::default(),
+                &opts,
+                &JobBudget::new(jobs),
+            )
+            .map(|_| ())
+            .unwrap_err()
+        };
+
+        // The same file is reported as with a serial scan, whichever thread got to it first
+        let serial = format!("{:#}", scan(1));
+        for _ in 0..10 {
+            assert_eq!(format!("{:#}", scan(4)), serial);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parallel_hashing_is_deterministic() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for i in 0..200 {
+            let file = format!("dir{}/file{i}", i % 7);
+
+            for dir in [&p, &c, &n] {
+                dir.create_dir_all(format!("dir{}", i % 7))?;
+            }
+
+            p.write(&file, format!("pristine-{i}"))?;
+            n.write(&file, format!("pristine-{i}"))?;
+
+            match i % 4 {
+                0 => c.write(&file, format!("current-{i}"))?,
+                1 => c.write(&file, format!("pristine-{i}"))?,
+                2 => c.write(format!("{file}.added"), "added")?,
+                _ => {}
+            }
+        }
+
+        let diff_with = |jobs: usize| -> anyhow::Result<_> {
+            let opts = TraverseOptions::new().jobs(NonZeroUsize::new(jobs).unwrap());
+            let (pristine_etc_files, current_etc_files, new_etc_files) =
+                traverse_etc_with(&p, &c, Some(&n), &opts)?;
+            let diff = compute_diff(
+                &pristine_etc_files,
+                &current_etc_files,
+                new_etc_files.as_ref().unwrap(),
+            )?;
+
+            Ok((diff.added, diff.modified, diff.removed))
+        };
+
+        let serial = diff_with(1)?;
+        assert_eq!(serial.0.len(), 50);
+        assert_eq!(serial.1.len(), 50);
+        assert_eq!(serial.2.len(), 100);
+
+        for paths in [&serial.0, &serial.1, &serial.2] {
+            assert!(paths.is_sorted());
+        }
+
+        for jobs in [2, 8, 64] {
+            assert_eq!(diff_with(jobs)?, serial);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_modification_classification() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for file in ["content", "mode", "both", "untouched"] {
+            p.write(file, file)?;
+            c.write(file, file)?;
+        }
+
+        c.write("content", "new content")?;
+
+        c.open("mode")?
+            .set_permissions(cap_std::fs::Permissions::from_mode(0o400))?;
+
+        c.write("both", "new both")?;
+        c.open("both")?
+            .set_permissions(cap_std::fs::Permissions::from_mode(0o600))?;
+
+        let (.., diff) = traverse_and_diff(&p, &c, &n)?;
+
+        assert_eq!(diff.content_changed, [PathBuf::from("content")]);
+        assert_eq!(diff.metadata_changed, [PathBuf::from("mode")]);
+        assert_eq!(diff.both_changed, [PathBuf::from("both")]);
+
+        assert_eq!(
+            diff.modified,
+            ["both", "content", "mode"].map(PathBuf::from)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_display_root() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        p.create_dir_all("a/b")?;
+        p.write("a/b/modified", "old")?;
+        p.write("removed", "removed")?;
+
+        c.create_dir_all("a/b")?;
+        c.write("a/b/modified", "new")?;
+        c.write("added", "added")?;
+
+        let (pristine_etc_files, current_etc_files, new_etc_files) =
+            traverse_etc(&p, &c, Some(&n))?;
+        let new_etc_files = new_etc_files.as_ref().unwrap();
+
+        let diff = compute_diff_with_root(
+            &pristine_etc_files,
+            &current_etc_files,
+            new_etc_files,
+            Some(Path::new("/etc")),
+        )?;
+
+        assert_eq!(diff.display_root(), Some(Path::new("/etc")));
+        assert_eq!(diff.added(), [PathBuf::from("/etc/added")]);
+        assert_eq!(diff.modified(), [PathBuf::from("/etc/a/b/modified")]);
+        assert_eq!(diff.content_changed(), [PathBuf::from("/etc/a/b/modified")]);
+        assert_eq!(diff.removed(), [PathBuf::from("/etc/removed")]);
+
+        let err = merge(&c, &current_etc_files, &n, new_etc_files, &diff).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("prefixed with \"/etc\""),
+            "{err:#}"
+        );
+
+        // Without a display root, paths stay relative to /etc
+        let diff = compute_diff(&pristine_etc_files, &current_etc_files, new_etc_files)?;
+        assert_eq!(diff.display_root(), None);
+        assert_eq!(diff.modified(), [PathBuf::from("a/b/modified")]);
+
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs root to change the ownership of files"]
+    fn test_ownership_change() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for file in ["chowned", "untouched"] {
+            p.write(file, file)?;
+            c.write(file, file)?;
+        }
+
+        let owner = c.metadata("chowned")?;
+        let (uid, gid) = (owner.uid() + 1000, owner.gid() + 1000);
+
+        rustix::fs::chownat(
+            &c,
+            "chowned",
+            Some(Uid::from_raw(uid)),
+            Some(Gid::from_raw(gid)),
+            AtFlags::empty(),
+        )
+        .context("Changing ownership")?;
+
+        let (_, current_etc_files, new_etc_files, diff) = traverse_and_diff(&p, &c, &n)?;
+
+        assert_eq!(diff.modified(), [PathBuf::from("chowned")]);
+        assert_eq!(diff.metadata_changed(), [PathBuf::from("chowned")]);
+
+        // The new owner is preserved by the merge
+        merge(&c, &current_etc_files, &n, &new_etc_files, &diff)?;
+        let merged = n.metadata("chowned")?;
+        assert_eq!((merged.uid(), merged.gid()), (uid, gid));
+
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs TMPDIR on a filesystem supporting user xattrs"]
+    fn test_xattr_change() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for file in ["relabeled", "untouched"] {
+            p.write(file, file)?;
+            c.write(file, file)?;
+        }
+
+        // Larger than the initial buffer used to read xattrs
+        let value = vec![b'x'; 300];
+        let path = Path::new(&format!("/proc/self/fd/{}", c.as_raw_fd())).join("relabeled");
+        lsetxattr(&path, "user.test", &value, XattrFlags::empty()).context("Setting xattr")?;
+
+        let (_, current_etc_files, new_etc_files, diff) = traverse_and_diff(&p, &c, &n)?;
+        assert_eq!(diff.modified(), [PathBuf::from("relabeled")]);
+        assert_eq!(diff.metadata_changed(), [PathBuf::from("relabeled")]);
+
+        merge(&c, &current_etc_files, &n, &new_etc_files, &diff)?;
+        let xattrs = collect_xattrs(&n, "relabeled")?;
+        assert_eq!(
+            xattrs.borrow().get(OsStr::new("user.test")).map(|v| &**v),
+            Some(value.as_slice())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned_verity_algorithm() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        p.write("file", "a")?;
+        c.write("file", "b")?;
+        c.write("added", "c")?;
+        n.write("file", "a")?;
+
+        let summarize = |opts: &TraverseOptions| -> anyhow::Result<_> {
+            let (pristine_etc_files, current_etc_files, new_etc_files) =
+                traverse_etc_with(&p, &c, Some(&n), opts)?;
+            let diff = compute_diff(
+                &pristine_etc_files,
+                &current_etc_files,
+                new_etc_files.as_ref().unwrap(),
+            )?;
+
+            Ok((diff.added, diff.content_changed, diff.removed))
+        };
+
+        // Files without fsverity enabled are hashed the same regardless of the algorithm
+        let detected = summarize(&TraverseOptions::new())?;
+        assert_eq!(detected.0, [PathBuf::from("added")]);
+        assert_eq!(detected.1, [PathBuf::from("file")]);
+
+        let sha256 = summarize(&TraverseOptions::new().verity_algorithm::<Sha256HashValue>())?;
+        let sha512 = summarize(&TraverseOptions::new().verity_algorithm::<Sha512HashValue>())?;
+
+        assert_eq!(sha256, detected);
+        assert_eq!(sha512, detected);
+
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs TMPDIR on a filesystem supporting fsverity, e.g. ext4 with the verity feature"]
+    fn test_pinned_verity_algorithm_enabled() -> anyhow::Result<()> {
+        use composefs::fsverity::{enable_verity_raw, measure_verity};
+
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        p.write("file", "a")?;
+        c.write("file", "a")?;
+
+        let file = c.open("file")?;
+        enable_verity_raw::<Sha256HashValue>(&file).context("Enabling fsverity")?;
+        let digest = measure_verity::<Sha256HashValue>(&file)?.to_hex();
+        drop(file);
+
+        let opts = TraverseOptions::new().verity_algorithm::<Sha256HashValue>();
+        let diff = diff_etc_with(&p, &c, &n, &opts)?;
+        assert_eq!(
+            diff.verity_changed(),
+            [VerityChange {
+                path: "file".into(),
+                old_verity: None,
+                new_verity: Some(digest),
+            }]
+        );
+
+        // Verity was enabled with another algorithm than the pinned one
+        let opts = TraverseOptions::new().verity_algorithm::<Sha512HashValue>();
+        assert!(diff_etc_with(&p, &c, &n, &opts).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for dir in [&p, &c, &n] {
+            dir.create_dir_all("a/cache")?;
+        }
+
+        p.write("a/db.lock", "1")?;
+        c.write("a/db.lock", "2")?;
+        p.write("stale.lock", "1")?;
+        c.write("added.lock", "1")?;
+        c.write("a/cache/entry", "1")?;
+        p.write("file", "a")?;
+        c.write("file", "b")?;
+
+        let exclude = |path: &Path| {
+            path.extension().is_some_and(|ext| ext == "lock") || path == Path::new("a/cache")
+        };
+
+        let (pristine_etc_files, current_etc_files, new_etc_files) =
+            traverse_etc_with(&p, &c, Some(&n), &TraverseOptions::new().exclude(&exclude))?;
+
+        assert!(
+            pristine_etc_files
+                .ref_leaf(OsStr::new("stale.lock"))
+                .is_err()
+        );
+
+        let diff = compute_diff(
+            &pristine_etc_files,
+            &current_etc_files,
+            new_etc_files.as_ref().unwrap(),
+        )?;
+
+        assert!(diff.added.is_empty());
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.modified, [PathBuf::from("file")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_summary() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        p.write("modified", "a")?;
+        c.write("modified", "b")?;
+        p.write("removed", "a")?;
+        c.write("added", "a")?;
+        c.write(OsStr::from_bytes(b"added-\xff"), "a")?;
+
+        let diff = diff_etc(&p, &c, &n)?;
+
+        let summary = diff.summary();
+        assert_eq!(
+            summary,
+            DiffSummary {
+                added: 2,
+                modified: 1,
+                removed: 1,
+                replaced: 0,
+            }
+        );
+        assert_eq!(
+            summary.to_string(),
+            "2 added, 1 modified, 1 removed, 0 replaced"
+        );
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_value(&diff)?;
+
+            assert_eq!(
+                json["added"],
+                serde_json::json!(["added", [97, 100, 100, 101, 100, 45, 255]])
+            );
+            assert_eq!(json["modified"], serde_json::json!(["modified"]));
+            assert_eq!(json["content_changed"], serde_json::json!(["modified"]));
+            assert_eq!(json["removed"], serde_json::json!(["removed"]));
+            assert_eq!(json["replaced"], serde_json::json!([]));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_symlink_retargeted() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        p.symlink("zoneinfo/UTC", "localtime")?;
+        c.symlink("zoneinfo/Europe/Berlin", "localtime")?;
+        p.symlink("unchanged", "same")?;
+        c.symlink("unchanged", "same")?;
+        p.write("was-file", "a")?;
+        c.symlink("target", "was-file")?;
+
+        let diff = diff_etc(&p, &c, &n)?;
+
+        assert_eq!(
+            diff.retargeted(),
+            [SymlinkChange {
+                path: "localtime".into(),
+                old_target: "zoneinfo/UTC".into(),
+                new_target: "zoneinfo/Europe/Berlin".into(),
+            }]
+        );
+        assert_eq!(
+            diff.modified(),
+            [PathBuf::from("localtime"), PathBuf::from("was-file")]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    #[ignore = "needs to run unprivileged, permissions aren't enforced with CAP_DAC_OVERRIDE"]
+    fn test_unreadable_file_skipped() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        p.write("secret", "a")?;
+        c.write("secret", "b")?;
+        c.write("added-secret", "b")?;
+        p.write("file", "a")?;
+        c.write("file", "b")?;
+
+        for file in ["secret", "added-secret"] {
+            c.set_permissions(file, Permissions::from_mode(0o000))?;
+        }
+
+        assert!(
+            c.open("secret").is_err(),
+            "permissions are not enforced, most likely running with CAP_DAC_OVERRIDE"
+        );
+
+        let diff = diff_etc(&p, &c, &n)?;
+
+        assert_eq!(
+            diff.skipped(),
+            ["added-secret", "secret"].map(|path| SkippedPath {
+                path: path.into(),
+                kind: std::io::ErrorKind::PermissionDenied,
+            })
+        );
+        assert!(diff.added().is_empty());
+        assert_eq!(diff.modified(), [PathBuf::from("file")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hardlinks() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
 
-            Some(new_etc_files)
+        for dir in ["pristine_etc", "current_etc", "new_etc", "target"] {
+            tempdir.create_dir(dir)?;
         }
 
-        None => None,
-    };
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+        let n = tempdir.open_dir("new_etc")?;
+        let t = tempdir.open_dir("target")?;
 
-    return Ok((pristine_etc_files, current_etc_files, new_etc_files));
-}
+        // Linked in the pristine /etc, but copies in the current one
+        p.write("a", "1")?;
+        p.hard_link("a", &p, "b")?;
+        c.write("a", "1")?;
+        c.write("b", "1")?;
+        n.write("a", "1")?;
+        n.write("b", "1")?;
 
-/// Computes the differences between two directory snapshots.
-#[context("Computing diff")]
-pub fn compute_diff(
-    pristine_etc_files: &Directory<CustomMetadata>,
-    current_etc_files: &Directory<CustomMetadata>,
-    new_etc_files: &Directory<CustomMetadata>,
-) -> anyhow::Result<Diff> {
-    let mut diff = Diff {
-        added: vec![],
-        modified: vec![],
-        removed: vec![],
-    };
+        // Linked files added by the user
+        c.create_dir("dir")?;
+        c.write("x", "2")?;
+        c.hard_link("x", &c, "dir/y")?;
 
-    get_modifications(
-        &pristine_etc_files,
-        &current_etc_files,
-        &new_etc_files,
-        PathBuf::new(),
-        &mut diff,
-    )?;
+        let diff = apply_merge(&p, &c, &n, &t)?;
 
-    get_deletions(
-        &pristine_etc_files,
-        &current_etc_files,
-        PathBuf::new(),
-        &mut diff,
-    )?;
+        assert_eq!(diff.hardlinks(), [vec![PathBuf::from("dir/y"), "x".into()]]);
+        assert_eq!(
+            diff.diverged_hardlinks(),
+            [vec![PathBuf::from("a"), "b".into()]]
+        );
 
-    Ok(diff)
-}
+        // Hashed once, but both paths are reported
+        assert_eq!(diff.added(), ["dir", "dir/y", "x"].map(PathBuf::from));
 
-/// Prints a colorized summary of differences to standard output.
-pub fn print_diff(diff: &Diff, writer: &mut impl Write) {
-    use owo_colors::OwoColorize;
+        assert_eq!(t.metadata("x")?.ino(), t.metadata("dir/y")?.ino());
+        assert_ne!(t.metadata("a")?.ino(), t.metadata("b")?.ino());
 
-    for added in &diff.added {
-        let _ = writeln!(writer, "{} {added:?}", ModificationType::Added.green());
+        Ok(())
     }
 
-    for modified in &diff.modified {
-        let _ = writeln!(writer, "{} {modified:?}", ModificationType::Modified.cyan());
-    }
+    #[test]
+    fn test_hash_cache() -> anyhow::Result<()> {
+        use std::sync::atomic::AtomicUsize;
 
-    for removed in &diff.removed {
-        let _ = writeln!(writer, "{} {removed:?}", ModificationType::Removed.red());
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for dir in [&p, &c, &n] {
+            dir.create_dir_all("a/b/c")?;
+
+            for (file, content) in FILES {
+                dir.write(file, content.as_bytes())?;
+            }
+        }
+
+        c.write("a/b/file1", "modified")?;
+        c.write("added", "added")?;
+
+        let hashed = AtomicUsize::new(0);
+        let hasher = |reader: &mut dyn Read| {
+            hashed.fetch_add(1, Ordering::Relaxed);
+            Sha256ContentHasher.hash(reader)
+        };
+
+        let cache = HashCache::new();
+        let opts = TraverseOptions::new().hasher(&hasher).cache(&cache);
+
+        let cold = diff_etc_with(&p, &c, &n, &opts)?;
+        let files = hashed.load(Ordering::Relaxed);
+        assert_eq!(files, 3 * FILES.len() + 1);
+        assert_eq!(cache.len(), files);
+
+        // Nothing changed, so nothing is hashed again
+        let warm = diff_etc_with(&p, &c, &n, &opts)?;
+        assert_eq!(hashed.load(Ordering::Relaxed), files);
+        assert_eq!(format!("{warm:?}"), format!("{cold:?}"));
+
+        // Same size, but with another modification time. It's set explicitly as the write could
+        // otherwise land within the timestamp granularity of the previous one.
+        c.write("added", "ADDED")?;
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        c.open("added")?.into_std().set_modified(mtime)?;
+        let diff = diff_etc_with(&p, &c, &n, &opts)?;
+        assert_eq!(hashed.load(Ordering::Relaxed), files + 1);
+        assert_eq!(format!("{diff:?}"), format!("{cold:?}"));
+
+        // Modifying a pristine file is picked up
+        p.write("a/b/file1", "modified")?;
+        let diff = diff_etc_with(&p, &c, &n, &opts)?;
+        assert_eq!(hashed.load(Ordering::Relaxed), files + 2);
+        assert!(!diff.modified().contains(&PathBuf::from("a/b/file1")));
+
+        // A file modified after the scan started is never cached
+        let future = std::time::SystemTime::now() + std::time::Duration::from_secs(3600);
+        c.open("added")?.into_std().set_modified(future)?;
+        diff_etc_with(&p, &c, &n, &opts)?;
+        assert_eq!(hashed.load(Ordering::Relaxed), files + 3);
+        diff_etc_with(&p, &c, &n, &opts)?;
+        assert_eq!(hashed.load(Ordering::Relaxed), files + 4);
+
+        cache.clear();
+        assert!(cache.is_empty());
+
+        Ok(())
     }
-}
 
-#[context("Collecting xattrs")]
-fn collect_xattrs(etc_fd: &CapStdDir, rel_path: impl AsRef<Path>) -> anyhow::Result<Xattrs> {
-    let link = format!("/proc/self/fd/{}", etc_fd.as_fd().as_raw_fd());
-    let path = Path::new(&link).join(rel_path);
+    #[test]
+    fn test_progress() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
 
-    const DEFAULT_SIZE: usize = 128;
+        for dir in [&p, &c, &n] {
+            dir.create_dir_all("a/b")?;
+            dir.write("a/b/file", "a")?;
+        }
 
-    // Start with a guess for size
-    let mut xattrs_name_buf: Vec<u8> = vec![0; DEFAULT_SIZE];
-    let mut size = llistxattr(&path, &mut xattrs_name_buf).context("llistxattr")?;
+        c.write("a/added", "b")?;
+        c.symlink("a/added", "link")?;
+        c.hard_link("a/added", &c, "a/b/hardlink")?;
+        n.write("a/file", "c")?;
+
+        let seen = Mutex::new(vec![]);
+        let progress = |path: &Path| seen.lock().unwrap().push(path.to_owned());
+
+        let with_progress = diff_etc_with(&p, &c, &n, &TraverseOptions::new().progress(&progress))?;
+        let without_progress = diff_etc(&p, &c, &n)?;
+
+        assert_eq!(with_progress.added(), without_progress.added());
+        assert_eq!(with_progress.modified(), without_progress.modified());
+        assert_eq!(with_progress.removed(), without_progress.removed());
+
+        let mut seen = seen.into_inner().unwrap();
+        seen.sort();
+
+        let mut expected: Vec<_> = [
+            // pristine
+            &["a", "a/b", "a/b/file"][..],
+            // current
+            &["a", "a/b", "a/b/file", "a/added", "a/b/hardlink", "link"],
+            // new
+            &["a", "a/b", "a/b/file", "a/file"],
+        ]
+        .concat()
+        .into_iter()
+        .map(PathBuf::from)
+        .collect();
+        expected.sort();
+
+        assert_eq!(seen, expected);
 
-    if size > xattrs_name_buf.capacity() {
-        xattrs_name_buf.resize(size, 0);
-        size = llistxattr(&path, &mut xattrs_name_buf).context("llistxattr")?;
+        Ok(())
     }
 
-    let xattrs: Xattrs = RefCell::new(BTreeMap::new());
+    #[test]
+    fn test_custom_content_hasher() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
 
-    for name_buf in xattrs_name_buf[..size]
-        .split(|&b| b == 0)
-        .filter(|x| !x.is_empty())
-    {
-        let name = OsStr::from_bytes(name_buf);
+        tempdir.create_dir("pristine_etc")?;
+        tempdir.create_dir("current_etc")?;
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+
+        p.write("file", "abc")?;
+        c.write("file", "abcd")?;
+
+        let hasher = |reader: &mut dyn Read| -> anyhow::Result<String> {
+            let mut buf = vec![];
+            reader.read_to_end(&mut buf)?;
+            Ok(format!("len-{}", buf.len()))
+        };
+
+        let (pristine_etc_files, current_etc_files, _) =
+            traverse_etc_with_hasher(&p, &c, None, &hasher)?;
 
-        let mut xattrs_value_buf = vec![0; DEFAULT_SIZE];
-        let mut size = lgetxattr(&path, name_buf, &mut xattrs_value_buf).context("lgetxattr")?;
+        for (tree, expected) in [
+            (&pristine_etc_files, "len-3"),
+            (&current_etc_files, "len-4"),
+        ] {
+            let LeafContent::Regular(meta) = &tree.ref_leaf(OsStr::new("file"))?.content else {
+                panic!("Expected a regular file");
+            };
 
-        if size > xattrs_value_buf.capacity() {
-            xattrs_value_buf.resize(size, 0);
-            size = lgetxattr(&path, name_buf, &mut xattrs_value_buf).context("lgetxattr")?;
+            assert_eq!(meta.content_hash, expected);
         }
 
-        xattrs.borrow_mut().insert(
-            Box::<OsStr>::from(name),
-            Box::<[u8]>::from(&xattrs_value_buf[..size]),
-        );
+        let diff = compute_diff(
+            &pristine_etc_files,
+            &current_etc_files,
+            &Directory::new(Stat::uninitialized()),
+        )?;
+        assert_eq!(diff.content_changed, [PathBuf::from("file")]);
+
+        Ok(())
     }
 
-    Ok(xattrs)
-}
+    fn test_stat(mode: u32, xattrs: &[(&str, &[u8])]) -> Stat {
+        Stat {
+            st_mode: mode,
+            st_uid: 0,
+            st_gid: 0,
+            st_mtim_sec: 0,
+            xattrs: RefCell::new(
+                xattrs
+                    .iter()
+                    .map(|(k, v)| (Box::from(OsStr::new(k)), Box::from(*v)))
+                    .collect(),
+            ),
+        }
+    }
 
-#[context("Copying xattrs")]
-fn copy_xattrs(xattrs: &Xattrs, new_etc_fd: &CapStdDir, path: &Path) -> anyhow::Result<()> {
-    for (attr, value) in xattrs.borrow().iter() {
-        let fdpath = &Path::new(&format!("/proc/self/fd/{}", new_etc_fd.as_raw_fd())).join(path);
-        lsetxattr(fdpath, attr.as_ref(), value, XattrFlags::empty())
-            .with_context(|| format!("setxattr {attr:?} for {fdpath:?}"))?;
+    fn test_file(name: &str, content: &str) -> (OsString, ScannedEntry) {
+        (
+            name.into(),
+            ScannedEntry::Leaf(
+                test_stat(0o100644, &[]),
+                LeafContent::Regular(CustomMetadata::new(content.into(), None)),
+            ),
+        )
     }
 
-    Ok(())
-}
+    fn test_verity_file(name: &str, verity: Option<&str>) -> (OsString, ScannedEntry) {
+        let metadata = match verity {
+            Some(verity) => CustomMetadata::new("".into(), Some(verity.into())),
+            None => CustomMetadata::new("content".into(), None),
+        };
 
-fn recurse_dir(dir: &CapStdDir, root: &mut Directory<CustomMetadata>) -> anyhow::Result<()> {
-    for entry in dir.entries()? {
-        let entry = entry.context(format!("Getting entry"))?;
-        let entry_name = entry.file_name();
+        (
+            name.into(),
+            ScannedEntry::Leaf(test_stat(0o100644, &[]), LeafContent::Regular(metadata)),
+        )
+    }
 
-        let entry_type = entry.file_type()?;
+    #[test]
+    fn test_diff_compose() -> anyhow::Result<()> {
+        let tree = |entries| {
+            let mut root = Directory::new(Stat::uninitialized());
+            build_tree(entries, &mut root);
+            root
+        };
 
-        let entry_meta = entry
-            .metadata()
-            .context(format!("Getting metadata for {entry_name:?}"))?;
+        let pristine = tree(vec![
+            test_file("modified", "a"),
+            test_file("removed", "a"),
+            test_file("readded", "a"),
+            test_file("untouched", "a"),
+        ]);
+
+        let current = tree(vec![
+            test_file("added", "b"),
+            test_file("transient", "b"),
+            test_file("modified", "b"),
+            test_file("untouched", "a"),
+        ]);
+
+        let later_current = tree(vec![
+            test_file("added", "c"),
+            test_file("modified", "c"),
+            test_file("readded", "c"),
+            test_file("untouched", "a"),
+        ]);
+
+        let new = Directory::new(Stat::uninitialized());
+        let earlier = compute_diff(&pristine, &current, &new)?;
+        let later = compute_diff(&current, &later_current, &new)?;
+
+        let diff = earlier.compose(&later)?;
+
+        // Added then removed cancels, added then modified stays added
+        assert_eq!(diff.added, [PathBuf::from("added")]);
+        // Modified then modified collapses, removed then added is modified
+        assert_eq!(diff.modified, ["modified", "readded"].map(PathBuf::from));
+        assert_eq!(diff.content_changed, [PathBuf::from("modified")]);
+        assert_eq!(diff.both_changed, [PathBuf::from("readded")]);
+        assert_eq!(diff.removed, [PathBuf::from("removed")]);
+        assert!(diff.replaced.is_empty());
+
+        // The same as diffing directly, here
+        let direct = compute_diff(&pristine, &later_current, &new)?;
+        assert_eq!(diff.summary(), direct.summary());
+
+        assert!(!diff.is_empty());
+        assert!(compute_diff(&pristine, &pristine, &new)?.is_empty());
+
+        let empty = Diff::default();
+        assert!(empty.compose(&empty)?.is_empty());
 
-        let xattrs = collect_xattrs(&dir, &entry_name)?;
+        Ok(())
+    }
 
-        // Do symlinks first as we don't want to follow back up any symlinks
-        if entry_type.is_symlink() {
-            let readlinkat_result = readlinkat(&dir, &entry_name, vec![])
-                .context(format!("readlinkat {entry_name:?}"))?;
+    #[test]
+    fn test_diff_compose_modifications() -> anyhow::Result<()> {
+        let tree = |entries| {
+            let mut root = Directory::new(Stat::uninitialized());
+            build_tree(entries, &mut root);
+            root
+        };
 
-            let os_str = OsStr::from_bytes(readlinkat_result.as_bytes());
+        let pristine = tree(vec![
+            test_verity_file("verity", None),
+            test_file("content", "a"),
+            test_file("mode", "a"),
+        ]);
+
+        let current = tree(vec![
+            test_verity_file("verity", Some("aa")),
+            test_file("content", "b"),
+            (
+                "mode".into(),
+                ScannedEntry::Leaf(
+                    test_stat(0o100600, &[]),
+                    LeafContent::Regular(CustomMetadata::new("a".into(), None)),
+                ),
+            ),
+        ]);
+
+        let later_current = tree(vec![
+            test_verity_file("verity", Some("aa")),
+            test_file("content", "c"),
+            test_file("mode", "b"),
+        ]);
+
+        let new = Directory::new(Stat::uninitialized());
+        let earlier = compute_diff(&pristine, &current, &new)?;
+        let later = compute_diff(&current, &later_current, &new)?;
+
+        let diff = earlier.compose(&later)?;
 
-            root.insert(
-                &entry_name,
-                Inode::Leaf(Rc::new(Leaf {
-                    stat: MyStat::from((&entry_meta, xattrs)).0,
-                    content: LeafContent::Symlink(Box::from(os_str)),
-                })),
-            );
+        assert_eq!(
+            diff.modified,
+            ["content", "mode", "verity"].map(PathBuf::from)
+        );
+        assert_eq!(
+            diff.content_changed,
+            ["content", "verity"].map(PathBuf::from)
+        );
+        // Only the metadata changed first, then both
+        assert_eq!(diff.both_changed, [PathBuf::from("mode")]);
+        assert_eq!(
+            diff.verity_changed(),
+            [VerityChange {
+                path: "verity".into(),
+                old_verity: None,
+                new_verity: Some("aa".into()),
+            }]
+        );
 
-            continue;
-        }
+        let rooted = compute_diff_with_root(&pristine, &current, &new, Some(Path::new("/etc")))?;
+        assert!(rooted.compose(&later).is_err());
 
-        if entry_type.is_dir() {
-            let dir = dir
-                .open_dir(&entry_name)
-                .with_context(|| format!("Opening dir {entry_name:?} inside {dir:?}"))?;
+        Ok(())
+    }
 
-            let mut directory = Directory::new(MyStat::from((&entry_meta, xattrs)).0);
+    #[test]
+    fn test_verity_changed() -> anyhow::Result<()> {
+        let mut pristine = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![
+                test_verity_file("enabled", None),
+                test_verity_file("disabled", Some("aa")),
+                test_verity_file("updated", Some("bb")),
+                test_verity_file("unchanged", Some("cc")),
+            ],
+            &mut pristine,
+        );
 
-            recurse_dir(&dir, &mut directory)?;
+        let mut current = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![
+                test_verity_file("enabled", Some("dd")),
+                test_verity_file("disabled", None),
+                test_verity_file("updated", Some("ee")),
+                test_verity_file("unchanged", Some("cc")),
+            ],
+            &mut current,
+        );
 
-            root.insert(&entry_name, Inode::Directory(Box::new(directory)));
+        let diff = compute_diff(&pristine, &current, &Directory::new(Stat::uninitialized()))?;
 
-            continue;
-        }
+        assert_eq!(
+            diff.verity_changed(),
+            [
+                VerityChange {
+                    path: "disabled".into(),
+                    old_verity: Some("aa".into()),
+                    new_verity: None,
+                },
+                VerityChange {
+                    path: "enabled".into(),
+                    old_verity: None,
+                    new_verity: Some("dd".into()),
+                },
+            ]
+        );
 
-        if !(entry_type.is_symlink() || entry_type.is_file()) {
-            // We cannot read any other device like socket, pipe, fifo.
-            // We shouldn't really find these in /etc in the first place
-            tracing::debug!("Ignoring non-regular/non-symlink file: {:?}", entry_name);
-            continue;
-        }
+        // A new digest is a plain content change
+        assert_eq!(
+            diff.content_changed(),
+            ["disabled", "enabled", "updated"].map(PathBuf::from)
+        );
 
-        // TODO: Another generic here but constrained to Sha256HashValue
-        // Regarding this, we'll definitely get DigestMismatch error if SHA512 is being used
-        // So we query the verity again if we get a DigestMismatch error
-        let measured_verity =
-            composefs::fsverity::measure_verity_opt::<Sha256HashValue>(entry.open()?);
+        let Some(Inode::Leaf(leaf)) = current.lookup(OsStr::new("enabled")) else {
+            panic!("enabled is not a file");
+        };
+        let LeafContent::Regular(metadata) = &leaf.content else {
+            panic!("enabled is not a regular file");
+        };
+        assert!(metadata.is_verity_enabled());
+        assert_eq!(metadata.verity(), Some("dd"));
 
-        let measured_verity = match measured_verity {
-            Ok(mv) => mv.map(|verity| verity.to_hex()),
+        Ok(())
+    }
 
-            Err(composefs::fsverity::MeasureVerityError::InvalidDigestAlgorithm { .. }) => {
-                composefs::fsverity::measure_verity_opt::<Sha512HashValue>(entry.open()?)?
-                    .map(|verity| verity.to_hex())
-            }
+    fn test_dir(
+        name: &str,
+        xattrs: &[(&str, &[u8])],
+        children: Vec<(OsString, ScannedEntry)>,
+    ) -> (OsString, ScannedEntry) {
+        (
+            name.into(),
+            ScannedEntry::Directory(test_stat(0o40755, xattrs), children),
+        )
+    }
 
-            Err(e) => Err(e)?,
-        };
+    fn test_whiteout(name: &str) -> (OsString, ScannedEntry) {
+        (
+            name.into(),
+            ScannedEntry::Leaf(test_stat(0o20000, &[]), LeafContent::CharacterDevice(0)),
+        )
+    }
 
-        if let Some(measured_verity) = measured_verity {
-            root.insert(
-                &entry_name,
-                Inode::Leaf(Rc::new(Leaf {
-                    stat: MyStat::from((&entry_meta, xattrs)).0,
-                    content: LeafContent::Regular(CustomMetadata::new(
-                        "".into(),
-                        Some(measured_verity),
-                    )),
-                })),
-            );
+    #[test]
+    fn test_overlay_whiteouts() -> anyhow::Result<()> {
+        let mut pristine = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![
+                test_dir("dir", &[], vec![test_file("file", "a")]),
+                test_file("file", "b"),
+                test_file("kept", "c"),
+            ],
+            &mut pristine,
+        );
 
-            continue;
+        let mut current = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![
+                test_whiteout("dir"),
+                test_whiteout("file"),
+                test_file("kept", "c"),
+                // Whiteout for something which was never there
+                test_whiteout("never-existed"),
+                // Whiteouts in added directories are ignored
+                test_dir("new", &[], vec![test_whiteout("whiteout")]),
+            ],
+            &mut current,
+        );
+
+        let diff = compute_diff(&pristine, &current, &Directory::new(Stat::uninitialized()))?;
+
+        assert_eq!(diff.removed, ["dir", "file"].map(PathBuf::from));
+        assert!(diff.modified.is_empty());
+        assert_eq!(diff.added, [PathBuf::from("new")]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_sorted() -> anyhow::Result<()> {
+        let mut pristine = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![
+                test_file("a", "a"),
+                test_file("b", "b"),
+                test_dir("c", &[], vec![test_file("file", "c")]),
+                test_file("d", "d"),
+            ],
+            &mut pristine,
+        );
+
+        // "b" is deleted through a whiteout, the others by not being there at all
+        let mut current = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![
+                test_whiteout("b"),
+                test_dir("c", &[], vec![]),
+                test_dir("c-new", &[], vec![test_file("file", "e")]),
+                test_file("d", "changed"),
+                test_file("e", "e"),
+            ],
+            &mut current,
+        );
+
+        let diff = compute_diff(&pristine, &current, &Directory::new(Stat::uninitialized()))?;
+
+        assert_eq!(diff.removed, ["a", "b", "c/file"].map(PathBuf::from));
+        assert_eq!(diff.added, ["c-new", "c-new/file", "e"].map(PathBuf::from));
+
+        for paths in [&diff.added, &diff.modified, &diff.removed] {
+            assert!(paths.is_sorted());
         }
 
-        let mut hasher = openssl::hash::Hasher::new(openssl::hash::MessageDigest::sha256())?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_compute_diff_detailed() -> anyhow::Result<()> {
+        let mode_changed = |name: &str, content: &str| {
+            (
+                OsString::from(name),
+                ScannedEntry::Leaf(
+                    test_stat(0o100600, &[]),
+                    LeafContent::Regular(CustomMetadata::new(content.into(), None)),
+                ),
+            )
+        };
 
-        let file = entry
-            .open()
-            .context(format!("Opening entry {entry_name:?}"))?;
+        let mut pristine = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![
+                test_file("both", "a"),
+                test_file("content", "a"),
+                test_file("metadata", "a"),
+                test_file("removed", "a"),
+                test_file("replaced", "a"),
+                test_file("type", "a"),
+                test_file("untouched", "a"),
+                test_verity_file("verity", None),
+            ],
+            &mut pristine,
+        );
 
-        let mut reader = BufReader::new(file);
-        std::io::copy(&mut reader, &mut hasher)?;
+        let mut current = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![
+                test_file("added", "b"),
+                mode_changed("both", "b"),
+                test_file("content", "b"),
+                mode_changed("metadata", "a"),
+                test_dir("replaced", &[], vec![test_file("inner", "b")]),
+                (
+                    "type".into(),
+                    ScannedEntry::Leaf(
+                        test_stat(0o120777, &[]),
+                        LeafContent::Symlink(OsStr::new("untouched").into()),
+                    ),
+                ),
+                test_file("untouched", "a"),
+                test_verity_file("verity", Some("dd")),
+            ],
+            &mut current,
+        );
 
-        let content_digest = hex::encode(hasher.finish()?);
+        let changes =
+            compute_diff_detailed(&pristine, &current, &Directory::new(Stat::uninitialized()))?;
 
-        root.insert(
-            &entry_name,
-            Inode::Leaf(Rc::new(Leaf {
-                stat: MyStat::from((&entry_meta, xattrs)).0,
-                content: LeafContent::Regular(CustomMetadata::new(content_digest, None)),
-            })),
+        assert_eq!(
+            changes.into_iter().collect::<Vec<_>>(),
+            [
+                ("added", ChangeKind::Added),
+                ("both", ChangeKind::ContentAndMetadataModified),
+                ("content", ChangeKind::ContentModified),
+                ("metadata", ChangeKind::MetadataModified),
+                ("removed", ChangeKind::Removed),
+                (
+                    "replaced",
+                    ChangeKind::Replaced {
+                        from_kind: EntryKind::File,
+                        to_kind: EntryKind::Directory,
+                    },
+                ),
+                ("replaced/inner", ChangeKind::Added),
+                ("type", ChangeKind::TypeChanged),
+                ("verity", ChangeKind::VerityChanged),
+            ]
+            .map(|(path, kind)| (PathBuf::from(path), kind))
         );
+
+        Ok(())
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_overlay_opaque_dir() -> anyhow::Result<()> {
+        let mut pristine = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![test_dir(
+                "dir",
+                &[],
+                vec![test_file("kept", "a"), test_file("hidden", "b")],
+            )],
+            &mut pristine,
+        );
 
-#[derive(Debug)]
-enum ModificationType {
-    Added,
-    Modified,
-    Removed,
-}
+        let mut current = Directory::new(Stat::uninitialized());
+        build_tree(
+            vec![test_dir(
+                "dir",
+                &[("trusted.overlay.opaque", b"y")],
+                vec![test_file("kept", "a"), test_file("new", "c")],
+            )],
+            &mut current,
+        );
 
-impl std::fmt::Display for ModificationType {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        let diff = compute_diff(&pristine, &current, &Directory::new(Stat::uninitialized()))?;
+
+        assert_eq!(diff.both_changed, [PathBuf::from("dir")]);
+        assert_eq!(diff.modified, [PathBuf::from("dir")]);
+        assert_eq!(diff.added, [PathBuf::from("dir/new")]);
+        assert_eq!(diff.removed, [PathBuf::from("dir/hidden")]);
+
+        Ok(())
     }
-}
 
-impl ModificationType {
-    fn symbol(&self) -> &'static str {
-        match self {
-            ModificationType::Added => "+",
-            ModificationType::Modified => "~",
-            ModificationType::Removed => "-",
+    #[test]
+    fn test_type_changed() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for dir in [&p, &c] {
+            dir.create_dir("a")?;
+            dir.write("a/file1", "file1")?;
+            dir.write("a/file2", "file2")?;
+            dir.write("a/edited", "edited")?;
         }
-    }
-}
 
-fn create_dir_with_perms(
-    new_etc_fd: &CapStdDir,
-    dir_name: &PathBuf,
-    stat: &Stat,
-    new_inode: Option<&Inode<CustomMetadata>>,
-) -> anyhow::Result<()> {
-    // The new directory is not present in the new_etc, so we create it, else we only copy the
-    // metadata
-    if new_inode.is_none() {
-        // Here we use `create_dir_all` to create every parent as we will set the permissions later
-        // on. Due to the fact that we have an ordered (sorted) list of directories and directory
-        // entries and we have a DFS traversal, we will always have directory creation starting from
-        // the parent anyway.
-        //
-        // The exception being, if a directory is modified in the current_etc, and a new directory
-        // is added inside the modified directory, say `dir/prems` has its permissions modified and
-        // `dir/prems/new` is the new directory created. Since we handle added files/directories first,
-        // we will create the directories `perms/new` with directory `new` also getting its
-        // permissions set, but `perms` will not. `perms` will have its permissions set up when we
-        // handle the modified directories.
-        new_etc_fd
-            .create_dir_all(&dir_name)
-            .context(format!("Failed to create dir {dir_name:?}"))?;
-    }
+        // File turned into a directory
+        c.remove_file("a/file1")?;
+        c.create_dir("a/file1")?;
+        c.write("a/file1/inner", "inner")?;
 
-    new_etc_fd
-        .set_permissions(&dir_name, Permissions::from_mode(stat.st_mode))
-        .context(format!("Changing permissions for dir {dir_name:?}"))?;
+        // File turned into a symlink
+        c.remove_file("a/file2")?;
+        c.symlink("edited", "a/file2")?;
 
-    rustix::fs::chownat(
-        &new_etc_fd,
-        dir_name,
-        Some(Uid::from_raw(stat.st_uid)),
-        Some(Gid::from_raw(stat.st_gid)),
-        AtFlags::SYMLINK_NOFOLLOW,
-    )
-    .context(format!("chown {dir_name:?}"))?;
+        // Plain content edit
+        c.write("a/edited", "new content")?;
 
-    copy_xattrs(&stat.xattrs, new_etc_fd, dir_name)?;
+        let (.., diff) = traverse_and_diff(&p, &c, &n)?;
 
-    Ok(())
-}
+        assert_eq!(
+            diff.replaced(),
+            [TreeReplacement {
+                path: "a/file1".into(),
+                from_kind: EntryKind::File,
+                to_kind: EntryKind::Directory,
+            }]
+        );
+        assert_eq!(diff.type_changed(), [PathBuf::from("a/file2")]);
+        assert!(diff.modified().contains(&PathBuf::from("a/file2")));
+        assert_eq!(diff.content_changed(), [PathBuf::from("a/edited")]);
 
-fn merge_leaf(
-    current_etc_fd: &CapStdDir,
-    new_etc_fd: &CapStdDir,
-    leaf: &Rc<Leaf<CustomMetadata>>,
-    new_inode: Option<&Inode<CustomMetadata>>,
-    file: &PathBuf,
-) -> anyhow::Result<()> {
-    let symlink = match &leaf.content {
-        LeafContent::Regular(..) => None,
-        LeafContent::Symlink(target) => Some(target),
+        Ok(())
+    }
 
-        _ => {
-            tracing::debug!("Found non file/symlink while merging. Ignoring");
-            return Ok(());
+    #[test]
+    fn test_tree_replacement() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for dir in [&p, &n] {
+            dir.create_dir_all("conf.d/sub")?;
+            dir.write("conf.d/a.conf", "a")?;
+            dir.write("conf.d/sub/b.conf", "b")?;
+            dir.write("file", "file")?;
         }
-    };
-
-    if matches!(new_inode, Some(Inode::Directory(..))) {
-        anyhow::bail!("Modified config file {file:?} newly defaults to directory. Cannot merge")
-    };
 
-    // If a new file with the same path exists, we delete it
-    new_etc_fd
-        .remove_all_optional(&file)
-        .context(format!("Deleting {file:?}"))?;
+        // Directory swapped for a symlink
+        c.symlink("../usr/share/conf.d", "conf.d")?;
 
-    if let Some(target) = symlink {
-        // Using rustix's symlinkat here as we might have absolute symlinks which clash with ambient_authority
-        symlinkat(&**target, new_etc_fd, file).context(format!("Creating symlink {file:?}"))?;
-    } else {
-        current_etc_fd
-            .copy(&file, new_etc_fd, &file)
-            .with_context(|| format!("Copying file {file:?}"))?;
-    };
+        // File swapped for a directory
+        c.create_dir("file")?;
+        c.write("file/inner", "inner")?;
 
-    rustix::fs::chownat(
-        &new_etc_fd,
-        file,
-        Some(Uid::from_raw(leaf.stat.st_uid)),
-        Some(Gid::from_raw(leaf.stat.st_gid)),
-        AtFlags::SYMLINK_NOFOLLOW,
-    )
-    .context(format!("chown {file:?}"))?;
+        let (_, current_etc_files, new_etc_files, diff) = traverse_and_diff(&p, &c, &n)?;
 
-    copy_xattrs(&leaf.stat.xattrs, new_etc_fd, file)?;
+        assert_eq!(
+            diff.replaced,
+            [
+                TreeReplacement {
+                    path: "conf.d".into(),
+                    from_kind: EntryKind::Directory,
+                    to_kind: EntryKind::Symlink,
+                },
+                TreeReplacement {
+                    path: "file".into(),
+                    from_kind: EntryKind::File,
+                    to_kind: EntryKind::Directory,
+                },
+            ]
+        );
 
-    Ok(())
-}
+        // No removals for the files under the old directory
+        assert!(diff.removed.is_empty());
+        assert!(diff.modified.is_empty());
+        assert_eq!(diff.added, [PathBuf::from("file/inner")]);
 
-fn merge_modified_files(
-    files: &Vec<PathBuf>,
-    current_etc_fd: &CapStdDir,
-    current_etc_dirtree: &Directory<CustomMetadata>,
-    new_etc_fd: &CapStdDir,
-    new_etc_dirtree: &Directory<CustomMetadata>,
-) -> anyhow::Result<()> {
-    for file in files {
-        let (dir, filename) = current_etc_dirtree
-            .split(OsStr::new(&file))
-            .context("Getting directory and file")?;
+        merge(&c, &current_etc_files, &n, &new_etc_files, &diff)?;
 
-        let current_inode = dir
-            .lookup(filename)
-            .ok_or_else(|| anyhow::anyhow!("{filename:?} not found"))?;
+        assert!(n.symlink_metadata("conf.d")?.is_symlink());
+        assert_eq!(n.read_link("conf.d")?, Path::new("../usr/share/conf.d"));
+        assert!(n.is_dir("file"));
+        assert_eq!(n.read_to_string("file/inner")?, "inner");
 
-        // This will error out if some directory in a chain does not exist
-        let res = new_etc_dirtree.split(OsStr::new(&file));
+        Ok(())
+    }
 
-        match res {
-            Ok((new_dir, filename)) => {
-                let new_inode = new_dir.lookup(filename);
+    #[test]
+    fn test_file_replaced_by_populated_dir() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
 
-                match current_inode {
-                    Inode::Directory(..) => {
-                        create_dir_with_perms(new_etc_fd, file, current_inode.stat(), new_inode)?;
-                    }
+        for dir in [&p, &c, &n] {
+            dir.create_dir("a")?;
+        }
 
-                    Inode::Leaf(leaf) => {
-                        merge_leaf(current_etc_fd, new_etc_fd, leaf, new_inode, file)?
-                    }
-                };
-            }
+        for dir in [&p, &n] {
+            dir.write("a/b", "file")?;
+            dir.create_dir_all("x/sub")?;
+            dir.write("x/sub/file", "file")?;
+        }
 
-            // Directory/File does not exist in the new /etc
-            Err(ImageError::NotFound(..)) => match current_inode {
-                Inode::Directory(..) => {
-                    create_dir_with_perms(new_etc_fd, file, current_inode.stat(), None)?
-                }
+        // File turned into a directory with nested children
+        c.create_dir_all("a/b/sub")?;
+        c.write("a/b/child", "child")?;
+        c.write("a/b/sub/deep", "deep")?;
 
-                Inode::Leaf(leaf) => {
-                    merge_leaf(current_etc_fd, new_etc_fd, leaf, None, file)?;
-                }
-            },
+        // And a populated directory turned into a file
+        c.write("x", "file")?;
 
-            Err(e) => Err(e)?,
-        };
-    }
+        let (_, current_etc_files, new_etc_files, diff) = traverse_and_diff(&p, &c, &n)?;
 
-    Ok(())
-}
+        assert_eq!(
+            diff.replaced(),
+            [
+                TreeReplacement {
+                    path: "a/b".into(),
+                    from_kind: EntryKind::File,
+                    to_kind: EntryKind::Directory,
+                },
+                TreeReplacement {
+                    path: "x".into(),
+                    from_kind: EntryKind::Directory,
+                    to_kind: EntryKind::File,
+                },
+            ]
+        );
 
-/// Goes through the added, modified, removed files and apply those changes to the new_etc
-/// This will overwrite, remove, modify files in new_etc
-/// Paths in `diff` are relative to `etc`
-#[context("Merging")]
-pub fn merge(
-    current_etc_fd: &CapStdDir,
-    current_etc_dirtree: &Directory<CustomMetadata>,
-    new_etc_fd: &CapStdDir,
-    new_etc_dirtree: &Directory<CustomMetadata>,
-    diff: &Diff,
-) -> anyhow::Result<()> {
-    merge_modified_files(
-        &diff.added,
-        current_etc_fd,
-        current_etc_dirtree,
-        new_etc_fd,
-        new_etc_dirtree,
-    )
-    .context("Merging added files")?;
+        // The replaced paths themselves are only reported as replaced, and the old children of
+        // "x" aren't removed one by one
+        assert!(diff.modified().is_empty());
+        assert!(diff.type_changed().is_empty());
+        assert!(diff.removed().is_empty());
+        assert_eq!(
+            diff.added(),
+            ["a/b/child", "a/b/sub", "a/b/sub/deep"].map(PathBuf::from)
+        );
 
-    merge_modified_files(
-        &diff.modified,
-        current_etc_fd,
-        current_etc_dirtree,
-        new_etc_fd,
-        new_etc_dirtree,
-    )
-    .context("Merging modified files")?;
+        merge(&c, &current_etc_files, &n, &new_etc_files, &diff)?;
 
-    for removed in &diff.removed {
-        let stat = new_etc_fd.metadata_optional(&removed)?;
+        assert_eq!(n.read_to_string("a/b/child")?, "child");
+        assert_eq!(n.read_to_string("a/b/sub/deep")?, "deep");
+        assert!(n.symlink_metadata("x")?.is_file());
+        assert_eq!(n.read_to_string("x")?, "file");
 
-        let Some(stat) = stat else {
-            // File/dir doesn't exist in new_etc
-            // Basically a no-op
-            continue;
-        };
+        Ok(())
+    }
 
-        if stat.is_file() || stat.is_symlink() {
-            new_etc_fd.remove_file(&removed)?;
-        } else if stat.is_dir() {
-            // We only add the directory to the removed array, if the entire directory was deleted
-            // So `remove_dir_all` should be okay here
-            new_etc_fd.remove_dir_all(&removed)?;
+    #[test]
+    fn test_deletion_respected_on_upgrade() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
+
+        for d in [&p, &n] {
+            d.create_dir_all("dir/sub")?;
+            d.write("dir/sub/file", "default")?;
+            d.write("file", "default")?;
+            d.write("kept", "default")?;
+            d.symlink("kept", "link")?;
+            d.create_dir("kept-dir")?;
+            d.write("kept-dir/file", "default")?;
+            d.symlink("missing", "dangling")?;
+            d.symlink("kept-dir", "dirlink")?;
         }
-    }
 
-    Ok(())
-}
+        // The new defaults changed, which must not bring the files back either
+        n.write("file", "new default")?;
+        n.write("dir/sub/new-file", "new default")?;
 
-#[cfg(test)]
-mod tests {
-    use cap_std::fs::PermissionsExt;
-    use cap_std_ext::cap_std::fs::Metadata;
+        c.write("kept", "default")?;
+        c.create_dir("kept-dir")?;
+        c.write("kept-dir/file", "default")?;
 
-    use super::*;
+        let (_, current_etc_files, new_etc_files, diff) = traverse_and_diff(&p, &c, &n)?;
+        assert_eq!(
+            diff.removed,
+            ["dangling", "dir", "dirlink", "file", "link"].map(PathBuf::from)
+        );
 
-    const FILES: &[(&str, &str)] = &[
-        ("a/file1", "a-file1"),
-        ("a/file2", "a-file2"),
-        ("a/b/file1", "ab-file1"),
-        ("a/b/file2", "ab-file2"),
-        ("a/b/c/fileabc", "abc-file1"),
-        ("a/b/c/modify-perms", "modify-perms"),
-        ("a/b/c/to-be-removed", "remove this"),
-        ("to-be-removed", "remove this 2"),
-    ];
+        merge(&c, &current_etc_files, &n, &new_etc_files, &diff)?;
+
+        assert!(!n.try_exists("dir")?);
+        assert!(!n.try_exists("file")?);
+        assert!(n.symlink_metadata_optional("link")?.is_none());
+        assert!(n.symlink_metadata_optional("dangling")?.is_none());
+        assert!(n.symlink_metadata_optional("dirlink")?.is_none());
+        assert_eq!(n.read_to_string("kept")?, "default");
+        assert_eq!(n.read_to_string("kept-dir/file")?, "default");
+
+        Ok(())
+    }
 
     #[test]
-    fn test_etc_diff_plus_merge() -> anyhow::Result<()> {
+    fn test_symlinked_dirs_not_followed() -> anyhow::Result<()> {
         let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
 
         tempdir.create_dir("pristine_etc")?;
-        tempdir.create_dir("current_etc")?;
-        tempdir.create_dir("new_etc")?;
+        tempdir.create_dir_all("current_etc/dir")?;
 
         let p = tempdir.open_dir("pristine_etc")?;
         let c = tempdir.open_dir("current_etc")?;
-        let n = tempdir.open_dir("new_etc")?;
 
-        p.create_dir_all("a/b/c")?;
-        c.create_dir_all("a/b/c")?;
+        // Links back up the tree would make us loop if followed
+        c.symlink("..", "dir/parent")?;
+        c.symlink(".", "self")?;
+        c.symlink("dir", "dir-link")?;
 
-        for (file, content) in FILES {
-            p.write(file, content.as_bytes())?;
-            c.write(file, content.as_bytes())?;
-        }
+        let (_, current_etc_files, _) = traverse_etc(&p, &c, None)?;
 
-        let new_files = ["new_file", "a/new_file", "a/b/c/new_file"];
+        for (path, target) in [("dir/parent", ".."), ("self", "."), ("dir-link", "dir")] {
+            let (dir, name) = current_etc_files.split(OsStr::new(path))?;
 
-        // Add some new files
-        for file in new_files {
-            c.write(file, b"hello")?;
+            match &dir.ref_leaf(name)?.content {
+                LeafContent::Symlink(link) => assert_eq!(&**link, OsStr::new(target)),
+                _ => panic!("{path} is not a symlink"),
+            }
         }
 
-        let overwritten_files = [FILES[1].0, FILES[4].0];
-        let perm_changed_files = [FILES[5].0];
+        assert!(
+            current_etc_files
+                .get_directory(OsStr::new("dir-link"))
+                .is_err()
+        );
 
-        // Modify some files
-        c.write(overwritten_files[0], b"some new content")?;
-        c.write(overwritten_files[1], b"some newer content")?;
+        Ok(())
+    }
 
-        // Modify permissions
-        let file = c.open(perm_changed_files[0])?;
-        // This should be enough as the usual files have permission 644
-        file.set_permissions(cap_std::fs::Permissions::from_mode(0o400))?;
+    #[test]
+    fn test_diff_compare_mtime() -> anyhow::Result<()> {
+        let (_tempdir, p, c, n) = etc_dirs()?;
 
-        // Remove some files
-        let deleted_files = [FILES[6].0, FILES[7].0];
-        c.remove_file(deleted_files[0])?;
-        c.remove_file(deleted_files[1])?;
+        p.write("touched.conf", "same")?;
+        c.write("touched.conf", "same")?;
 
-        let (pristine_etc_files, current_etc_files, new_etc_files) =
-            traverse_etc(&p, &c, Some(&n))?;
+        let mtime = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1000);
+        c.open("touched.conf")?.into_std().set_modified(mtime)?;
 
-        let res = compute_diff(
+        let (pristine_etc_files, current_etc_files, new_etc_files, diff) =
+            traverse_and_diff(&p, &c, &n)?;
+        assert!(diff.is_empty());
+
+        let opts = DiffOptions::new().compare_mtime(true);
+        let diff = compute_diff_with(
             &pristine_etc_files,
             &current_etc_files,
-            new_etc_files.as_ref().unwrap(),
+            &new_etc_files,
+            &opts,
         )?;
-
-        merge(
-            &c,
-            &current_etc_files,
-            &n,
-            new_etc_files.as_ref().unwrap(),
-            &res,
-        )
-        .expect("Merge failed");
-
-        let added_dirs = ["a", "a/b", "a/b/c"];
-
-        // 3 for the files, and 3 for the directories
-        assert_eq!(res.added.len(), new_files.len() + added_dirs.len());
-
-        // Test modified files
-        let all_modified_files = overwritten_files
-            .iter()
-            .chain(&perm_changed_files)
-            .collect::<Vec<_>>();
-
-        assert_eq!(res.modified.len(), all_modified_files.len());
-        assert!(res.modified.iter().all(|file| {
-            all_modified_files
-                .iter()
-                .find(|x| PathBuf::from(*x) == *file)
-                .is_some()
-        }));
-
-        // Test removed files
-        assert_eq!(res.removed.len(), deleted_files.len());
-        assert!(res.removed.iter().all(|file| {
-            deleted_files
-                .iter()
-                .find(|x| PathBuf::from(*x) == *file)
-                .is_some()
-        }));
+        assert_eq!(diff.metadata_changed(), [PathBuf::from("touched.conf")]);
+        assert!(diff.content_changed().is_empty());
 
         Ok(())
     }
@@ -904,6 +4407,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_apply_merge() -> anyhow::Result<()> {
+        let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        for dir in ["pristine_etc", "current_etc", "new_etc", "target"] {
+            tempdir.create_dir(dir)?;
+        }
+
+        let p = tempdir.open_dir("pristine_etc")?;
+        let c = tempdir.open_dir("current_etc")?;
+        let n = tempdir.open_dir("new_etc")?;
+        let t = tempdir.open_dir("target")?;
+
+        for d in [&p, &c, &n] {
+            d.create_dir_all("a/b")?;
+        }
+
+        // Modified by the user, so kept
+        p.write("a/modified", "v1")?;
+        c.write("a/modified", "mine")?;
+        n.write("a/modified", "v2")?;
+
+        // Not modified by the user, so upgraded
+        p.write("a/b/unmodified", "v1")?;
+        c.write("a/b/unmodified", "v1")?;
+        n.write("a/b/unmodified", "v2")?;
+
+        // Added by the user, so kept
+        c.write("a/added", "added")?;
+
+        // Deleted by the user, so not resurrected
+        p.write("deleted", "v1")?;
+        n.write("deleted", "v2")?;
+
+        // Only in the new defaults
+        n.create_dir("new-dir")?;
+        n.write("new-dir/new-default", "new")?;
+        n.symlink("new-dir/new-default", "new-link")?;
+
+        let diff = apply_merge(&p, &c, &n, &t)?;
+        assert_eq!(diff.modified(), [PathBuf::from("a/modified")]);
+        assert_eq!(diff.added(), [PathBuf::from("a/added")]);
+        assert_eq!(diff.removed(), [PathBuf::from("deleted")]);
+
+        assert_eq!(t.read_to_string("a/modified")?, "mine");
+        assert_eq!(t.read_to_string("a/b/unmodified")?, "v2");
+        assert_eq!(t.read_to_string("a/added")?, "added");
+        assert!(!t.try_exists("deleted")?);
+        assert_eq!(t.read_to_string("new-dir/new-default")?, "new");
+        assert_eq!(t.read_link("new-link")?, Path::new("new-dir/new-default"));
+
+        // The sources are left alone
+        assert_eq!(n.read_to_string("a/modified")?, "v2");
+        assert!(n.try_exists("deleted")?);
+        assert!(!n.try_exists("a/added")?);
+
+        // Merging into a non-empty directory is refused
+        assert!(apply_merge(&p, &c, &n, &t).is_err());
+
+        Ok(())
+    }
+
     #[test]
     fn test_merge() -> anyhow::Result<()> {
         let tempdir = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
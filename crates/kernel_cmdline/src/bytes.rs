@@ -7,9 +7,13 @@ use std::borrow::Cow;
 use std::cmp::Ordering;
 use std::ops::Deref;
 
-use crate::{Action, utf8};
+use crate::{
+    Action, ArgGroup, CmdlineDiff, DEBUG_BOOT_ARGS, INITRD_ARG_PREFIX, KeyPolicy,
+    OVERLAY_REMOVE_PREFIX, ParseWarning, utf8,
+};
 
 use anyhow::Result;
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 /// A parsed kernel command line.
@@ -17,7 +21,7 @@ use serde::{Deserialize, Serialize};
 /// Wraps the raw command line bytes and provides methods for parsing and iterating
 /// over individual parameters. Uses copy-on-write semantics to avoid unnecessary
 /// allocations when working with borrowed data.
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default)]
 pub struct Cmdline<'a>(Cow<'a, [u8]>);
 
 /// An owned Cmdline.  Alias for `Cmdline<'static>`.
@@ -116,7 +120,70 @@ impl<'a> Cmdline<'a> {
     ///
     /// Returns an error if the file cannot be read or if there are I/O issues.
     pub fn from_proc() -> Result<Self> {
-        Ok(Self(Cow::Owned(std::fs::read("/proc/cmdline")?)))
+        Self::from_reader(std::fs::File::open("/proc/cmdline")?)
+    }
+
+    /// Reads a command line from `reader` until EOF.
+    ///
+    /// The bytes are read into an owned buffer, so this works equally
+    /// well with a file, a pipe or a command line captured from a boot
+    /// configuration.
+    ///
+    /// Returns an error if reading fails.
+    pub fn from_reader(mut reader: impl std::io::Read) -> Result<CmdlineOwned> {
+        let mut buf = Vec::new();
+        reader.read_to_end(&mut buf)?;
+
+        Ok(Cmdline(Cow::Owned(buf)))
+    }
+
+    /// Builds a command line from NUL-separated arguments, as in
+    /// `/proc/<pid>/cmdline`.
+    ///
+    /// Each argument becomes one parameter, split into a key and value
+    /// on its first `=`.  Arguments are taken literally, with values
+    /// containing whitespace quoted so that they parse back the same.
+    /// Empty arguments are dropped as a command line can't hold them,
+    /// and arguments containing double quotes may not parse back the
+    /// same as quotes can't be escaped.
+    pub fn from_nul_separated(input: &[u8]) -> CmdlineOwned {
+        let params: Vec<Vec<u8>> = input
+            .split(|&b| b == b'\0')
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| match arg.iter().position(|&b| b == b'=') {
+                Some(i) => format_param(&arg[..i], Some(&arg[i + 1..])),
+                None => format_param(arg, None),
+            })
+            .collect();
+
+        Cmdline(Cow::Owned(params.join(b" ".as_slice())))
+    }
+
+    /// Reads the command line of the process `pid` from
+    /// `/proc/<pid>/cmdline`, see [`Self::from_nul_separated`].
+    ///
+    /// This is the process' argv, not the kernel command line.
+    /// Returns an error if the file cannot be read, e.g. as the process
+    /// doesn't exist.
+    pub fn from_pid(pid: u32) -> Result<CmdlineOwned> {
+        let input = std::fs::read(format!("/proc/{pid}/cmdline"))?;
+
+        Ok(Self::from_nul_separated(&input))
+    }
+
+    /// Returns the raw bytes of the command line, exactly as they were
+    /// provided.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Converts this command line into an owned one, copying the
+    /// underlying bytes if they are borrowed.
+    ///
+    /// This allows a parsed command line to outlive the buffer it was
+    /// created from.
+    pub fn into_owned(self) -> CmdlineOwned {
+        Cmdline(Cow::Owned(self.0.into_owned()))
     }
 
     /// Returns an iterator over all parameters in the command line.
@@ -128,6 +195,19 @@ impl<'a> Cmdline<'a> {
         CmdlineIter(self.iter_bytes())
     }
 
+    /// Returns an iterator over all parameters in the command line,
+    /// along with anything suspicious about each of them.
+    ///
+    /// Parsing is the same as with [`Self::iter`], which accepts every
+    /// input.  This is meant for tooling which wants to warn about
+    /// likely mistakes, see [`ParseWarning`].
+    pub fn iter_checked(&'a self) -> impl Iterator<Item = (Parameter<'a>, Vec<ParseWarning>)> {
+        self.iter().map(|p| {
+            let warnings = p.warnings();
+            (p, warnings)
+        })
+    }
+
     /// Returns an iterator over all parameters in the command line as byte slices.
     ///
     /// This is similar to `iter()` but yields `&[u8]` directly instead of `Parameter`,
@@ -136,6 +216,33 @@ impl<'a> Cmdline<'a> {
         CmdlineIterBytes(&self.0)
     }
 
+    /// Returns the number of parameters in the command line.
+    ///
+    /// This splits the command line the same way as [`Self::iter`],
+    /// but without parsing each parameter into a key and value.
+    pub fn param_count(&self) -> usize {
+        self.iter_bytes().count()
+    }
+
+    /// Returns the length of the command line in bytes, including any
+    /// whitespace and quotes.
+    pub fn byte_len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Split off the first parameter of the command line.
+    ///
+    /// Returns the first parameter along with a command line borrowing
+    /// the rest of the input, without copying, or `None` if there are
+    /// no parameters.  Iterating over the rest yields the same
+    /// parameters as the remainder of [`Self::iter`] would.
+    pub fn split_first(&'a self) -> Option<(Parameter<'a>, Cmdline<'a>)> {
+        let mut params = self.iter_bytes();
+        let first = params.next().and_then(Parameter::parse_internal)?;
+
+        Some((first, Cmdline(Cow::Borrowed(params.0))))
+    }
+
     /// Returns an iterator over all parameters in the command line
     /// which are valid UTF-8.
     pub fn iter_utf8(&'a self) -> impl Iterator<Item = utf8::Parameter<'a>> {
@@ -152,6 +259,42 @@ impl<'a> Cmdline<'a> {
         self.iter().find(|p| p.key == key)
     }
 
+    /// Locate a kernel argument with the given key name, ignoring
+    /// ASCII case.
+    ///
+    /// This is for keys whose casing is inconsistent in practice, such
+    /// as some firmware-provided arguments.  The kernel itself treats
+    /// keys as case-sensitive, so prefer [`Self::find`] unless this is
+    /// needed.  Dashes and underscores are still treated as equivalent.
+    pub fn find_ci<T: AsRef<[u8]> + ?Sized>(&'a self, key: &T) -> Option<Parameter<'a>> {
+        let key = ParameterKey(key.as_ref());
+        self.iter().find(|p| p.key.eq_ignore_case(&key))
+    }
+
+    /// Locate the last kernel argument with the given key name.
+    ///
+    /// The kernel generally gives the last occurrence of a duplicated
+    /// parameter priority.  Otherwise the same as [`Self::find`].
+    pub fn find_last<T: AsRef<[u8]> + ?Sized>(&'a self, key: &T) -> Option<Parameter<'a>> {
+        let key = ParameterKey(key.as_ref());
+        self.iter().filter(|p| p.key == key).last()
+    }
+
+    /// Iterate over every kernel argument with the given key name.
+    ///
+    /// Useful for parameters such as `console=` which may legitimately
+    /// appear several times.  Parameters are yielded in the order they
+    /// appear on the command line, regardless of whether their values
+    /// are valid UTF-8.  Key comparison treats dashes and underscores
+    /// as equivalent.
+    pub fn find_all<T: AsRef<[u8]> + ?Sized>(
+        &'a self,
+        key: &'a T,
+    ) -> impl Iterator<Item = Parameter<'a>> + 'a {
+        let key = ParameterKey(key.as_ref());
+        self.iter().filter(move |p| p.key == key)
+    }
+
     /// Locate a kernel argument with the given key name.
     ///
     /// Returns an error if a parameter with the given key name is
@@ -183,6 +326,61 @@ impl<'a> Cmdline<'a> {
             .filter(move |p| p.key.0.starts_with(prefix.as_ref()))
     }
 
+    /// Iterate over the parameters of the given kernel module.
+    ///
+    /// Module parameters follow the `module.param=value` convention,
+    /// e.g. `i915.enable_psr=0`.  Yields `(param, value)` pairs in the
+    /// order they appear on the command line, with the `module.`
+    /// prefix stripped from each key.  Dashes and underscores are
+    /// treated as equivalent in both the module name and the
+    /// parameter name.
+    pub fn module_params<T: AsRef<[u8]> + ?Sized>(
+        &'a self,
+        module: &'a T,
+    ) -> impl Iterator<Item = (ParameterKey<'a>, Option<&'a [u8]>)> + 'a {
+        let module = ParameterKey(module.as_ref());
+        self.iter().filter_map(move |p| {
+            let key = p.key.0;
+            let dot = key.iter().position(|&b| b == b'.')?;
+            (ParameterKey(&key[..dot]) == module).then(|| (ParameterKey(&key[dot + 1..]), p.value))
+        })
+    }
+
+    /// Iterate over the initrd arguments, i.e. those prefixed with
+    /// [`INITRD_ARG_PREFIX`].
+    ///
+    /// Yields `(key, value)` pairs in the order they appear on the
+    /// command line, with the `rd.` prefix stripped from each key, so
+    /// `rd.break` is yielded as `break`.
+    pub fn initrd_params(
+        &'a self,
+    ) -> impl Iterator<Item = (ParameterKey<'a>, Option<&'a [u8]>)> + 'a {
+        self.iter().filter_map(|p| {
+            let key = p.key.0.strip_prefix(INITRD_ARG_PREFIX.as_bytes())?;
+            Some((ParameterKey(key), p.value))
+        })
+    }
+
+    /// Iterate over the kernel arguments whose key is one of `keys`.
+    ///
+    /// Parameters are yielded in the order they appear on the command
+    /// line.  Key comparison treats dashes and underscores as equivalent.
+    pub fn iter_matching<T: AsRef<[u8]>>(
+        &'a self,
+        keys: &[T],
+    ) -> impl Iterator<Item = Parameter<'a>> {
+        self.iter()
+            .filter(move |p| keys.iter().any(|k| p.key == ParameterKey::from(k)))
+    }
+
+    /// Check whether a kernel argument with the given key name is present.
+    ///
+    /// This matches both switches and `key=value` parameters.  Key
+    /// comparison treats dashes and underscores as equivalent.
+    pub fn contains_param<T: AsRef<[u8]> + ?Sized>(&'a self, key: &T) -> bool {
+        self.find(key).is_some()
+    }
+
     /// Locate the value of the kernel argument with the given key name.
     ///
     /// Returns the first value matching the given key, or `None` if not found.
@@ -191,6 +389,21 @@ impl<'a> Cmdline<'a> {
         self.find(&key).and_then(|p| p.value)
     }
 
+    /// Locate the value of the last kernel argument with the given key name.
+    ///
+    /// Otherwise the same as [`Self::value_of`].
+    pub fn value_of_last<T: AsRef<[u8]> + ?Sized>(&'a self, key: &T) -> Option<&'a [u8]> {
+        self.find_last(&key).and_then(|p| p.value)
+    }
+
+    /// Locate the value of the kernel argument with the given key
+    /// name, ignoring ASCII case.
+    ///
+    /// Otherwise the same as [`Self::value_of`], see [`Self::find_ci`].
+    pub fn value_of_ci<T: AsRef<[u8]> + ?Sized>(&'a self, key: &T) -> Option<&'a [u8]> {
+        self.find_ci(&key).and_then(|p| p.value)
+    }
+
     /// Find the value of the kernel argument with the provided name, which must be present.
     ///
     /// Otherwise the same as [`Self::value_of`].
@@ -202,6 +415,105 @@ impl<'a> Cmdline<'a> {
         })
     }
 
+    /// Check that the command line contains no control characters.
+    ///
+    /// Parsing treats a NUL like any other byte and a newline like any
+    /// other whitespace, which gives confusing results for crafted or
+    /// mangled input.  This returns an error listing every ASCII
+    /// control character and its byte offset so callers can fail
+    /// loudly instead.  Tabs are allowed, as is a single trailing
+    /// newline like the one `/proc/cmdline` ends with.
+    pub fn validate(&self) -> Result<()> {
+        let input = self.0.strip_suffix(b"\n").unwrap_or(&self.0);
+
+        let invalid: Vec<_> = input
+            .iter()
+            .enumerate()
+            .filter(|&(_, &b)| b.is_ascii_control() && b != b'\t')
+            .map(|(offset, &b)| match b {
+                b'\0' => format!("NUL at offset {offset}"),
+                b'\n' => format!("newline at offset {offset}"),
+                b => format!("{b:#04x} at offset {offset}"),
+            })
+            .collect();
+
+        if !invalid.is_empty() {
+            anyhow::bail!(
+                "Kernel command line contains control characters: {}",
+                invalid.join(", ")
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Hash the set of parameters, independently of their order.
+    ///
+    /// Two command lines hash the same if they contain the same
+    /// parameters, compared like [`Parameter`]s are, in any order.
+    /// Duplicate parameters are counted, so `foo foo` differs from
+    /// `foo`.
+    ///
+    /// The digest is the 64-bit FNV-1a hash of the parameters sorted
+    /// by key then value, each encoded as its key with dashes turned
+    /// into underscores, then `=` and the unquoted value unless it is
+    /// a switch, then a NUL byte.  This is stable across releases and
+    /// platforms, so it can be persisted for change detection.
+    pub fn stable_hash(&'a self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+        const FNV_PRIME: u64 = 0x100000001b3;
+
+        let mut params: Vec<_> = self.iter().collect();
+        params.sort();
+
+        params
+            .iter()
+            .flat_map(|p| {
+                let value = p
+                    .value
+                    .map(|v| std::iter::once(b'=').chain(v.iter().copied()));
+                p.key
+                    .iter()
+                    .chain(value.into_iter().flatten())
+                    .chain(std::iter::once(b'\0'))
+            })
+            .fold(FNV_OFFSET_BASIS, |hash, b| {
+                (hash ^ u64::from(b)).wrapping_mul(FNV_PRIME)
+            })
+    }
+
+    /// Check whether this is a debug or emergency boot.
+    ///
+    /// Returns `true` if any of the arguments in [`DEBUG_BOOT_ARGS`]
+    /// are present.
+    pub fn is_debug_boot(&'a self) -> bool {
+        self.is_debug_boot_with(DEBUG_BOOT_ARGS)
+    }
+
+    /// Check whether any of the given debug boot arguments are present.
+    ///
+    /// A switch in `triggers` matches any parameter with the same key,
+    /// whatever its value; a `key=value` trigger must match exactly.
+    pub fn is_debug_boot_with<T: AsRef<[u8]>>(&'a self, triggers: &[T]) -> bool {
+        triggers
+            .iter()
+            .filter_map(Parameter::parse)
+            .any(|trigger| self.iter().any(|p| p.matches(&trigger)))
+    }
+
+    /// Find the member of a group of mutually exclusive arguments
+    /// that takes effect.
+    ///
+    /// Returns the last parameter on the command line matching any
+    /// member of `group`, or `None` if no member is present.
+    pub fn effective_group_value(&'a self, group: &ArgGroup) -> Option<Parameter<'a>> {
+        let members: Vec<_> = group.members.iter().filter_map(Parameter::parse).collect();
+
+        self.iter()
+            .filter(|p| members.iter().any(|m| p.matches(m)))
+            .last()
+    }
+
     /// Add a parameter to the command line if it doesn't already exist
     ///
     /// Returns `Action::Added` if the parameter did not already exist
@@ -295,6 +607,112 @@ impl<'a> Cmdline<'a> {
         }
     }
 
+    /// Append `key=value` to the command line if the result fits in
+    /// `max_total` bytes
+    ///
+    /// The value is quoted if it contains whitespace.  If appending
+    /// would grow the command line past `max_total` (e.g. the kernel's
+    /// `COMMAND_LINE_SIZE`), an error is returned and the command line
+    /// is left unchanged.
+    pub fn try_push_kv<K: AsRef<[u8]> + ?Sized, V: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: &V,
+        max_total: usize,
+    ) -> Result<()> {
+        let param = format_kv(key.as_ref(), value.as_ref());
+
+        let needs_space = self.0.last().is_some_and(|b| !b.is_ascii_whitespace());
+        let total = self.0.len() + usize::from(needs_space) + param.len();
+        if total > max_total {
+            let param = String::from_utf8_lossy(&param);
+            anyhow::bail!(
+                "Adding '{param}' would grow the command line to {total} bytes, exceeding the limit of {max_total}"
+            );
+        }
+
+        let self_mut = self.0.to_mut();
+        if needs_space {
+            self_mut.push(b' ');
+        }
+        self_mut.extend_from_slice(&param);
+
+        Ok(())
+    }
+
+    /// Set `key` to `value`
+    ///
+    /// Like [`Self::add_or_modify`], this replaces the first parameter
+    /// with the same key (treating dashes and underscores as
+    /// equivalent) and removes any others, or appends the parameter if
+    /// the key isn't present.  The value is quoted if it contains
+    /// whitespace, so that it parses back to the same value.
+    pub fn set<K: AsRef<[u8]> + ?Sized, V: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: &V,
+    ) -> Action {
+        let param = format_kv(key.as_ref(), value.as_ref());
+        let param = Parameter::parse(&param).expect("key=value is never empty");
+
+        self.add_or_modify(&param)
+    }
+
+    /// Set `key` to `value`, unless `key` is already present
+    ///
+    /// Unlike [`Self::set`], an existing parameter with the same key
+    /// (treating dashes and underscores as equivalent) is never
+    /// replaced, whatever its value, and `Action::Existed` is
+    /// returned.  A switch counts as present too.  This is meant for
+    /// adding defaults which the user may have overridden.
+    pub fn set_if_absent<K: AsRef<[u8]> + ?Sized, V: AsRef<[u8]> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: &V,
+    ) -> Action {
+        if self.contains_param(key) {
+            return Action::Existed;
+        }
+
+        self.set(key, value)
+    }
+
+    /// Append the switch `key`, i.e. a parameter without a value
+    ///
+    /// Like [`Self::add`], nothing is changed if the switch is already
+    /// present.  Parameters with the same key but with a value are
+    /// left alone.
+    pub fn push_switch<K: AsRef<[u8]> + ?Sized>(&mut self, key: &K) -> Action {
+        let key = key.as_ref();
+
+        self.add(&Parameter {
+            parameter: key,
+            key: ParameterKey(key),
+            value: None,
+        })
+    }
+
+    /// Serialize the command line to bytes
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.to_vec()
+    }
+
+    /// Insert a parameter at the given position in the command line
+    ///
+    /// `index` counts parameters, not bytes; an index past the end
+    /// appends the parameter.  Unlike `add`, this always inserts,
+    /// even if the exact parameter already exists.
+    pub fn insert_at(&mut self, index: usize, param: &Parameter) {
+        let mut new_params: Vec<&[u8]> = self.iter().map(|p| p.parameter).collect();
+        new_params.insert(index.min(new_params.len()), param.parameter);
+        self.0 = Cow::Owned(new_params.join(b" ".as_slice()));
+    }
+
+    /// Insert a parameter at the start of the command line
+    pub fn push_front(&mut self, param: &Parameter) {
+        self.insert_at(0, param)
+    }
+
     /// Remove parameter(s) with the given key from the command line
     ///
     /// Returns `true` if parameter(s) were removed.
@@ -340,6 +758,165 @@ impl<'a> Cmdline<'a> {
         removed
     }
 
+    /// Apply a key allowlist or denylist to the command line
+    ///
+    /// Returns a new command line containing only the parameters
+    /// permitted by `policy`, in their original order, along with the
+    /// keys of every parameter that was rejected so callers can log
+    /// them.
+    pub fn filter_keys(&'a self, policy: KeyPolicy) -> (CmdlineOwned, Vec<ParameterKey<'a>>) {
+        let mut kept = Vec::new();
+        let mut rejected = Vec::new();
+
+        for p in self.iter() {
+            if policy.permits(&p.key) {
+                kept.push(p.parameter);
+            } else {
+                rejected.push(p.key);
+            }
+        }
+
+        (Cmdline::from(kept.join(b" ".as_slice())), rejected)
+    }
+
+    /// Return the parameters of this command line for which `keep`
+    /// returns true
+    ///
+    /// The parameters keep their order and are copied verbatim,
+    /// including any quotes, so the result parses to the same
+    /// parameters.  This is useful for e.g. stripping secrets from a
+    /// command line before logging it.
+    pub fn filtered(&'a self, mut keep: impl FnMut(&Parameter<'a>) -> bool) -> CmdlineOwned {
+        let params: Vec<&[u8]> = self
+            .iter()
+            .filter(|p| keep(p))
+            .map(|p| p.parameter)
+            .collect();
+
+        Cmdline::from(params.join(b" ".as_slice()))
+    }
+
+    /// Return the parameters of this command line that are not in `baseline`
+    ///
+    /// A parameter is dropped only if `baseline` contains it with the
+    /// same key and value.  The remaining parameters keep their order.
+    pub fn minus(&self, baseline: &Cmdline) -> CmdlineOwned {
+        let params: Vec<&[u8]> = self
+            .iter()
+            .filter(|p| !baseline.iter().any(|b| b == *p))
+            .map(|p| p.parameter)
+            .collect();
+
+        Cmdline::from(params.join(b" ".as_slice()))
+    }
+
+    /// Combine this command line with `overrides`, which take precedence
+    ///
+    /// Keys are compared with dashes and underscores treated as
+    /// equivalent.  For every key present in `overrides`:
+    ///
+    ///   - All parameters with that key in this command line, including
+    ///     duplicates, are replaced by all the parameters with that key
+    ///     in `overrides`.  They are put where the first of the replaced
+    ///     parameters was.  A switch replaces `key=value` parameters
+    ///     and vice versa, as the key is all that matters.
+    ///   - If the key isn't in this command line, the parameters are
+    ///     appended in the order they appear in `overrides`.
+    ///
+    /// A parameter of `overrides` whose key is prefixed with
+    /// [`OVERLAY_REMOVE_PREFIX`], e.g. `!quiet`, instead removes all
+    /// parameters with that key from this command line.  Only its key
+    /// matters; it never ends up in the result.
+    ///
+    /// Parameters whose key isn't in `overrides` are kept as is, in
+    /// their original order.
+    pub fn overlay(&self, overrides: &Cmdline) -> CmdlineOwned {
+        let removed: Vec<_> = overrides
+            .iter()
+            .filter_map(|o| o.key.0.strip_prefix(OVERLAY_REMOVE_PREFIX.as_bytes()))
+            .map(ParameterKey)
+            .collect();
+
+        let mut params: Vec<&[u8]> = Vec::new();
+        let mut replaced: Vec<ParameterKey> = Vec::new();
+
+        for p in self.iter() {
+            if removed.contains(&p.key) || replaced.contains(&p.key) {
+                continue;
+            }
+
+            let len = params.len();
+            params.extend(
+                overrides
+                    .iter()
+                    .filter(|o| o.key == p.key)
+                    .map(|o| o.parameter),
+            );
+
+            if params.len() == len {
+                params.push(p.parameter);
+            } else {
+                replaced.push(p.key);
+            }
+        }
+
+        for o in overrides.iter() {
+            if o.key.0.starts_with(OVERLAY_REMOVE_PREFIX.as_bytes()) || replaced.contains(&o.key) {
+                continue;
+            }
+
+            params.push(o.parameter);
+        }
+
+        Cmdline::from(params.join(b" ".as_slice()))
+    }
+
+    /// Compute the differences between this command line and `other`
+    ///
+    /// Parameters are grouped by key, in order of first appearance.
+    /// For each key, parameters present with the same value on both
+    /// sides are unchanged.  The remaining parameters are paired up in
+    /// order as `changed`; any left over on one side only are
+    /// `removed` or `added`.  In particular, a switch turning into a
+    /// `key=value` parameter (or vice versa) is a change.
+    pub fn diff(&'a self, other: &'a Cmdline<'a>) -> CmdlineDiff<Parameter<'a>> {
+        let mut keys: Vec<ParameterKey<'a>> = Vec::new();
+        for p in self.iter().chain(other.iter()) {
+            if !keys.contains(&p.key) {
+                keys.push(p.key);
+            }
+        }
+
+        let mut diff = CmdlineDiff::default();
+        for key in keys {
+            let mut new: Vec<_> = other.iter().filter(|p| p.key == key).collect();
+            let old: Vec<_> = self
+                .iter()
+                .filter(|p| p.key == key)
+                .filter(|p| match new.iter().position(|n| n == p) {
+                    Some(idx) => {
+                        new.remove(idx);
+                        false
+                    }
+                    None => true,
+                })
+                .collect();
+
+            let mut old = old.into_iter();
+            let mut new = new.into_iter();
+            loop {
+                match (old.next(), new.next()) {
+                    (Some(o), Some(n)) => diff.changed.push((o, n)),
+                    (Some(o), None) => diff.removed.push(o),
+                    (None, Some(n)) => diff.added.push(n),
+                    (None, None) => break,
+                }
+            }
+        }
+
+        diff
+    }
+
     #[cfg(test)]
     pub(crate) fn is_owned(&self) -> bool {
         matches!(self.0, Cow::Owned(_))
@@ -351,6 +928,149 @@ impl<'a> Cmdline<'a> {
     }
 }
 
+/// Format a `key=value` parameter, quoting the value if it contains
+/// whitespace
+///
+/// If the key contains whitespace the whole parameter is quoted
+/// instead, as a quoted value alone would still split it.
+fn format_kv(key: &[u8], value: &[u8]) -> Vec<u8> {
+    if key.iter().any(u8::is_ascii_whitespace) {
+        return [b"\"", key, b"=", value, b"\""].concat();
+    }
+
+    let mut param = Vec::with_capacity(key.len() + value.len() + 3);
+    param.extend_from_slice(key);
+    param.push(b'=');
+
+    if value.iter().any(u8::is_ascii_whitespace) {
+        param.push(b'"');
+        param.extend_from_slice(value);
+        param.push(b'"');
+    } else {
+        param.extend_from_slice(value);
+    }
+
+    param
+}
+
+/// Format a parameter such that it parses back to the same key and
+/// value, quoting it if needed
+fn format_param(key: &[u8], value: Option<&[u8]>) -> Vec<u8> {
+    match value {
+        Some(value) => format_kv(key, value),
+        None if key.iter().any(u8::is_ascii_whitespace) => [b"\"", key, b"\""].concat(),
+        None => key.to_vec(),
+    }
+}
+
+impl std::fmt::Display for Cmdline<'_> {
+    /// Formats the command line in a normalized form
+    ///
+    /// Parameters are separated by single spaces and values containing
+    /// whitespace are quoted, so the output parses back to the same
+    /// parameters.  Bytes which are not valid UTF-8 are replaced with
+    /// U+FFFD.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&String::from_utf8_lossy(&self.normalized()))
+    }
+}
+
+impl Cmdline<'_> {
+    /// The command line in the normalized form written by
+    /// [`Display`](std::fmt::Display), before any lossy conversion
+    pub(crate) fn normalized(&self) -> Vec<u8> {
+        let mut normalized = Vec::with_capacity(self.0.len());
+
+        for (i, p) in self.iter().enumerate() {
+            if i > 0 {
+                normalized.push(b' ');
+            }
+
+            normalized.extend(format_param(&p.key, p.value));
+        }
+
+        normalized
+    }
+}
+
+/// A key or value in the serialized form of a [`Cmdline`].
+///
+/// Valid UTF-8 is serialized as a string, anything else as an explicit
+/// array of bytes so that no data is lost.
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum SerializedText<'a> {
+    Str(Cow<'a, str>),
+    Bytes(Cow<'a, [u8]>),
+}
+
+#[cfg(feature = "serde")]
+impl<'a> From<&'a [u8]> for SerializedText<'a> {
+    fn from(bytes: &'a [u8]) -> Self {
+        match str::from_utf8(bytes) {
+            Ok(s) => Self::Str(Cow::Borrowed(s)),
+            Err(_) => Self::Bytes(Cow::Borrowed(bytes)),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl SerializedText<'_> {
+    fn as_bytes(&self) -> &[u8] {
+        match self {
+            Self::Str(s) => s.as_bytes(),
+            Self::Bytes(b) => b,
+        }
+    }
+}
+
+/// A single parameter in the serialized form of a [`Cmdline`].
+#[cfg(feature = "serde")]
+#[derive(Serialize, Deserialize)]
+struct SerializedParameter<'a> {
+    key: SerializedText<'a>,
+    value: Option<SerializedText<'a>>,
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Cmdline<'_> {
+    /// Serializes the command line as an ordered sequence of
+    /// `{key, value}` entries, where `value` is `null` for switches.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(|p| SerializedParameter {
+            key: SerializedText::from(p.key.0),
+            value: p.value.map(SerializedText::from),
+        }))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Cmdline<'_> {
+    /// Reconstructs the command line from the entries written by
+    /// [`Serialize`], quoting values which contain whitespace.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+
+        let params = Vec::<SerializedParameter>::deserialize(deserializer)?;
+        let mut cmdline = Vec::new();
+        for p in params {
+            let key = p.key.as_bytes();
+            if key.is_empty() || key.contains(&b'=') {
+                let key = String::from_utf8_lossy(key);
+                return Err(D::Error::custom(format!("invalid parameter key '{key}'")));
+            }
+
+            if !cmdline.is_empty() {
+                cmdline.push(b' ');
+            }
+            cmdline.extend(format_param(key, p.value.as_ref().map(|v| v.as_bytes())));
+        }
+
+        Ok(Cmdline(Cow::Owned(cmdline)))
+    }
+}
+
 impl<'a> IntoIterator for &'a Cmdline<'a> {
     type Item = Parameter<'a>;
     type IntoIter = CmdlineIter<'a>;
@@ -426,6 +1146,15 @@ impl ParameterKey<'_> {
             .iter()
             .map(|&c: &u8| if c == b'-' { b'_' } else { c })
     }
+
+    /// Compares two parameter keys for equality, ignoring ASCII case.
+    ///
+    /// Dashes and underscores are treated as equivalent, as with `==`.
+    pub fn eq_ignore_case(&self, other: &ParameterKey<'_>) -> bool {
+        self.iter()
+            .map(|c| c.to_ascii_lowercase())
+            .eq(other.iter().map(|c| c.to_ascii_lowercase()))
+    }
 }
 
 impl PartialEq for ParameterKey<'_> {
@@ -479,6 +1208,53 @@ impl<'a> Parameter<'a> {
             .and_then(Self::parse_internal)
     }
 
+    /// Parse a single parameter, interpreting escaped quotes.
+    ///
+    /// Unlike [`Self::parse`], which only strips the outermost quotes
+    /// and keeps everything else literally, this produces an owned,
+    /// unescaped `(key, value)` pair:
+    ///
+    ///   - Every unescaped double quote opens or closes a quoted
+    ///     section and is removed, so `"foo"=bar` has the key `foo`.
+    ///   - Inside a quoted section, `\"` is a literal quote and `\\` a
+    ///     literal backslash.  Any other backslash, and any backslash
+    ///     outside a quoted section, is kept as is.
+    ///   - The parameter ends at the first whitespace outside a quoted
+    ///     section; the rest of the input is discarded.
+    ///   - An unbalanced quote extends the quoted section to the end of
+    ///     the input, as the kernel does, and is removed.
+    ///
+    /// The key and value are split on the first `=`.  Returns `None`
+    /// if the input is empty or contains only whitespace.
+    pub fn parse_escaped<T: AsRef<[u8]> + ?Sized>(input: &T) -> Option<(Vec<u8>, Option<Vec<u8>>)> {
+        let input = input.as_ref();
+        let start = input.iter().position(|b| !b.is_ascii_whitespace())?;
+
+        let mut unescaped = Vec::with_capacity(input.len() - start);
+        let mut in_quotes = false;
+        let mut bytes = input[start..].iter().copied().peekable();
+        while let Some(b) = bytes.next() {
+            match b {
+                b'"' => in_quotes = !in_quotes,
+                b'\\' if in_quotes => match bytes.next_if(|&n| n == b'"' || n == b'\\') {
+                    Some(escaped) => unescaped.push(escaped),
+                    None => unescaped.push(b),
+                },
+                b if b.is_ascii_whitespace() && !in_quotes => break,
+                b => unescaped.push(b),
+            }
+        }
+
+        match unescaped.iter().position(|&b| b == b'=') {
+            Some(i) => {
+                let value = unescaped.split_off(i + 1);
+                unescaped.truncate(i);
+                Some((unescaped, Some(value)))
+            }
+            None => Some((unescaped, None)),
+        }
+    }
+
     /// Parse a parameter from a byte slice that contains exactly one parameter.
     ///
     /// This is an internal method that assumes the input has already been
@@ -528,34 +1304,166 @@ impl<'a> Parameter<'a> {
     pub fn value(&self) -> Option<&'a [u8]> {
         self.value
     }
-}
 
-impl PartialEq for Parameter<'_> {
-    fn eq(&self, other: &Self) -> bool {
-        // Note we don't compare parameter because we want hyphen-dash insensitivity for the key
-        self.key == other.key && self.value == other.value
+    /// Returns the key as a string, or `None` if it isn't valid UTF-8
+    ///
+    /// Only the key is checked, unlike when converting the whole
+    /// parameter into a [`utf8::Parameter`].
+    pub fn key_str(&self) -> Option<&'a str> {
+        str::from_utf8(self.key.0).ok()
     }
-}
-
-impl Eq for Parameter<'_> {}
 
-impl Ord for Parameter<'_> {
-    fn cmp(&self, other: &Self) -> Ordering {
-        self.key.cmp(&other.key).then(self.value.cmp(&other.value))
+    /// Returns the value as a string, or `None` if this is a switch or
+    /// the value isn't valid UTF-8
+    ///
+    /// Only the value is checked, unlike when converting the whole
+    /// parameter into a [`utf8::Parameter`].
+    pub fn value_str(&self) -> Option<&'a str> {
+        self.value.and_then(|v| str::from_utf8(v).ok())
     }
-}
 
-impl PartialOrd for Parameter<'_> {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+    /// Returns `true` if this parameter is a switch without a value
+    pub fn is_switch(&self) -> bool {
+        self.value.is_none()
     }
-}
 
-impl Deref for Parameter<'_> {
-    type Target = [u8];
+    /// Returns anything suspicious about how this parameter was
+    /// written, see [`ParseWarning`].
+    pub fn warnings(&self) -> Vec<ParseWarning> {
+        let mut warnings = vec![];
 
-    fn deref(&self) -> &Self::Target {
-        self.parameter
+        if self.parameter.iter().filter(|&&b| b == b'"').count() % 2 != 0 {
+            warnings.push(ParseWarning::UnbalancedQuote);
+        }
+
+        if self.key.0.is_empty() {
+            warnings.push(ParseWarning::EmptyKey);
+        }
+
+        if self.key.0.contains(&b'"') {
+            warnings.push(ParseWarning::QuoteInKey);
+        }
+
+        warnings
+    }
+
+    /// Returns `true` if the value of this parameter equals `value`.
+    ///
+    /// Quoting is normalized on both sides by stripping the double
+    /// quotes surrounding a value, so `foo="bar baz"` equals both
+    /// `bar baz` and `"bar baz"`.  An unbalanced quote is compared
+    /// as is.  A switch without a value never matches.
+    pub fn value_equals<T: AsRef<[u8]> + ?Sized>(&self, value: &T) -> bool {
+        fn dequote(v: &[u8]) -> &[u8] {
+            v.strip_prefix(b"\"")
+                .and_then(|v| v.strip_suffix(b"\""))
+                .unwrap_or(v)
+        }
+
+        self.value
+            .is_some_and(|v| dequote(v) == dequote(value.as_ref()))
+    }
+
+    /// Parse the value of the parameter as a nested parameter
+    ///
+    /// Some parameters carry a `KEY=VALUE` pair of their own, e.g.
+    /// `systemd.setenv=FOO=bar` has the nested key `FOO` and value
+    /// `bar`.  The nested key and value are split on the first `=` of
+    /// the value, and a value without one yields a nested switch.
+    /// Returns `None` for a switch or an empty value.
+    pub fn value_as_kv(&self) -> Option<Parameter<'a>> {
+        self.value
+            .filter(|v| !v.is_empty())
+            .and_then(Self::parse_internal)
+    }
+
+    /// Split the value of the parameter into comma-separated segments
+    ///
+    /// Empty segments are preserved, so `foo=a,,b` yields `a`, an
+    /// empty segment, then `b`.  A switch without a value yields
+    /// nothing.  See [`Self::values_multi`] for quote-aware splitting
+    /// on arbitrary separators.
+    pub fn values(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.value.into_iter().flat_map(|v| v.split(|&b| b == b','))
+    }
+
+    /// Split the value of the parameter on any of the given separators
+    ///
+    /// Separators inside double quotes are ignored, and quotes
+    /// surrounding an item are stripped.  Empty items are skipped, so
+    /// e.g. splitting `a, b` on `b", "` yields `a` and `b`.  A switch
+    /// without a value yields nothing.
+    pub fn values_multi(&self, seps: &[u8]) -> impl Iterator<Item = &'a [u8]> {
+        let mut rest = self.value.unwrap_or_default();
+
+        std::iter::from_fn(move || {
+            while !rest.is_empty() {
+                let mut in_quotes = false;
+                let end = rest
+                    .iter()
+                    .position(|&b| {
+                        if b == b'"' {
+                            in_quotes = !in_quotes;
+                        }
+                        !in_quotes && seps.contains(&b)
+                    })
+                    .unwrap_or(rest.len());
+
+                let (item, tail) = rest.split_at(end);
+                rest = tail.get(1..).unwrap_or_default();
+
+                let item = item
+                    .strip_prefix(b"\"")
+                    .and_then(|i| i.strip_suffix(b"\""))
+                    .unwrap_or(item);
+
+                if !item.is_empty() {
+                    return Some(item);
+                }
+            }
+
+            None
+        })
+    }
+
+    /// Returns `true` if this parameter matches `pattern`.
+    ///
+    /// A switch pattern matches any parameter with the same key,
+    /// whatever its value; a `key=value` pattern must match exactly.
+    pub(crate) fn matches(&self, pattern: &Parameter) -> bool {
+        match pattern.value {
+            None => self.key == pattern.key,
+            Some(_) => self == pattern,
+        }
+    }
+}
+
+impl PartialEq for Parameter<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        // Note we don't compare parameter because we want hyphen-dash insensitivity for the key
+        self.key == other.key && self.value == other.value
+    }
+}
+
+impl Eq for Parameter<'_> {}
+
+impl Ord for Parameter<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key.cmp(&other.key).then(self.value.cmp(&other.value))
+    }
+}
+
+impl PartialOrd for Parameter<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Deref for Parameter<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.parameter
     }
 }
 
@@ -569,6 +1477,77 @@ where
     }
 }
 
+/// An owned kernel command line parameter.
+///
+/// Unlike [`Parameter`], this doesn't borrow from a command line, so
+/// parameters from several command lines can be collected together.
+#[derive(Clone, Debug)]
+pub struct ParameterOwned(Vec<u8>);
+
+impl ParameterOwned {
+    /// Borrow this as a [`Parameter`]
+    pub fn as_parameter(&self) -> Parameter<'_> {
+        // SAFETY: We only construct this from a parsed Parameter
+        Parameter::parse_internal(&self.0).expect("We only construct this from a parsed Parameter")
+    }
+
+    /// Returns the key part of the parameter
+    pub fn key(&self) -> ParameterKey<'_> {
+        self.as_parameter().key()
+    }
+
+    /// Returns the optional value part of the parameter
+    pub fn value(&self) -> Option<&[u8]> {
+        self.as_parameter().value()
+    }
+}
+
+impl From<Parameter<'_>> for ParameterOwned {
+    fn from(param: Parameter<'_>) -> Self {
+        Self(param.parameter.to_vec())
+    }
+}
+
+impl From<&Parameter<'_>> for ParameterOwned {
+    fn from(param: &Parameter<'_>) -> Self {
+        Self(param.parameter.to_vec())
+    }
+}
+
+impl PartialEq for ParameterOwned {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_parameter() == other.as_parameter()
+    }
+}
+
+impl Eq for ParameterOwned {}
+
+impl PartialEq<Parameter<'_>> for ParameterOwned {
+    fn eq(&self, other: &Parameter<'_>) -> bool {
+        self.as_parameter() == *other
+    }
+}
+
+impl Ord for ParameterOwned {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_parameter().cmp(&other.as_parameter())
+    }
+}
+
+impl PartialOrd for ParameterOwned {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Deref for ParameterOwned {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -640,6 +1619,89 @@ mod tests {
         assert_eq!(p.value, None);
     }
 
+    #[test]
+    fn test_parameter_parse_escaped() {
+        fn parse(s: &[u8]) -> (Vec<u8>, Option<Vec<u8>>) {
+            Parameter::parse_escaped(s).unwrap()
+        }
+        fn kv(k: &[u8], v: &[u8]) -> (Vec<u8>, Option<Vec<u8>>) {
+            (k.to_vec(), Some(v.to_vec()))
+        }
+
+        assert_eq!(parse(b"foo=bar"), kv(b"foo", b"bar"));
+        assert_eq!(parse(b"  switch  rest"), (b"switch".to_vec(), None));
+        assert_eq!(parse(b"foo=\"a b\" rest"), kv(b"foo", b"a b"));
+
+        // Escaped quotes inside a quoted value are unescaped, and do
+        // not end the quoted section
+        assert_eq!(
+            parse(br#"foo="say \"hi there\"" rest"#),
+            kv(b"foo", br#"say "hi there""#)
+        );
+        assert_eq!(parse(br#"foo="a\\b\c""#), kv(b"foo", br#"a\b\c"#));
+
+        // Backslashes outside quotes are literal
+        assert_eq!(parse(br#"foo=a\\b"#), kv(b"foo", br#"a\\b"#));
+
+        // All unescaped quotes are removed, including around the key
+        assert_eq!(parse(br#""foo"=bar"#), kv(b"foo", b"bar"));
+        assert_eq!(
+            parse(br#"foo="internal"quotes"#),
+            kv(b"foo", b"internalquotes")
+        );
+
+        // An unbalanced quote runs to the end of the input
+        assert_eq!(parse(br#"foo="a b c"#), kv(b"foo", b"a b c"));
+        assert_eq!(parse(br#"foo="a \""#), kv(b"foo", br#"a ""#));
+
+        assert_eq!(Parameter::parse_escaped(b""), None);
+        assert_eq!(Parameter::parse_escaped(b"   "), None);
+
+        // The zero-copy parser is unaffected
+        let p = param(r#"foo="say \"hi\"""#);
+        assert_eq!(p.value, Some(br#"say \"hi\""#.as_slice()));
+    }
+
+    #[test]
+    fn test_iter_checked() {
+        fn warnings(s: &str) -> Vec<Vec<ParseWarning>> {
+            Cmdline::from(s).iter_checked().map(|(_, w)| w).collect()
+        }
+
+        assert_eq!(
+            warnings("foo=bar \"baz=a b\" quiet"),
+            [vec![], vec![], vec![]]
+        );
+
+        assert_eq!(
+            warnings("=value foo"),
+            [vec![ParseWarning::EmptyKey], vec![]]
+        );
+        assert_eq!(warnings("\"\""), [vec![ParseWarning::EmptyKey]]);
+
+        assert_eq!(warnings("\"foo\"=bar"), [vec![ParseWarning::QuoteInKey]]);
+
+        // The unbalanced quote swallows the rest of the command line
+        let kargs = Cmdline::from("foo=\"bar baz quiet");
+        let checked: Vec<_> = kargs.iter_checked().collect();
+        assert_eq!(checked.len(), 1);
+        assert_eq!(checked[0].0, param("foo=\"bar baz quiet\""));
+        assert_eq!(checked[0].1, [ParseWarning::UnbalancedQuote]);
+
+        assert_eq!(
+            warnings("\"\"\"=x"),
+            [vec![
+                ParseWarning::UnbalancedQuote,
+                ParseWarning::QuoteInKey
+            ]]
+        );
+
+        // Parsing itself is unchanged
+        let kargs = Cmdline::from("=value \"foo\"=bar");
+        let checked: Vec<_> = kargs.iter_checked().map(|(p, _)| p).collect();
+        assert_eq!(checked, kargs.iter().collect::<Vec<_>>());
+    }
+
     #[test]
     fn test_parameter_pathological() {
         // valid things that certified insane people would do
@@ -738,6 +1800,24 @@ mod tests {
         let _static_kargs: CmdlineOwned = Cmdline::new();
     }
 
+    #[test]
+    fn test_into_owned() {
+        let owned: CmdlineOwned = {
+            let buf = b"foo=bar \"baz=a b\" switch".to_vec();
+            let kargs = Cmdline::from(&buf);
+            assert!(kargs.is_borrowed());
+            kargs.into_owned()
+        };
+
+        assert!(owned.is_owned());
+        assert_eq!(owned.value_of("foo"), Some(b"bar".as_slice()));
+        let params: Vec<_> = owned.iter().collect();
+        assert_eq!(
+            params,
+            [param("foo=bar"), param("\"baz=a b\""), param("switch")]
+        );
+    }
+
     #[test]
     fn test_kargs_iter_utf8() {
         let kargs = Cmdline::from(b"foo=bar,bar2 \xff baz=fuz bad=oh\xffno wiz");
@@ -780,6 +1860,100 @@ mod tests {
         assert!(kargs.iter().count() > 0);
     }
 
+    #[test]
+    fn test_initrd_params() {
+        let kargs = Cmdline::from(b"rd.break rd.luks.uuid=abc foo=bar".as_slice());
+
+        let params: Vec<_> = kargs.initrd_params().collect();
+        assert_eq!(
+            params,
+            [
+                (ParameterKey::from("break"), None),
+                (ParameterKey::from("luks.uuid"), Some(b"abc".as_slice())),
+            ]
+        );
+
+        let kargs = Cmdline::from(b"foo=bar rdbreak".as_slice());
+        assert_eq!(kargs.initrd_params().count(), 0);
+    }
+
+    #[test]
+    fn test_find_ci() {
+        let kargs = Cmdline::from(b"Root=/dev/sda1 Init-On-Free=1 quiet".as_slice());
+
+        assert_eq!(kargs.value_of_ci("ROOT").unwrap(), b"/dev/sda1");
+        assert!(kargs.value_of("ROOT").is_none());
+
+        // Dashes and underscores are still equivalent
+        let p = kargs.find_ci("init_on_free").unwrap();
+        assert_eq!(p.key.0, b"Init-On-Free");
+        assert!(kargs.find("init_on_free").is_none());
+
+        assert!(kargs.find_ci("QUIET").is_some());
+        assert!(kargs.find_ci("loud").is_none());
+
+        assert!(ParameterKey::from("a-B").eq_ignore_case(&ParameterKey::from("A_b")));
+        assert!(!ParameterKey::from("ab").eq_ignore_case(&ParameterKey::from("abc")));
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let kargs = Cmdline::from_reader(b"foo=bar\xff baz\n".as_slice()).unwrap();
+
+        assert_eq!(kargs.value_of("foo").unwrap(), b"bar\xff");
+        assert!(kargs.find("baz").is_some());
+        assert_eq!(kargs.iter().count(), 2);
+
+        let kargs = Cmdline::from_reader(std::io::empty()).unwrap();
+        assert_eq!(kargs.iter().count(), 0);
+    }
+
+    #[test]
+    fn test_from_nul_separated() {
+        let kargs = Cmdline::from_nul_separated(b"arg1\0arg2=val\0");
+        assert_eq!(kargs.as_bytes(), b"arg1 arg2=val");
+
+        let mut iter = kargs.iter();
+        assert_eq!(iter.next(), Some(param("arg1")));
+        assert_eq!(iter.next(), Some(param("arg2=val")));
+        assert_eq!(iter.next(), None);
+
+        // Whitespace inside an argument doesn't split it, and empty
+        // arguments are dropped
+        let kargs = Cmdline::from_nul_separated(b"--opt=a b\0\0two words\0more words=x y\0x=\xff");
+        let mut iter = kargs.iter();
+        let p = iter.next().unwrap();
+        assert_eq!(p.key(), "--opt".into());
+        assert_eq!(p.value(), Some(b"a b".as_slice()));
+        let p = iter.next().unwrap();
+        assert_eq!(p.key(), "two words".into());
+        assert!(p.is_switch());
+        let p = iter.next().unwrap();
+        assert_eq!(p.key(), "more words".into());
+        assert_eq!(p.value(), Some(b"x y".as_slice()));
+        let p = iter.next().unwrap();
+        assert_eq!(p.key(), "x".into());
+        assert_eq!(p.value(), Some(b"\xff".as_slice()));
+        assert_eq!(iter.next(), None);
+
+        // Whitespace in the key quotes the whole parameter
+        let kargs = Cmdline::from_nul_separated(b"two words=x");
+        assert_eq!(kargs.as_bytes(), b"\"two words=x\"");
+        assert_eq!(kargs.param_count(), 1);
+        assert_eq!(kargs.value_of("two words"), Some(b"x".as_slice()));
+
+        assert!(Cmdline::from_nul_separated(b"").iter().next().is_none());
+        assert!(Cmdline::from_nul_separated(b"\0\0").iter().next().is_none());
+    }
+
+    #[test]
+    fn test_from_pid() {
+        let kargs = Cmdline::from_pid(std::process::id()).unwrap();
+        let argv0 = std::env::args_os().next().unwrap();
+        let first = kargs.iter().next().unwrap();
+        assert_eq!(first.key(), argv0.as_encoded_bytes().into());
+    }
+
     #[test]
     fn test_kargs_find_dash_hyphen() {
         let kargs = Cmdline::from(b"a-b=1 a_b=2".as_slice());
@@ -832,6 +2006,79 @@ mod tests {
         assert_eq!(kargs.value_of("under-key"), Some(b"value2".as_slice()));
     }
 
+    #[test]
+    fn test_find_all_exact() {
+        let kargs = Cmdline::from(b"console=ttyS0 quiet console=tty0 consoleblank=0");
+        let consoles: Vec<_> = kargs.find_all("console").filter_map(|p| p.value).collect();
+        assert_eq!(consoles, [b"ttyS0".as_slice(), b"tty0"]);
+
+        // Non-UTF8 values are kept, and dashes match underscores
+        let kargs = Cmdline::from(b"foo-bar=\xff foo_bar=ok");
+        let values: Vec<_> = kargs.find_all("foo-bar").filter_map(|p| p.value).collect();
+        assert_eq!(values, [b"\xff".as_slice(), b"ok"]);
+
+        assert_eq!(kargs.find_all("missing").count(), 0);
+    }
+
+    #[test]
+    fn test_find_last() {
+        let kargs = Cmdline::from(b"root=/dev/a quiet root=/dev/b");
+        assert_eq!(kargs.value_of("root"), Some(b"/dev/a".as_slice()));
+        assert_eq!(kargs.value_of_last("root"), Some(b"/dev/b".as_slice()));
+        assert_eq!(
+            kargs.find_last("root").unwrap(),
+            Parameter::parse("root=/dev/b").unwrap()
+        );
+
+        // Dash/underscore equivalence and quote stripping match find()
+        let kargs = Cmdline::from(b"foo-bar=1 \"foo_bar=a b\" foo-bar");
+        assert_eq!(kargs.find_last("foo_bar").unwrap().value, None);
+        let kargs = Cmdline::from(b"foo-bar=1 \"foo_bar=a b\"");
+        assert_eq!(kargs.value_of_last("foo-bar"), Some(b"a b".as_slice()));
+
+        assert!(kargs.find_last("missing").is_none());
+        assert_eq!(kargs.value_of_last("missing"), None);
+    }
+
+    #[test]
+    fn test_value_equals() {
+        let p = param("foo=\"bar baz\"");
+        assert!(p.value_equals("bar baz"));
+        assert!(p.value_equals("\"bar baz\""));
+        assert!(!p.value_equals("bar"));
+
+        let p = param("foo=bar");
+        assert!(p.value_equals("\"bar\""));
+        assert!(p.value_equals(b"bar"));
+
+        // Only a balanced pair of quotes is stripped
+        assert!(!p.value_equals("\"bar"));
+        assert!(!p.value_equals("bar\""));
+        assert!(!p.value_equals("\""));
+
+        let p = param("foo=");
+        assert!(p.value_equals(""));
+        assert!(p.value_equals("\"\""));
+
+        // A switch has no value to compare
+        assert!(!param("foo").value_equals(""));
+    }
+
+    #[test]
+    fn test_contains_is_switch() {
+        let kargs = Cmdline::from(b"quiet rd.break=pre-mount foo-bar=1");
+        assert!(kargs.contains_param("quiet"));
+        assert!(kargs.contains_param("rd.break"));
+        assert!(kargs.contains_param("foo_bar"));
+        assert!(!kargs.contains_param("missing"));
+        assert!(!kargs.contains_param("rd"));
+
+        assert!(param("quiet").is_switch());
+        assert!(!param("rd.break=pre-mount").is_switch());
+        // An empty value is still a value
+        assert!(!param("foo=").is_switch());
+    }
+
     #[test]
     fn test_require_value_of() {
         let kargs = Cmdline::from(b"foo=bar baz=qux switch".as_slice());
@@ -873,6 +2120,187 @@ mod tests {
         assert_eq!(rd_args[3], param("rd.qux=c"));
     }
 
+    #[test]
+    fn test_module_params() {
+        let kargs = Cmdline::from(b"i915.enable_psr=0 quiet i915.fastboot=1 nvidia.foo=bar i915");
+        let params: Vec<_> = kargs.module_params("i915").collect();
+        assert_eq!(
+            params,
+            [
+                (ParameterKey::from("enable_psr"), Some(b"0".as_slice())),
+                (ParameterKey::from("fastboot"), Some(b"1".as_slice())),
+            ]
+        );
+
+        // Dash/underscore equivalence applies to both halves independently
+        let kargs = Cmdline::from(b"snd-hda-intel.power_save=1 snd_hda_intel.probe-mask=2");
+        let params: Vec<_> = kargs.module_params("snd_hda_intel").collect();
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].0, "power-save".into());
+        assert_eq!(params[1].0, "probe_mask".into());
+        assert_eq!(params[1].1, Some(b"2".as_slice()));
+
+        assert_eq!(kargs.module_params("nvidia").count(), 0);
+    }
+
+    #[test]
+    fn test_iter_matching() {
+        let kargs = Cmdline::from(
+            b"console=tty0 quiet root=/dev/sda1 console=ttyS0 systemd.log_level=debug ro rd.lvm.lv=vg/root",
+        );
+        let matched: Vec<_> = kargs
+            .iter_matching(&["console", "rd.lvm.lv", "systemd.log-level", "missing"])
+            .collect();
+        assert_eq!(
+            matched,
+            [
+                param("console=tty0"),
+                param("console=ttyS0"),
+                param("systemd.log_level=debug"),
+                param("rd.lvm.lv=vg/root")
+            ]
+        );
+
+        assert_eq!(kargs.iter_matching::<&str>(&[]).next(), None);
+    }
+
+    #[test]
+    fn test_effective_group_value() {
+        const MODESET: ArgGroup = ArgGroup::new(&["nomodeset", "modeset", "i915.modeset=0"]);
+
+        let kargs = Cmdline::from(b"nomodeset quiet i915.modeset=1 modeset=1 root=/dev/sda");
+        assert_eq!(
+            kargs.effective_group_value(&MODESET),
+            Some(param("modeset=1"))
+        );
+
+        let kargs = Cmdline::from(b"modeset=1 quiet i915.modeset=1 nomodeset");
+        assert_eq!(
+            kargs.effective_group_value(&MODESET),
+            Some(param("nomodeset"))
+        );
+
+        // Only the exact key=value member matches
+        let kargs = Cmdline::from(b"nomodeset i915.modeset=1");
+        assert_eq!(
+            kargs.effective_group_value(&MODESET),
+            Some(param("nomodeset"))
+        );
+        let kargs = Cmdline::from(b"nomodeset i915.modeset=0");
+        assert_eq!(
+            kargs.effective_group_value(&MODESET),
+            Some(param("i915.modeset=0"))
+        );
+
+        let kargs = Cmdline::from(b"quiet root=/dev/sda");
+        assert_eq!(kargs.effective_group_value(&MODESET), None);
+    }
+
+    #[test]
+    fn test_parameter_owned() {
+        let owned = {
+            let kargs = Cmdline::from(b"foo-bar=a \"baz=b c\" switch");
+            kargs.iter().map(ParameterOwned::from).collect::<Vec<_>>()
+        };
+
+        assert_eq!(owned[0].key(), "foo_bar".into());
+        assert_eq!(owned[0].value(), Some(b"a".as_slice()));
+        assert_eq!(&*owned[1], b"\"baz=b c\"");
+        assert_eq!(owned[1].value(), Some(b"b c".as_slice()));
+        assert!(owned[2].as_parameter().is_switch());
+
+        // Keys keep their dash/underscore equivalence
+        assert_eq!(owned[0], ParameterOwned::from(param("foo_bar=a")));
+        assert_eq!(owned[0], param("foo_bar=a"));
+        assert_ne!(owned[0], param("foo_bar=b"));
+        assert_ne!(owned[2], param("switch=a"));
+    }
+
+    #[test]
+    fn test_key_str_value_str() {
+        let p = param("foo=bar");
+        assert_eq!(p.key_str(), Some("foo"));
+        assert_eq!(p.value_str(), Some("bar"));
+
+        let p = param("quiet");
+        assert_eq!(p.key_str(), Some("quiet"));
+        assert_eq!(p.value_str(), None);
+
+        // Each half is checked on its own
+        let p = Parameter::parse(b"foo=\xff").unwrap();
+        assert_eq!(p.key_str(), Some("foo"));
+        assert_eq!(p.value_str(), None);
+
+        let p = Parameter::parse(b"\xff=bar").unwrap();
+        assert_eq!(p.key_str(), None);
+        assert_eq!(p.value_str(), Some("bar"));
+    }
+
+    #[test]
+    fn test_value_as_kv() {
+        let p = param("systemd.setenv=FOO=bar");
+        let nested = p.value_as_kv().unwrap();
+        assert_eq!(nested.key(), "FOO".into());
+        assert_eq!(nested.value(), Some(b"bar".as_slice()));
+
+        // Only the first `=` of the value splits the nested parameter
+        let nested = param("systemd.setenv=FOO=bar=baz").value_as_kv().unwrap();
+        assert_eq!(nested.key(), "FOO".into());
+        assert_eq!(nested.value(), Some(b"bar=baz".as_slice()));
+
+        let nested = param("systemd.setenv=FOO=").value_as_kv().unwrap();
+        assert_eq!(nested.key(), "FOO".into());
+        assert_eq!(nested.value(), Some(b"".as_slice()));
+
+        // Whole parameter quoted on the command line
+        let kargs = Cmdline::from(br#""systemd.setenv=FOO=a b""#);
+        let p = kargs.find("systemd.setenv").unwrap();
+        let nested = p.value_as_kv().unwrap();
+        assert_eq!(nested.key(), "FOO".into());
+        assert_eq!(nested.value(), Some(b"a b".as_slice()));
+
+        let nested = param("systemd.unit=rescue.target").value_as_kv().unwrap();
+        assert_eq!(nested.key(), "rescue.target".into());
+        assert!(nested.is_switch());
+
+        assert!(param("systemd.setenv").value_as_kv().is_none());
+        assert!(param("systemd.setenv=").value_as_kv().is_none());
+    }
+
+    #[test]
+    fn test_values() {
+        fn values(s: &str) -> Vec<&[u8]> {
+            param(s).values().collect()
+        }
+
+        assert_eq!(values("foo=bar,bar2"), [b"bar".as_slice(), b"bar2"]);
+        assert_eq!(values("foo=bar"), [b"bar".as_slice()]);
+        assert_eq!(values("foo=a,,b"), [b"a".as_slice(), b"", b"b"]);
+        assert_eq!(values("\"foo=a b,c\""), [b"a b".as_slice(), b"c"]);
+        assert!(values("switch").is_empty());
+    }
+
+    #[test]
+    fn test_values_multi() {
+        let p = param("foo=a,b:c,,d");
+        let values: Vec<_> = p.values_multi(b",:").collect();
+        assert_eq!(values, [b"a".as_slice(), b"b", b"c", b"d"]);
+
+        // Separators inside quotes are kept
+        let p = param(r#"foo=a:"b,c":d"#);
+        let values: Vec<_> = p.values_multi(b",:").collect();
+        assert_eq!(values, [b"a".as_slice(), b"b,c", b"d"]);
+
+        // Whole value quoted on the command line
+        let kargs = Cmdline::from(br#"foo="a, b c""#);
+        let p = kargs.find("foo").unwrap();
+        let values: Vec<_> = p.values_multi(b", ").collect();
+        assert_eq!(values, [b"a".as_slice(), b"b", b"c"]);
+
+        assert_eq!(param("foo").values_multi(b",").next(), None);
+        assert_eq!(param("foo=").values_multi(b",").next(), None);
+    }
+
     #[test]
     fn test_add() {
         let mut kargs = Cmdline::from(b"console=tty0 console=ttyS1");
@@ -913,6 +2341,152 @@ mod tests {
         assert_eq!(kargs.0, b"foo".as_slice());
     }
 
+    #[test]
+    fn test_display() {
+        let kargs = Cmdline::from(b"  foo=bar   baz\tquux=\"a b\"\n");
+        assert_eq!(kargs.to_string(), "foo=bar baz quux=\"a b\"");
+
+        // Parameters quoted as a whole are requoted around the value
+        let kargs = Cmdline::from(b"\"foo=a b\" \"switch with spaces\" \"plain\"");
+        let displayed = kargs.to_string();
+        assert_eq!(displayed, "foo=\"a b\" \"switch with spaces\" plain");
+        assert_eq!(Cmdline::from(displayed.as_bytes()), kargs);
+
+        // Non-UTF8 bytes are replaced
+        let kargs = Cmdline::from(b"foo=\xff bar");
+        assert_eq!(kargs.to_string(), "foo=\u{fffd} bar");
+
+        assert_eq!(Cmdline::new().to_string(), "");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let kargs = Cmdline::from(b"root=/dev/sda1 quiet \"foo=a b\" bad=\xffvalue");
+        let json = serde_json::to_value(&kargs).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"key": "root", "value": "/dev/sda1"},
+                {"key": "quiet", "value": null},
+                {"key": "foo", "value": "a b"},
+                {"key": "bad", "value": [0xff, b'v', b'a', b'l', b'u', b'e']},
+            ])
+        );
+
+        let roundtrip: CmdlineOwned = serde_json::from_value(json).unwrap();
+        assert_eq!(roundtrip, kargs);
+        assert_eq!(roundtrip.value_of("bad"), Some(b"\xffvalue".as_slice()));
+
+        let empty: CmdlineOwned = serde_json::from_str("[]").unwrap();
+        assert_eq!(empty.iter().count(), 0);
+
+        let err = serde_json::from_str::<CmdlineOwned>(r#"[{"key": "a=b", "value": "c"}]"#);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_set_remove_push_switch() {
+        let mut kargs = Cmdline::from(b"rd_break=pre-mount root=/dev/sda1 quiet root=/dev/sda3");
+
+        assert_eq!(kargs.set("root", "/dev/sda2"), Action::Modified);
+        assert_eq!(kargs.set("root", "/dev/sda2"), Action::Existed);
+
+        // Dashes and underscores are equivalent when replacing
+        assert_eq!(kargs.set("rd-break", "initqueue"), Action::Modified);
+        assert_eq!(kargs.value_of("rd_break"), Some(b"initqueue".as_slice()));
+
+        // Values with whitespace are quoted
+        assert_eq!(kargs.set("foo", "a b"), Action::Added);
+        assert_eq!(kargs.value_of("foo"), Some(b"a b".as_slice()));
+
+        assert!(kargs.remove(&"quiet".into()));
+
+        assert_eq!(kargs.push_switch("rd.break"), Action::Added);
+        assert_eq!(kargs.push_switch("rd.break"), Action::Existed);
+
+        let bytes = kargs.to_bytes();
+        assert_eq!(
+            bytes,
+            b"rd-break=initqueue root=/dev/sda2 foo=\"a b\" rd.break"
+        );
+
+        // Round trips through the parser
+        assert_eq!(Cmdline::from(bytes), kargs);
+    }
+
+    #[test]
+    fn test_set_if_absent() {
+        let mut kargs = Cmdline::from(b"console=ttyS0 rd_break quiet");
+
+        // Already set by the user, with another value or as a switch
+        assert_eq!(kargs.set_if_absent("console", "tty0"), Action::Existed);
+        assert_eq!(
+            kargs.set_if_absent("rd-break", "pre-mount"),
+            Action::Existed
+        );
+        assert_eq!(kargs.as_bytes(), b"console=ttyS0 rd_break quiet");
+
+        assert_eq!(kargs.set_if_absent("root", "/dev/sda1"), Action::Added);
+        assert_eq!(kargs.set_if_absent("root", "/dev/sda2"), Action::Existed);
+        assert_eq!(
+            kargs.as_bytes(),
+            b"console=ttyS0 rd_break quiet root=/dev/sda1"
+        );
+    }
+
+    #[test]
+    fn test_try_push_kv() {
+        let mut kargs = Cmdline::from(b"root=/dev/sda");
+
+        kargs.try_push_kv("console", "ttyS0", 31).unwrap();
+        assert_eq!(kargs.as_bytes(), b"root=/dev/sda console=ttyS0");
+
+        // Exactly at the limit
+        kargs.try_push_kv("a", "b", 31).unwrap();
+        assert_eq!(kargs.as_bytes(), b"root=/dev/sda console=ttyS0 a=b");
+        assert_eq!(kargs.as_bytes().len(), 31);
+
+        // Over the limit, nothing is changed
+        let err = kargs.try_push_kv("c", "d", 31).unwrap_err();
+        assert!(err.to_string().contains("35 bytes"));
+        assert_eq!(kargs.as_bytes(), b"root=/dev/sda console=ttyS0 a=b");
+
+        // Values with whitespace are quoted
+        let mut kargs = Cmdline::new();
+        kargs.try_push_kv("foo", "a b", 100).unwrap();
+        assert_eq!(kargs.as_bytes(), b"foo=\"a b\"");
+        assert_eq!(kargs.value_of("foo"), Some(b"a b".as_slice()));
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut kargs = Cmdline::from(b"root=/dev/sda1 quiet");
+
+        kargs.push_front(&param("BOOT_IMAGE=/vmlinuz"));
+        assert_eq!(
+            kargs.as_bytes(),
+            b"BOOT_IMAGE=/vmlinuz root=/dev/sda1 quiet"
+        );
+
+        kargs.insert_at(2, &param("rw"));
+        assert_eq!(
+            kargs.as_bytes(),
+            b"BOOT_IMAGE=/vmlinuz root=/dev/sda1 rw quiet"
+        );
+
+        // Out of range indices append
+        kargs.insert_at(100, &param("--"));
+        assert_eq!(
+            kargs.as_bytes(),
+            b"BOOT_IMAGE=/vmlinuz root=/dev/sda1 rw quiet --"
+        );
+
+        let mut kargs = Cmdline::new();
+        kargs.push_front(&param("quiet"));
+        assert_eq!(kargs.as_bytes(), b"quiet");
+    }
+
     #[test]
     fn test_add_or_modify() {
         let mut kargs = Cmdline::from(b"foo=bar");
@@ -1107,6 +2681,271 @@ mod tests {
         assert_eq!(params.len(), 0);
     }
 
+    #[test]
+    fn test_diff() {
+        let old =
+            Cmdline::from(b"root=/dev/sda1 quiet console=tty0 console=ttyS0 debug mitigations=off");
+        let new = Cmdline::from(b"root=/dev/sda2 quiet console=ttyS0 debug=1 nosmt mitigations");
+        let diff = old.diff(&new);
+
+        assert_eq!(diff.added, [param("nosmt")]);
+        assert_eq!(diff.removed, [param("console=tty0")]);
+        assert_eq!(
+            diff.changed,
+            [
+                (param("root=/dev/sda1"), param("root=/dev/sda2")),
+                // A switch gaining a value is a change, and vice versa
+                (param("debug"), param("debug=1")),
+                (param("mitigations=off"), param("mitigations")),
+            ]
+        );
+
+        // Duplicate keys are matched up by value, then in order
+        let old = Cmdline::from(b"console=a console=b console=c");
+        let new = Cmdline::from(b"console=c console=d console=e console=f");
+        let diff = old.diff(&new);
+        assert_eq!(diff.added, [param("console=f")]);
+        assert!(diff.removed.is_empty());
+        assert_eq!(
+            diff.changed,
+            [
+                (param("console=a"), param("console=d")),
+                (param("console=b"), param("console=e"))
+            ]
+        );
+
+        // Dash/underscore differences alone are not changes
+        let old = Cmdline::from(b"foo-bar=1 baz");
+        let new = Cmdline::from(b"foo_bar=1 baz");
+        assert_eq!(old.diff(&new), CmdlineDiff::default());
+
+        let empty = Cmdline::new();
+        let diff = old.diff(&empty);
+        assert_eq!(diff.removed, [param("foo-bar=1"), param("baz")]);
+    }
+
+    #[test]
+    fn test_minus() {
+        let baseline = Cmdline::from(b"ro quiet console=tty0 rhgb");
+        let kargs = Cmdline::from(b"root=/dev/sda1 ro console=ttyS0 quiet console=tty0 rd_break");
+
+        let extra = kargs.minus(&baseline);
+        assert_eq!(extra.as_bytes(), b"root=/dev/sda1 console=ttyS0 rd_break");
+
+        assert_eq!(kargs.minus(&kargs).as_bytes(), b"");
+        assert_eq!(kargs.minus(&Cmdline::new()), kargs);
+    }
+
+    #[test]
+    fn test_validate() {
+        Cmdline::from(b"root=/dev/sda1\tquiet foo=\xff\n".as_slice())
+            .validate()
+            .unwrap();
+        Cmdline::new().validate().unwrap();
+
+        let err = Cmdline::from(b"foo=a\0b quiet".as_slice())
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Kernel command line contains control characters: NUL at offset 5"
+        );
+
+        let err = Cmdline::from(b"foo\nbar\x1b baz\n\n".as_slice())
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Kernel command line contains control characters: newline at offset 3, 0x1b at offset 7, newline at offset 12"
+        );
+
+        // Parsing itself is unchanged
+        let kargs = Cmdline::from(b"foo\nbar".as_slice());
+        assert_eq!(kargs.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_stable_hash() {
+        let hash = |s: &str| Cmdline::from(s).stable_hash();
+
+        // FNV-1a of the empty input
+        assert_eq!(hash(""), 0xcbf29ce484222325);
+        assert_eq!(hash("foo=a"), hash("foo=a"));
+
+        // Order, spacing, quoting and dash/underscore don't matter
+        assert_eq!(hash("foo=a bar=b"), hash("bar=b foo=a"));
+        assert_eq!(hash("foo=a  bar-baz=b"), hash("bar_baz=b \"foo=a\""));
+
+        assert_ne!(hash("foo=a bar=b"), hash("foo=a bar=c"));
+        assert_ne!(hash("foo"), hash("foo="));
+        assert_ne!(hash("foo"), hash("foo foo"));
+        assert_ne!(hash("foo=a"), hash("Foo=a"));
+        // Parameter boundaries are part of the digest
+        assert_ne!(hash("foo=a b"), hash("foo=ab"));
+    }
+
+    #[test]
+    fn test_split_first() {
+        let kargs = Cmdline::from(b"  foo=bar \"baz=a b\"   quiet\xff\n".as_slice());
+
+        let (first, rest) = kargs.split_first().unwrap();
+        assert_eq!(first, param("foo=bar"));
+        assert!(rest.is_borrowed());
+
+        // Splitting recursively yields the same parameters as iterating
+        fn split_all(kargs: &Cmdline, split: &mut Vec<Vec<u8>>) {
+            if let Some((first, rest)) = kargs.split_first() {
+                split.push(first.to_vec());
+                split_all(&rest, split);
+            }
+        }
+
+        let mut split = vec![first.to_vec()];
+        split_all(&rest, &mut split);
+        let expected: Vec<_> = kargs.iter().map(|p| p.to_vec()).collect();
+        assert_eq!(split, expected);
+
+        assert!(Cmdline::from(b" \t".as_slice()).split_first().is_none());
+    }
+
+    #[test]
+    fn test_param_count() {
+        let kargs = Cmdline::from(b"  foo=bar \"baz=a b\"   quiet\xff\n".as_slice());
+        assert_eq!(kargs.param_count(), 3);
+        assert_eq!(kargs.param_count(), kargs.iter().count());
+        assert_eq!(kargs.byte_len(), 29);
+
+        for input in [b"".as_slice(), b" \t\n".as_slice()] {
+            let kargs = Cmdline::from(input);
+            assert_eq!(kargs.param_count(), 0);
+            assert_eq!(kargs.byte_len(), input.len());
+        }
+    }
+
+    #[test]
+    fn test_overlay() {
+        let base = Cmdline::from(b"root=/dev/sda1 console=tty0 quiet console=ttyS0 rhgb");
+
+        // Addition
+        let merged = base.overlay(&Cmdline::from(b"rd.break selinux=0"));
+        assert_eq!(
+            merged.as_bytes(),
+            b"root=/dev/sda1 console=tty0 quiet console=ttyS0 rhgb rd.break selinux=0"
+        );
+
+        // Replacement, of all duplicates in place, and by a switch
+        let merged = base.overlay(&Cmdline::from(
+            b"console=hvc0 rhgb=1 nomodeset console=hvc1",
+        ));
+        assert_eq!(
+            merged.as_bytes(),
+            b"root=/dev/sda1 console=hvc0 console=hvc1 quiet rhgb=1 nomodeset"
+        );
+        let merged = base.overlay(&Cmdline::from(b"root"));
+        assert_eq!(
+            merged.as_bytes(),
+            b"root console=tty0 quiet console=ttyS0 rhgb"
+        );
+
+        // Removal, which doesn't keep later parameters from being added
+        let merged = base.overlay(&Cmdline::from(b"!console !missing !rhgb rhgb=0"));
+        assert_eq!(merged.as_bytes(), b"root=/dev/sda1 quiet rhgb=0");
+
+        // No-ops
+        assert_eq!(base.overlay(&Cmdline::new()), base);
+        assert_eq!(base.overlay(&Cmdline::from(b"quiet rhgb")), base);
+        assert_eq!(
+            Cmdline::new().overlay(&base).as_bytes(),
+            b"root=/dev/sda1 console=tty0 quiet console=ttyS0 rhgb"
+        );
+    }
+
+    #[test]
+    fn test_filter_keys_allow() {
+        let kargs = Cmdline::from(b"root=/dev/sda quiet rd-break console=tty0 console=ttyS0");
+        let (filtered, rejected) =
+            kargs.filter_keys(KeyPolicy::Allow(&["root", "console", "rd_break"]));
+
+        let mut iter = filtered.iter();
+        assert_eq!(iter.next(), Some(param("root=/dev/sda")));
+        assert_eq!(iter.next(), Some(param("rd-break")));
+        assert_eq!(iter.next(), Some(param("console=tty0")));
+        assert_eq!(iter.next(), Some(param("console=ttyS0")));
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(rejected.len(), 1);
+        assert_eq!(rejected[0], "quiet".into());
+    }
+
+    #[test]
+    fn test_filtered() {
+        let kargs = Cmdline::from(
+            b"root=/dev/sda1 rd.luks.key=/secret:UUID=abcd \"rootflags=a b\" rd.luks.uuid=abcd rd.luks.key=/other quiet",
+        );
+        let luks_key = ParameterKey::from("rd.luks.key");
+
+        let filtered = kargs.filtered(|p| p.key != luks_key);
+        assert!(filtered.is_owned());
+        assert_eq!(
+            filtered.as_bytes(),
+            b"root=/dev/sda1 \"rootflags=a b\" rd.luks.uuid=abcd quiet"
+        );
+
+        // Everything else parses the same as before
+        let expected: Vec<_> = kargs.iter().filter(|p| p.key != luks_key).collect();
+        assert_eq!(filtered.iter().collect::<Vec<_>>(), expected);
+
+        assert_eq!(
+            kargs.filtered(|_| true).as_bytes(),
+            kargs.iter_bytes().collect::<Vec<_>>().join(b" ".as_slice())
+        );
+        assert!(kargs.filtered(|_| false).as_bytes().is_empty());
+    }
+
+    #[test]
+    fn test_filter_keys_deny() {
+        let kargs = Cmdline::from(b"root=/dev/sda init=/bin/sh quiet init_on_free=1");
+        let (filtered, rejected) = kargs.filter_keys(KeyPolicy::Deny(&["init", "init-on-free"]));
+
+        let mut iter = filtered.iter();
+        assert_eq!(iter.next(), Some(param("root=/dev/sda")));
+        assert_eq!(iter.next(), Some(param("quiet")));
+        assert_eq!(iter.next(), None);
+
+        assert_eq!(rejected, vec!["init".into(), "init_on_free".into()]);
+    }
+
+    #[test]
+    fn test_is_debug_boot() {
+        for trigger in [
+            "rd.break",
+            "rd.break=pre-mount",
+            "emergency",
+            "single",
+            "systemd.unit=emergency.target",
+            "init=/bin/sh",
+        ] {
+            let s = format!("root=UUID=1234 ro {trigger} quiet");
+            assert!(Cmdline::from(&s).is_debug_boot(), "{trigger}");
+        }
+
+        let kargs =
+            Cmdline::from("root=UUID=1234 ro systemd.unit=multi-user.target init=/sbin/init");
+        assert!(!kargs.is_debug_boot());
+
+        // The set of triggers can be overridden
+        assert!(kargs.is_debug_boot_with(&["systemd.unit=multi-user.target"]));
+        assert!(!Cmdline::from("single").is_debug_boot_with(&["emergency"]));
+    }
+
+    #[test]
+    fn test_as_bytes() {
+        let input = b"  foo=\"bar  baz\"   \xff wiz\n";
+        assert_eq!(Cmdline::from(input).as_bytes(), input);
+        assert_eq!(Cmdline::from(input.to_vec()).as_bytes(), input);
+        assert_eq!(Cmdline::new().as_bytes(), b"");
+    }
+
     #[test]
     fn test_cmdline_eq() {
         // Ordering, quoting, and the whole dash-underscore
@@ -9,14 +9,57 @@
 //!
 //! The `utf8` module performs the same functionality, but requires
 //! all data to be valid UTF-8.
+//!
+//! The optional `serde` feature implements `Serialize` and
+//! `Deserialize` for both command line types, as an ordered list of
+//! `{key, value}` entries.
 
 pub mod bytes;
 pub mod utf8;
 
 /// This is used by dracut.
 pub const INITRD_ARG_PREFIX: &str = "rd.";
+/// Prefix marking a parameter of an overlay command line as removing
+/// the parameter(s) with that key from the base, e.g. `!quiet`.  See
+/// `Cmdline::overlay`.
+pub const OVERLAY_REMOVE_PREFIX: &str = "!";
 /// The kernel argument for configuring the rootfs flags.
 pub const ROOTFLAGS: &str = "rootflags";
+/// The kernel argument bootloaders set to the path of the booted kernel.
+pub const BOOT_IMAGE: &str = "BOOT_IMAGE";
+/// Kernel arguments which indicate a debug or emergency boot.
+///
+/// Switches match regardless of any value they were given (so
+/// `rd.break=pre-mount` matches `rd.break`), while `key=value`
+/// entries must match exactly.  This is the default set used by
+/// `is_debug_boot`; use `is_debug_boot_with` to check a different set.
+pub const DEBUG_BOOT_ARGS: &[&str] = &[
+    "rd.break",
+    "emergency",
+    "single",
+    "systemd.unit=emergency.target",
+    "init=/bin/sh",
+];
+
+/// A group of mutually exclusive kernel arguments.
+///
+/// When several members of a group appear on a command line, the last
+/// one takes effect, even if they are spelled differently (e.g.
+/// `nomodeset` followed by `modeset=1`).  Members are matched the same
+/// way as [`DEBUG_BOOT_ARGS`]: switches match regardless of any value,
+/// while `key=value` members must match exactly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArgGroup<'g> {
+    /// The arguments making up this group.
+    pub members: &'g [&'g str],
+}
+
+impl<'g> ArgGroup<'g> {
+    /// Create a group from its member arguments.
+    pub const fn new(members: &'g [&'g str]) -> Self {
+        Self { members }
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 /// Possible outcomes for `add_or_modify` operations.
@@ -30,3 +73,83 @@ pub enum Action {
     /// the newly-requested value.  No modification was made.
     Existed,
 }
+
+/// Something suspicious about a parameter, which parsing accepted
+/// anyway.
+///
+/// This is reported by the `iter_checked` method on `Cmdline`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ParseWarning {
+    /// The parameter has an odd number of double quotes, so its
+    /// quoted section extends to the end of the command line.
+    UnbalancedQuote,
+    /// The parameter has an empty key, e.g. the value-only `=foo`.
+    EmptyKey,
+    /// The key still contains a double quote after parsing, e.g. the
+    /// key of `"foo"=bar` is `foo"`.
+    QuoteInKey,
+}
+
+/// A policy restricting which parameter keys may appear in a command line.
+///
+/// Keys are compared with dashes and underscores treated as
+/// equivalent, the same as [`bytes::ParameterKey`] equality.
+#[derive(Debug, Clone, Copy)]
+pub enum KeyPolicy<'k> {
+    /// Only parameters whose key is in the list are permitted.
+    Allow(&'k [&'k str]),
+    /// Parameters whose key is in the list are rejected.
+    Deny(&'k [&'k str]),
+}
+
+impl KeyPolicy<'_> {
+    /// Returns `true` if a parameter with the given key is permitted
+    /// by this policy.
+    pub(crate) fn permits(&self, key: &bytes::ParameterKey) -> bool {
+        let listed = |keys: &[&str]| keys.iter().any(|k| bytes::ParameterKey::from(*k) == *key);
+        match self {
+            KeyPolicy::Allow(keys) => listed(keys),
+            KeyPolicy::Deny(keys) => !listed(keys),
+        }
+    }
+}
+
+/// The differences between two command lines.
+///
+/// This is created by the `diff` method on `Cmdline`.  Keys are
+/// compared with dashes and underscores treated as equivalent.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CmdlineDiff<P> {
+    /// Parameters only present in the new command line
+    pub added: Vec<P>,
+    /// Parameters only present in the old command line
+    pub removed: Vec<P>,
+    /// Parameters whose key is present in both command lines but
+    /// with a different value, as `(old, new)` pairs
+    pub changed: Vec<(P, P)>,
+}
+
+impl<P> Default for CmdlineDiff<P> {
+    fn default() -> Self {
+        Self {
+            added: Vec::new(),
+            removed: Vec::new(),
+            changed: Vec::new(),
+        }
+    }
+}
+
+impl<P> CmdlineDiff<P> {
+    /// Convert each parameter in the diff with `f`.
+    pub(crate) fn map<Q>(self, f: impl Fn(P) -> Q) -> CmdlineDiff<Q> {
+        CmdlineDiff {
+            added: self.added.into_iter().map(&f).collect(),
+            removed: self.removed.into_iter().map(&f).collect(),
+            changed: self
+                .changed
+                .into_iter()
+                .map(|(o, n)| (f(o), f(n)))
+                .collect(),
+        }
+    }
+}
@@ -5,9 +5,10 @@
 
 use std::ops::Deref;
 
-use crate::{Action, bytes};
+use crate::{Action, ArgGroup, CmdlineDiff, KeyPolicy, ParseWarning, bytes};
 
 use anyhow::Result;
+#[cfg(feature = "serde")]
 use serde::{Deserialize, Serialize};
 
 /// A parsed UTF-8 kernel command line.
@@ -15,7 +16,7 @@ use serde::{Deserialize, Serialize};
 /// Wraps the raw command line bytes and provides methods for parsing and iterating
 /// over individual parameters. Uses copy-on-write semantics to avoid unnecessary
 /// allocations when working with borrowed data.
-#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Cmdline<'a>(bytes::Cmdline<'a>);
 
 /// An owned `Cmdline`.  Alias for `Cmdline<'static>`.
@@ -87,15 +88,61 @@ impl<'a> Cmdline<'a> {
     ///   - There are I/O issues
     ///   - The cmdline from proc is not valid UTF-8
     pub fn from_proc() -> Result<Self> {
-        let cmdline = std::fs::read("/proc/cmdline")?;
+        Self::from_reader(std::fs::File::open("/proc/cmdline")?)
+    }
 
-        // SAFETY: validate the value from proc is valid UTF-8.  We
-        // don't need to save this, but checking now will ensure we
-        // can safely convert from the underlying bytes back to UTF-8
+    /// Reads a command line from `reader` until EOF.
+    ///
+    /// The bytes are read into an owned buffer, so this works equally
+    /// well with a file, a pipe or a command line captured from a boot
+    /// configuration.
+    ///
+    /// Returns an error if:
+    ///   - Reading fails
+    ///   - The data read is not valid UTF-8
+    pub fn from_reader(reader: impl std::io::Read) -> Result<CmdlineOwned> {
+        let cmdline = bytes::Cmdline::from_reader(reader)?;
+
+        // SAFETY: validate the data read is valid UTF-8.  We don't
+        // need to save this, but checking now will ensure we can
+        // safely convert from the underlying bytes back to UTF-8
         // later.
         str::from_utf8(&cmdline)?;
 
-        Ok(Self(bytes::Cmdline::from(cmdline)))
+        Ok(Cmdline(cmdline))
+    }
+
+    /// Builds a command line from NUL-separated arguments, as in
+    /// `/proc/<pid>/cmdline`.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::from_nul_separated`].
+    pub fn from_nul_separated(input: &str) -> CmdlineOwned {
+        Cmdline(bytes::Cmdline::from_nul_separated(input.as_bytes()))
+    }
+
+    /// Reads the command line of the process `pid` from
+    /// `/proc/<pid>/cmdline`.
+    ///
+    /// Returns an error if:
+    ///   - The file cannot be read, e.g. as the process doesn't exist
+    ///   - The command line is not valid UTF-8
+    pub fn from_pid(pid: u32) -> Result<CmdlineOwned> {
+        let cmdline = bytes::Cmdline::from_pid(pid)?;
+
+        // SAFETY: validate the data read is valid UTF-8, see
+        // `from_reader`
+        str::from_utf8(&cmdline)?;
+
+        Ok(Cmdline(cmdline))
+    }
+
+    /// Converts this command line into an owned one, copying the
+    /// underlying bytes if they are borrowed.
+    ///
+    /// This allows a parsed command line to outlive the buffer it was
+    /// created from.
+    pub fn into_owned(self) -> CmdlineOwned {
+        Cmdline(self.0.into_owned())
     }
 
     /// Returns an iterator over all parameters in the command line.
@@ -107,6 +154,16 @@ impl<'a> Cmdline<'a> {
         CmdlineIter(self.0.iter())
     }
 
+    /// Returns an iterator over all parameters in the command line,
+    /// along with anything suspicious about each of them.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::iter_checked`].
+    pub fn iter_checked(&'a self) -> impl Iterator<Item = (Parameter<'a>, Vec<ParseWarning>)> {
+        self.0
+            .iter_checked()
+            .map(|(p, warnings)| (Parameter::from_bytes(p), warnings))
+    }
+
     /// Returns an iterator over all parameters in the command line as string slices.
     ///
     /// This is similar to `iter()` but yields `&str` directly instead of `Parameter`,
@@ -115,6 +172,27 @@ impl<'a> Cmdline<'a> {
         CmdlineIterStr(self.0.iter_bytes())
     }
 
+    /// Returns the number of parameters in the command line.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::param_count`].
+    pub fn param_count(&self) -> usize {
+        self.0.param_count()
+    }
+
+    /// Returns the length of the command line in bytes.
+    pub fn byte_len(&self) -> usize {
+        self.0.byte_len()
+    }
+
+    /// Split off the first parameter of the command line.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::split_first`].
+    pub fn split_first(&'a self) -> Option<(Parameter<'a>, Cmdline<'a>)> {
+        self.0
+            .split_first()
+            .map(|(first, rest)| (Parameter::from_bytes(first), Cmdline(rest)))
+    }
+
     /// Locate a kernel argument with the given key name.
     ///
     /// Returns the first parameter matching the given key, or `None` if not found.
@@ -124,6 +202,35 @@ impl<'a> Cmdline<'a> {
         self.iter().find(|p| p.key() == key)
     }
 
+    /// Locate a kernel argument with the given key name, ignoring
+    /// ASCII case.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::find_ci`].
+    pub fn find_ci<T: AsRef<str> + ?Sized>(&'a self, key: &T) -> Option<Parameter<'a>> {
+        self.0
+            .find_ci(key.as_ref().as_bytes())
+            .map(Parameter::from_bytes)
+    }
+
+    /// Locate the last kernel argument with the given key name.
+    ///
+    /// Otherwise the same as [`Self::find`].
+    pub fn find_last<T: AsRef<str> + ?Sized>(&'a self, key: &T) -> Option<Parameter<'a>> {
+        let key = ParameterKey::from(key.as_ref());
+        self.iter().filter(|p| p.key() == key).last()
+    }
+
+    /// Iterate over every kernel argument with the given key name.
+    ///
+    /// Parameters are yielded in the order they appear on the command
+    /// line.  Key comparison treats dashes and underscores as equivalent.
+    pub fn find_all<T: AsRef<str> + ?Sized>(
+        &'a self,
+        key: &'a T,
+    ) -> impl Iterator<Item = Parameter<'a>> + 'a {
+        self.0.find_all(key.as_ref()).map(Parameter::from_bytes)
+    }
+
     /// Find all kernel arguments starting with the given UTF-8 prefix.
     ///
     /// This is a variant of [`Self::find`].
@@ -135,6 +242,62 @@ impl<'a> Cmdline<'a> {
             .filter(move |p| p.key().starts_with(prefix.as_ref()))
     }
 
+    /// Iterate over the parameters of the given kernel module.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::module_params`].
+    pub fn module_params<T: AsRef<str> + ?Sized>(
+        &'a self,
+        module: &'a T,
+    ) -> impl Iterator<Item = (ParameterKey<'a>, Option<&'a str>)> + 'a {
+        self.0.module_params(module.as_ref()).map(|(key, value)| {
+            let value = value.map(|v| {
+                // SAFETY: We know this is valid UTF-8 since we only
+                // construct the underlying `bytes` from valid UTF-8
+                str::from_utf8(v).expect("We only construct the underlying bytes from valid UTF-8")
+            });
+            (ParameterKey::from_bytes(key), value)
+        })
+    }
+
+    /// Iterate over the initrd arguments, with the `rd.` prefix
+    /// stripped from each key.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::initrd_params`].
+    pub fn initrd_params(
+        &'a self,
+    ) -> impl Iterator<Item = (ParameterKey<'a>, Option<&'a str>)> + 'a {
+        self.0.initrd_params().map(|(key, value)| {
+            let value = value.map(|v| {
+                // SAFETY: We know this is valid UTF-8 since we only
+                // construct the underlying `bytes` from valid UTF-8
+                str::from_utf8(v).expect("We only construct the underlying bytes from valid UTF-8")
+            });
+            (ParameterKey::from_bytes(key), value)
+        })
+    }
+
+    /// Iterate over the kernel arguments whose key is one of `keys`.
+    ///
+    /// Parameters are yielded in the order they appear on the command
+    /// line.  Key comparison treats dashes and underscores as equivalent.
+    pub fn iter_matching<T: AsRef<str>>(
+        &'a self,
+        keys: &[T],
+    ) -> impl Iterator<Item = Parameter<'a>> {
+        self.iter().filter(move |p| {
+            keys.iter()
+                .any(|k| p.key() == ParameterKey::from(k.as_ref()))
+        })
+    }
+
+    /// Check whether a kernel argument with the given key name is present.
+    ///
+    /// This matches both switches and `key=value` parameters.  Key
+    /// comparison treats dashes and underscores as equivalent.
+    pub fn contains_param<T: AsRef<str> + ?Sized>(&'a self, key: &T) -> bool {
+        self.0.contains_param(key.as_ref())
+    }
+
     /// Locate the value of the kernel argument with the given key name.
     ///
     /// Returns the first value matching the given key, or `None` if not found.
@@ -147,6 +310,29 @@ impl<'a> Cmdline<'a> {
         })
     }
 
+    /// Locate the value of the last kernel argument with the given key name.
+    ///
+    /// Otherwise the same as [`Self::value_of`].
+    pub fn value_of_last<T: AsRef<str> + ?Sized>(&'a self, key: &T) -> Option<&'a str> {
+        self.0.value_of_last(key.as_ref().as_bytes()).map(|v| {
+            // SAFETY: We know this is valid UTF-8 since we only
+            // construct the underlying `bytes` from valid UTF-8
+            str::from_utf8(v).expect("We only construct the underlying bytes from valid UTF-8")
+        })
+    }
+
+    /// Locate the value of the kernel argument with the given key
+    /// name, ignoring ASCII case.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::value_of_ci`].
+    pub fn value_of_ci<T: AsRef<str> + ?Sized>(&'a self, key: &T) -> Option<&'a str> {
+        self.0.value_of_ci(key.as_ref().as_bytes()).map(|v| {
+            // SAFETY: We know this is valid UTF-8 since we only
+            // construct the underlying `bytes` from valid UTF-8
+            str::from_utf8(v).expect("We only construct the underlying bytes from valid UTF-8")
+        })
+    }
+
     /// Find the value of the kernel argument with the provided name, which must be present.
     ///
     /// Otherwise the same as [`Self::value_of`].
@@ -156,6 +342,160 @@ impl<'a> Cmdline<'a> {
             .ok_or_else(|| anyhow::anyhow!("Failed to find kernel argument '{key}'"))
     }
 
+    /// Check that the command line contains no control characters.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::validate`].
+    pub fn validate(&self) -> Result<()> {
+        self.0.validate()
+    }
+
+    /// Hash the set of parameters, independently of their order.
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::stable_hash`].
+    pub fn stable_hash(&'a self) -> u64 {
+        self.0.stable_hash()
+    }
+
+    /// Collect the encrypted devices configured by the `rd.luks.*` arguments.
+    ///
+    /// Every UUID mentioned by `rd.luks.uuid=<uuid>`,
+    /// `rd.luks.name=<uuid>=<name>` or `rd.luks.options=<uuid>=<options>`
+    /// yields one device, in order of first appearance.  An optional
+    /// `luks-` prefix on the UUID is stripped.  A bare
+    /// `rd.luks.options=<options>` applies to every device that has no
+    /// options of its own, matching systemd-cryptsetup-generator.
+    pub fn luks_config(&'a self) -> Vec<LuksDevice> {
+        fn device<'d>(devices: &'d mut Vec<LuksDevice>, uuid: &str) -> &'d mut LuksDevice {
+            let idx = match devices.iter().position(|d| d.uuid == uuid) {
+                Some(idx) => idx,
+                None => {
+                    devices.push(LuksDevice {
+                        uuid: uuid.to_owned(),
+                        name: None,
+                        options: None,
+                    });
+                    devices.len() - 1
+                }
+            };
+            &mut devices[idx]
+        }
+
+        // Split `<uuid>=<rest>`, but only if the first part actually
+        // looks like a UUID, as options may contain `=` themselves.
+        fn split_uuid(value: &str) -> Option<(&str, &str)> {
+            let (uuid, rest) = value.split_once('=')?;
+            let uuid = uuid.strip_prefix("luks-").unwrap_or(uuid);
+            let is_uuid =
+                !uuid.is_empty() && uuid.chars().all(|c| c.is_ascii_hexdigit() || c == '-');
+            is_uuid.then_some((uuid, rest))
+        }
+
+        let uuid_key = ParameterKey::from("rd.luks.uuid");
+        let name_key = ParameterKey::from("rd.luks.name");
+        let options_key = ParameterKey::from("rd.luks.options");
+
+        let mut devices = Vec::new();
+        let mut default_options = None;
+
+        for p in self.iter() {
+            let Some(value) = p.value() else {
+                continue;
+            };
+            let key = p.key();
+
+            if key == uuid_key {
+                device(&mut devices, value.strip_prefix("luks-").unwrap_or(value));
+            } else if key == name_key {
+                if let Some((uuid, name)) = split_uuid(value) {
+                    device(&mut devices, uuid).name = Some(name.to_owned());
+                }
+            } else if key == options_key {
+                match split_uuid(value) {
+                    Some((uuid, options)) => {
+                        device(&mut devices, uuid).options = Some(options.to_owned())
+                    }
+                    None => default_options = Some(value.to_owned()),
+                }
+            }
+        }
+
+        if let Some(default_options) = default_options {
+            for d in devices.iter_mut().filter(|d| d.options.is_none()) {
+                d.options = Some(default_options.clone());
+            }
+        }
+
+        devices
+    }
+
+    /// Parse the value of the `rootflags=` argument into mount options.
+    ///
+    /// Each comma-separated option is split into its name and an
+    /// optional value, e.g. `rootflags=subvol=root,ro` yields
+    /// `("subvol", Some("root"))` and `("ro", None)`.  Empty options
+    /// are skipped.  As with the kernel, the last `rootflags=` wins.
+    ///
+    /// Returns `None` if `rootflags=` is not present.
+    pub fn rootflags(&'a self) -> Option<Vec<(&'a str, Option<&'a str>)>> {
+        let value = self.value_of_last(crate::ROOTFLAGS)?;
+        let options = value
+            .split(',')
+            .filter(|o| !o.is_empty())
+            .map(|o| match o.split_once('=') {
+                Some((k, v)) => (k, Some(v)),
+                None => (o, None),
+            })
+            .collect();
+        Some(options)
+    }
+
+    /// Parse the value of the `BOOT_IMAGE=` argument, which most
+    /// bootloaders set to the path of the kernel they booted.
+    ///
+    /// GRUB may prefix the path with the device it was loaded from,
+    /// e.g. `(hd0,gpt2)/vmlinuz`, which is split off.  The first
+    /// `BOOT_IMAGE=` wins, as that is the one the bootloader prepends.
+    ///
+    /// Returns `None` if `BOOT_IMAGE=` is not present.
+    pub fn boot_image(&'a self) -> Option<BootImage<'a>> {
+        let raw = self.value_of(crate::BOOT_IMAGE)?;
+
+        let (device, path) = raw
+            .strip_prefix('(')
+            .and_then(|rest| rest.split_once(')'))
+            .map_or((None, raw), |(device, path)| (Some(device), path));
+
+        Some(BootImage { raw, device, path })
+    }
+
+    /// Check whether this is a debug or emergency boot.
+    ///
+    /// Returns `true` if any of the arguments in
+    /// [`DEBUG_BOOT_ARGS`](crate::DEBUG_BOOT_ARGS) are present.
+    pub fn is_debug_boot(&'a self) -> bool {
+        self.0.is_debug_boot()
+    }
+
+    /// Check whether any of the given debug boot arguments are present.
+    ///
+    /// A switch in `triggers` matches any parameter with the same key,
+    /// whatever its value; a `key=value` trigger must match exactly.
+    pub fn is_debug_boot_with<T: AsRef<str>>(&'a self, triggers: &[T]) -> bool {
+        let triggers: Vec<&[u8]> = triggers.iter().map(|t| t.as_ref().as_bytes()).collect();
+        self.0.is_debug_boot_with(&triggers)
+    }
+
+    /// Find the member of a group of mutually exclusive arguments
+    /// that takes effect.
+    ///
+    /// Returns the last parameter on the command line matching any
+    /// member of `group`, or `None` if no member is present.
+    pub fn effective_group_value(&'a self, group: &ArgGroup) -> Option<Parameter<'a>> {
+        self.0
+            .effective_group_value(group)
+            .map(Parameter::from_bytes)
+    }
+
     /// Add a parameter to the command line if it doesn't already exist
     ///
     /// Returns `Action::Added` if the parameter did not already exist
@@ -188,6 +528,71 @@ impl<'a> Cmdline<'a> {
         self.0.add_or_modify(&param.0)
     }
 
+    /// Append `key=value` to the command line if the result fits in
+    /// `max_total` bytes
+    ///
+    /// The value is quoted if it contains whitespace.  If appending
+    /// would grow the command line past `max_total` (e.g. the kernel's
+    /// `COMMAND_LINE_SIZE`), an error is returned and the command line
+    /// is left unchanged.
+    pub fn try_push_kv<K: AsRef<str> + ?Sized, V: AsRef<str> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: &V,
+        max_total: usize,
+    ) -> Result<()> {
+        self.0.try_push_kv(key.as_ref(), value.as_ref(), max_total)
+    }
+
+    /// Set `key` to `value`
+    ///
+    /// Like [`Self::add_or_modify`], this replaces the first parameter
+    /// with the same key (treating dashes and underscores as
+    /// equivalent) and removes any others, or appends the parameter if
+    /// the key isn't present.  The value is quoted if it contains
+    /// whitespace, so that it parses back to the same value.
+    pub fn set<K: AsRef<str> + ?Sized, V: AsRef<str> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: &V,
+    ) -> Action {
+        self.0.set(key.as_ref(), value.as_ref())
+    }
+
+    /// Set `key` to `value`, unless `key` is already present
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::set_if_absent`].
+    pub fn set_if_absent<K: AsRef<str> + ?Sized, V: AsRef<str> + ?Sized>(
+        &mut self,
+        key: &K,
+        value: &V,
+    ) -> Action {
+        self.0.set_if_absent(key.as_ref(), value.as_ref())
+    }
+
+    /// Append the switch `key`, i.e. a parameter without a value
+    ///
+    /// Like [`Self::add`], nothing is changed if the switch is already
+    /// present.  Parameters with the same key but with a value are
+    /// left alone.
+    pub fn push_switch<K: AsRef<str> + ?Sized>(&mut self, key: &K) -> Action {
+        self.0.push_switch(key.as_ref())
+    }
+
+    /// Insert a parameter at the given position in the command line
+    ///
+    /// `index` counts parameters, not bytes; an index past the end
+    /// appends the parameter.  Unlike `add`, this always inserts,
+    /// even if the exact parameter already exists.
+    pub fn insert_at(&mut self, index: usize, param: &Parameter) {
+        self.0.insert_at(index, &param.0)
+    }
+
+    /// Insert a parameter at the start of the command line
+    pub fn push_front(&mut self, param: &Parameter) {
+        self.0.push_front(&param.0)
+    }
+
     /// Remove parameter(s) with the given key from the command line
     ///
     /// Returns `true` if parameter(s) were removed.
@@ -203,6 +608,49 @@ impl<'a> Cmdline<'a> {
         self.0.remove_exact(&param.0)
     }
 
+    /// Apply a key allowlist or denylist to the command line
+    ///
+    /// Returns a new command line containing only the parameters
+    /// permitted by `policy`, in their original order, along with the
+    /// keys of every parameter that was rejected so callers can log
+    /// them.
+    pub fn filter_keys(&'a self, policy: KeyPolicy) -> (CmdlineOwned, Vec<ParameterKey<'a>>) {
+        let (filtered, rejected) = self.0.filter_keys(policy);
+        let rejected = rejected.into_iter().map(ParameterKey::from_bytes).collect();
+
+        (Cmdline(filtered), rejected)
+    }
+
+    /// Return the parameters of this command line for which `keep`
+    /// returns true
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::filtered`].
+    pub fn filtered(&'a self, mut keep: impl FnMut(&Parameter<'a>) -> bool) -> CmdlineOwned {
+        Cmdline(self.0.filtered(|p| keep(&Parameter::from_bytes(p.clone()))))
+    }
+
+    /// Return the parameters of this command line that are not in `baseline`
+    ///
+    /// A parameter is dropped only if `baseline` contains it with the
+    /// same key and value.  The remaining parameters keep their order.
+    pub fn minus(&self, baseline: &Cmdline) -> CmdlineOwned {
+        Cmdline(self.0.minus(&baseline.0))
+    }
+
+    /// Combine this command line with `overrides`, which take precedence
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::overlay`].
+    pub fn overlay(&self, overrides: &Cmdline) -> CmdlineOwned {
+        Cmdline(self.0.overlay(&overrides.0))
+    }
+
+    /// Compute the differences between this command line and `other`
+    ///
+    /// Otherwise the same as [`bytes::Cmdline::diff`].
+    pub fn diff(&'a self, other: &'a Cmdline<'a>) -> CmdlineDiff<Parameter<'a>> {
+        self.0.diff(&other.0).map(Parameter::from_bytes)
+    }
+
     #[cfg(test)]
     pub(crate) fn is_owned(&self) -> bool {
         self.0.is_owned()
@@ -235,8 +683,32 @@ where
 }
 
 impl<'a> std::fmt::Display for Cmdline<'a> {
+    /// Formats the command line in the same normalized form as
+    /// [`bytes::Cmdline`]
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        f.write_str(self)
+        let normalized = self.0.normalized();
+
+        // Normalizing only adds ASCII spaces and quotes to valid UTF-8
+        f.write_str(str::from_utf8(&normalized).expect("Normalized UTF-8 is still valid UTF-8"))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for Cmdline<'_> {
+    /// Serializes the command line in the same form as
+    /// [`bytes::Cmdline`].
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Cmdline<'_> {
+    /// Deserializes a command line, which must be valid UTF-8.
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let cmdline = bytes::Cmdline::deserialize(deserializer)?;
+        str::from_utf8(&cmdline).map_err(serde::de::Error::custom)?;
+        Ok(Self(cmdline))
     }
 }
 
@@ -264,6 +736,32 @@ impl<'a, 'other> Extend<Parameter<'other>> for Cmdline<'a> {
     }
 }
 
+/// An encrypted device configured via the `rd.luks.*` kernel arguments.
+///
+/// This is created by the `luks_config` method on `Cmdline`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LuksDevice {
+    /// The UUID of the LUKS device, without any `luks-` prefix
+    pub uuid: String,
+    /// The name of the mapped device, if set via `rd.luks.name=`
+    pub name: Option<String>,
+    /// The crypttab-style options for the device, if set via `rd.luks.options=`
+    pub options: Option<String>,
+}
+
+/// The kernel image the bootloader booted, from `BOOT_IMAGE=`.
+///
+/// This is created by the `boot_image` method on `Cmdline`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootImage<'a> {
+    /// The value as given on the command line
+    pub raw: &'a str,
+    /// The GRUB device the image was loaded from, e.g. `hd0,gpt2`
+    pub device: Option<&'a str>,
+    /// The path of the image, without any device
+    pub path: &'a str,
+}
+
 /// A single kernel command line parameter key
 ///
 /// Handles quoted values and treats dashes and underscores in keys as equivalent.
@@ -300,6 +798,15 @@ impl<'a> ParameterKey<'a> {
     }
 }
 
+impl ParameterKey<'_> {
+    /// Compares two parameter keys for equality, ignoring ASCII case.
+    ///
+    /// Otherwise the same as [`bytes::ParameterKey::eq_ignore_case`].
+    pub fn eq_ignore_case(&self, other: &ParameterKey<'_>) -> bool {
+        self.0.eq_ignore_case(&other.0)
+    }
+}
+
 impl<'a, T: AsRef<str> + ?Sized> From<&'a T> for ParameterKey<'a> {
     fn from(input: &'a T) -> Self {
         Self(bytes::ParameterKey(input.as_ref().as_bytes()))
@@ -358,6 +865,60 @@ impl<'a> Parameter<'a> {
             str::from_utf8(p).expect("We only construct the underlying bytes from valid UTF-8")
         })
     }
+
+    /// Returns `true` if this parameter is a switch without a value
+    pub fn is_switch(&self) -> bool {
+        self.0.is_switch()
+    }
+
+    /// Returns anything suspicious about how this parameter was
+    /// written, see [`ParseWarning`].
+    pub fn warnings(&self) -> Vec<ParseWarning> {
+        self.0.warnings()
+    }
+
+    /// Returns `true` if the value of this parameter equals `value`.
+    ///
+    /// Otherwise the same as [`bytes::Parameter::value_equals`].
+    pub fn value_equals<T: AsRef<str> + ?Sized>(&self, value: &T) -> bool {
+        self.0.value_equals(value.as_ref())
+    }
+
+    /// Parse the value of the parameter as a nested parameter
+    ///
+    /// Otherwise the same as [`bytes::Parameter::value_as_kv`].
+    pub fn value_as_kv(&self) -> Option<Parameter<'a>> {
+        self.0.value_as_kv().map(Self::from_bytes)
+    }
+
+    /// Split the value of the parameter into comma-separated segments
+    ///
+    /// Otherwise the same as [`bytes::Parameter::values`], including
+    /// preserving empty segments.
+    pub fn values(&'a self) -> impl Iterator<Item = &'a str> {
+        self.0.values().map(|v| {
+            // SAFETY: Splitting valid UTF-8 on an ASCII byte yields valid UTF-8
+            str::from_utf8(v).expect("Splitting valid UTF-8 on an ASCII byte yields valid UTF-8")
+        })
+    }
+
+    /// Split the value of the parameter on any of the given separators
+    ///
+    /// Separators inside double quotes are ignored, and quotes
+    /// surrounding an item are stripped.  Empty items are skipped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `seps` contains a non-ASCII byte, as splitting on it
+    /// would cut a character in half.
+    pub fn values_multi(&'a self, seps: &[u8]) -> impl Iterator<Item = &'a str> {
+        assert!(seps.is_ascii(), "Separators must be ASCII");
+
+        self.0.values_multi(seps).map(|v| {
+            // SAFETY: Splitting valid UTF-8 on ASCII bytes yields valid UTF-8
+            str::from_utf8(v).expect("Splitting valid UTF-8 on ASCII bytes yields valid UTF-8")
+        })
+    }
 }
 
 impl<'a> TryFrom<bytes::Parameter<'a>> for Parameter<'a> {
@@ -410,6 +971,67 @@ impl<'a> PartialEq for Parameter<'a> {
     }
 }
 
+/// An owned kernel command line parameter.
+///
+/// Otherwise the same as [`bytes::ParameterOwned`].
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ParameterOwned(bytes::ParameterOwned);
+
+impl ParameterOwned {
+    /// Borrow this as a [`Parameter`]
+    pub fn as_parameter(&self) -> Parameter<'_> {
+        Parameter::from_bytes(self.0.as_parameter())
+    }
+
+    /// Returns the key part of the parameter
+    pub fn key(&self) -> ParameterKey<'_> {
+        ParameterKey::from_bytes(self.0.key())
+    }
+
+    /// Returns the optional value part of the parameter
+    pub fn value(&self) -> Option<&str> {
+        self.0.value().map(|p| {
+            // SAFETY: We know this is valid UTF-8 since we only
+            // construct the underlying `bytes` from valid UTF-8
+            str::from_utf8(p).expect("We only construct the underlying bytes from valid UTF-8")
+        })
+    }
+}
+
+impl From<Parameter<'_>> for ParameterOwned {
+    fn from(param: Parameter<'_>) -> Self {
+        Self(param.0.into())
+    }
+}
+
+impl From<&Parameter<'_>> for ParameterOwned {
+    fn from(param: &Parameter<'_>) -> Self {
+        Self((&param.0).into())
+    }
+}
+
+impl PartialEq<Parameter<'_>> for ParameterOwned {
+    fn eq(&self, other: &Parameter<'_>) -> bool {
+        self.0 == other.0
+    }
+}
+
+impl std::fmt::Display for ParameterOwned {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        f.write_str(self)
+    }
+}
+
+impl Deref for ParameterOwned {
+    type Target = str;
+
+    fn deref(&self) -> &Self::Target {
+        // SAFETY: We know this is valid UTF-8 since we only
+        // construct the underlying `bytes` from valid UTF-8
+        str::from_utf8(&self.0).expect("We only construct the underlying bytes from valid UTF-8")
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -609,6 +1231,51 @@ mod tests {
         let _static_kargs: CmdlineOwned = Cmdline::new();
     }
 
+    #[test]
+    fn test_display() {
+        let kargs = Cmdline::from("foo=bar   baz");
+        assert_eq!(kargs.to_string(), "foo=bar baz");
+
+        let kargs = Cmdline::from("  foo=bar   baz\tquux=\"a b\"\n");
+        assert_eq!(kargs.to_string(), "foo=bar baz quux=\"a b\"");
+
+        // Non-ASCII UTF-8 is kept as is
+        let kargs = Cmdline::from("\"name=caf\u{e9} cr\u{e8}me\" x=\u{fc}");
+        let displayed = kargs.to_string();
+        assert_eq!(displayed, "name=\"caf\u{e9} cr\u{e8}me\" x=\u{fc}");
+        assert_eq!(Cmdline::from(displayed.as_str()), kargs);
+
+        assert_eq!(Cmdline::new().to_string(), "");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let kargs = Cmdline::from("root=/dev/sda1 quiet");
+        let json = serde_json::to_string(&kargs).unwrap();
+        assert_eq!(
+            json,
+            r#"[{"key":"root","value":"/dev/sda1"},{"key":"quiet","value":null}]"#
+        );
+        assert_eq!(serde_json::from_str::<CmdlineOwned>(&json).unwrap(), kargs);
+
+        // Non-UTF8 data is rejected
+        let err = serde_json::from_str::<CmdlineOwned>(r#"[{"key":"a","value":[255]}]"#);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn test_into_owned() {
+        let owned: CmdlineOwned = {
+            let buf = String::from("root=/dev/sda1 quiet");
+            Cmdline::from(buf.as_str()).into_owned()
+        };
+
+        assert!(owned.is_owned());
+        assert_eq!(owned.value_of("root"), Some("/dev/sda1"));
+        assert_eq!(owned.iter().count(), 2);
+    }
+
     #[test]
     fn test_kargs_simple_from_string() {
         let kargs = Cmdline::from("foo=bar,bar2 baz=fuz wiz".to_string());
@@ -635,6 +1302,172 @@ mod tests {
         assert!(kargs.iter().count() > 0);
     }
 
+    #[test]
+    fn test_initrd_params() {
+        let kargs = Cmdline::from("rd.break rd.luks.uuid=abc foo=bar");
+
+        let params: Vec<_> = kargs
+            .initrd_params()
+            .map(|(key, value)| (key.to_string(), value))
+            .collect();
+        assert_eq!(
+            params,
+            [
+                ("break".to_owned(), None),
+                ("luks.uuid".to_owned(), Some("abc")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_ci() {
+        let kargs = Cmdline::from("root=/dev/sda1 Init-On-Free=1");
+
+        assert_eq!(kargs.value_of_ci("ROOT"), Some("/dev/sda1"));
+        assert_eq!(kargs.value_of("ROOT"), None);
+
+        let p = kargs.find_ci("INIT_ON_FREE").unwrap();
+        assert_eq!(&*p.key(), "Init-On-Free");
+        assert!(p.key().eq_ignore_case(&ParameterKey::from("init_on_free")));
+        assert!(kargs.find("INIT_ON_FREE").is_none());
+    }
+
+    #[test]
+    fn test_from_reader() {
+        let kargs = Cmdline::from_reader("root=/dev/sda1 quiet\n".as_bytes()).unwrap();
+
+        assert_eq!(kargs.value_of("root"), Some("/dev/sda1"));
+        assert!(kargs.find("quiet").is_some());
+        assert_eq!(kargs.iter().count(), 2);
+
+        assert!(Cmdline::from_reader(b"foo=bar\xff".as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_from_nul_separated() {
+        let kargs = Cmdline::from_nul_separated("arg1\0arg2=val\0--opt=a b\0");
+        assert_eq!(&*kargs, "arg1 arg2=val --opt=\"a b\"");
+        assert_eq!(kargs.value_of("arg2"), Some("val"));
+        assert_eq!(kargs.value_of("--opt"), Some("a b"));
+    }
+
+    /// A real-world /proc/cmdline sample, along with the arguments we expect to find in it
+    struct CmdlineFixture {
+        name: &'static str,
+        cmdline: &'static str,
+        root: &'static str,
+        rootflags: Option<&'static str>,
+        initrd_args: &'static [&'static str],
+        param_count: usize,
+    }
+
+    const CMDLINE_FIXTURES: &[CmdlineFixture] = &[
+        CmdlineFixture {
+            name: "fedora-coreos",
+            cmdline: include_str!("../tests/fixtures/cmdline/fedora-coreos.txt"),
+            root: "UUID=7e3a4b1c-2d5f-4e6a-8b9c-0d1e2f3a4b5c",
+            rootflags: Some("prjquota"),
+            initrd_args: &[],
+            param_count: 10,
+        },
+        CmdlineFixture {
+            name: "rhel9",
+            cmdline: include_str!("../tests/fixtures/cmdline/rhel9.txt"),
+            root: "/dev/mapper/rhel-root",
+            rootflags: None,
+            initrd_args: &["rd.lvm.lv=rhel/root", "rd.lvm.lv=rhel/swap"],
+            param_count: 9,
+        },
+        CmdlineFixture {
+            name: "debian12",
+            cmdline: include_str!("../tests/fixtures/cmdline/debian12.txt"),
+            root: "UUID=0a1b2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d",
+            rootflags: None,
+            initrd_args: &[],
+            param_count: 4,
+        },
+        CmdlineFixture {
+            name: "arm64-devicetree",
+            cmdline: include_str!("../tests/fixtures/cmdline/arm64-devicetree.txt"),
+            root: "PARTUUID=6c586e13-02",
+            rootflags: None,
+            initrd_args: &[],
+            param_count: 19,
+        },
+        CmdlineFixture {
+            name: "luks",
+            cmdline: include_str!("../tests/fixtures/cmdline/luks.txt"),
+            root: "UUID=5d4c3b2a-1908-4f7e-8d6c-5b4a39281706",
+            rootflags: Some("subvol=root"),
+            initrd_args: &[
+                "rd.luks.uuid=luks-1b8f2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d",
+                "rd.luks.options=discard",
+            ],
+            param_count: 8,
+        },
+    ];
+
+    #[test]
+    fn test_proc_cmdline_fixtures() {
+        use crate::{INITRD_ARG_PREFIX, ROOTFLAGS};
+
+        for fixture in CMDLINE_FIXTURES {
+            let name = fixture.name;
+            let kargs = Cmdline::from(fixture.cmdline);
+
+            assert_eq!(kargs.iter().count(), fixture.param_count, "{name}");
+            assert!(
+                kargs.iter().all(|p| !p.contains(char::is_whitespace)),
+                "{name}"
+            );
+
+            assert_eq!(kargs.value_of("root"), Some(fixture.root), "{name}");
+            assert_eq!(kargs.value_of(ROOTFLAGS), fixture.rootflags, "{name}");
+
+            let initrd_args: Vec<_> = kargs.find_all_starting_with(INITRD_ARG_PREFIX).collect();
+            let expected: Vec<_> = fixture.initrd_args.iter().map(|a| param(a)).collect();
+            assert_eq!(initrd_args, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_proc_cmdline_fixture_details() {
+        let [fcos, _, _, arm64, luks] = CMDLINE_FIXTURES else {
+            unreachable!()
+        };
+
+        let kargs = Cmdline::from(fcos.cmdline);
+        assert!(
+            kargs
+                .value_of("BOOT_IMAGE")
+                .unwrap()
+                .starts_with("(hd0,gpt3)/ostree/")
+        );
+        assert_eq!(kargs.value_of("mitigations"), Some("auto,nosmt"));
+        assert!(kargs.find("rw").unwrap().value().is_none());
+
+        let kargs = Cmdline::from(arm64.cmdline);
+        assert_eq!(
+            kargs.value_of("smsc95xx.macaddr"),
+            Some("DC:A6:32:00:00:01")
+        );
+        let consoles: Vec<_> = kargs.iter_matching(&["console"]).collect();
+        assert_eq!(
+            consoles,
+            [param("console=ttyS0,115200"), param("console=tty1")]
+        );
+
+        let kargs = Cmdline::from(luks.cmdline);
+        assert_eq!(
+            kargs.luks_config(),
+            [LuksDevice {
+                uuid: "1b8f2c3d-4e5f-4a6b-8c7d-9e0f1a2b3c4d".into(),
+                name: None,
+                options: Some("discard".into()),
+            }]
+        );
+    }
+
     #[test]
     fn test_kargs_find_dash_hyphen() {
         let kargs = Cmdline::from("a-b=1 a_b=2");
@@ -687,6 +1520,61 @@ mod tests {
         assert_eq!(kargs.value_of("under-key"), Some("value2"));
     }
 
+    #[test]
+    fn test_find_last() {
+        let kargs = Cmdline::from("root=/dev/a quiet root=/dev/b");
+        assert_eq!(kargs.value_of("root"), Some("/dev/a"));
+        assert_eq!(kargs.value_of_last("root"), Some("/dev/b"));
+        assert_eq!(kargs.find_last("root").unwrap().value(), Some("/dev/b"));
+        assert!(kargs.find_last("missing").is_none());
+    }
+
+    #[test]
+    fn test_module_params() {
+        let kargs = Cmdline::from("i915.enable_psr=0 i915.fastboot=1 nvidia.foo=bar");
+        let params: Vec<_> = kargs.module_params("i915").collect();
+        assert_eq!(
+            params,
+            [
+                (ParameterKey::from("enable_psr"), Some("0")),
+                (ParameterKey::from("fastboot"), Some("1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_find_all() {
+        let kargs = Cmdline::from("console=ttyS0 console=tty0");
+        let consoles: Vec<_> = kargs.find_all("console").collect();
+        assert_eq!(consoles.len(), 2);
+        assert_eq!(consoles[0].value(), Some("ttyS0"));
+        assert_eq!(consoles[1].value(), Some("tty0"));
+    }
+
+    #[test]
+    fn test_value_equals() {
+        let p = param("foo=\"bar baz\"");
+        assert!(p.value_equals("bar baz"));
+        assert!(p.value_equals("\"bar baz\""));
+        assert!(!p.value_equals("bar\u{a0}baz"));
+        assert!(!param("foo").value_equals(""));
+    }
+
+    #[test]
+    fn test_contains_is_switch() {
+        let kargs = Cmdline::from("quiet root=/dev/sda1");
+        assert!(kargs.contains_param("quiet"));
+        assert!(kargs.contains_param("root"));
+        assert!(!kargs.contains_param("missing"));
+        // Only whole keys match, unlike a substring search
+        assert!(!kargs.contains_param("sda1"));
+        // ...which is still available through Deref
+        assert!(kargs.contains("sda1"));
+
+        assert!(param("quiet").is_switch());
+        assert!(!param("root=/dev/sda1").is_switch());
+    }
+
     #[test]
     fn test_require_value_of() {
         let kargs = Cmdline::from("foo=bar baz=qux switch");
@@ -737,6 +1625,25 @@ mod tests {
         assert_eq!(rd_args[3], param("rd.qux=c"));
     }
 
+    #[test]
+    fn test_iter_matching() {
+        let kargs = Cmdline::from(
+            "console=tty0 quiet root=/dev/sda1 console=ttyS0 systemd.log_level=debug ro rd.lvm.lv=vg/root",
+        );
+        let matched: Vec<_> = kargs
+            .iter_matching(&["console", "rd.lvm.lv", "systemd.log-level", "missing"])
+            .collect();
+        assert_eq!(
+            matched,
+            [
+                param("console=tty0"),
+                param("console=ttyS0"),
+                param("systemd.log_level=debug"),
+                param("rd.lvm.lv=vg/root")
+            ]
+        );
+    }
+
     #[test]
     fn test_param_key_eq() {
         let k1 = ParameterKey::from("a-b");
@@ -747,6 +1654,86 @@ mod tests {
         assert_ne!(k1, k2);
     }
 
+    #[test]
+    fn test_effective_group_value() {
+        const CONSOLE: ArgGroup = ArgGroup::new(&["console", "earlycon"]);
+
+        let kargs = Cmdline::from("console=tty0 earlycon quiet console=ttyS0,115200");
+        assert_eq!(
+            kargs.effective_group_value(&CONSOLE),
+            Some(param("console=ttyS0,115200"))
+        );
+
+        let kargs = Cmdline::from("quiet");
+        assert_eq!(kargs.effective_group_value(&CONSOLE), None);
+    }
+
+    #[test]
+    fn test_parameter_owned() {
+        let owned: Vec<ParameterOwned> = ["foo-bar=a", "baz"]
+            .into_iter()
+            .flat_map(|s| {
+                Cmdline::from(s)
+                    .iter()
+                    .map(ParameterOwned::from)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        assert_eq!(owned[0].key(), "foo_bar".into());
+        assert_eq!(owned[0].value(), Some("a"));
+        assert_eq!(owned[0].to_string(), "foo-bar=a");
+        assert_eq!(owned[0], param("foo_bar=a"));
+        assert_eq!(owned[1], ParameterOwned::from(param("baz")));
+        assert_ne!(owned[1], param("baz=a"));
+    }
+
+    #[test]
+    fn test_value_as_kv() {
+        let p = param("systemd.setenv=FOO=bar");
+        let nested = p.value_as_kv().unwrap();
+        assert_eq!(nested.key(), "FOO".into());
+        assert_eq!(nested.value(), Some("bar"));
+
+        assert!(param("systemd.setenv").value_as_kv().is_none());
+    }
+
+    #[test]
+    fn test_values() {
+        let kargs = Cmdline::from("foo=a,b,c bar=a,,b baz");
+        let foo = kargs.find("foo").unwrap();
+        let values: Vec<_> = foo.values().collect();
+        assert_eq!(values, ["a", "b", "c"]);
+
+        let bar = kargs.find("bar").unwrap();
+        let values: Vec<_> = bar.values().collect();
+        assert_eq!(values, ["a", "", "b"]);
+
+        assert_eq!(kargs.find("baz").unwrap().values().next(), None);
+
+        let p = param("foo=ä,ö");
+        let values: Vec<_> = p.values().collect();
+        assert_eq!(values, ["ä", "ö"]);
+    }
+
+    #[test]
+    fn test_values_multi() {
+        let p = param("rd.luks.options=discard,tpm2-device=auto:headless");
+        let values: Vec<_> = p.values_multi(b",:").collect();
+        assert_eq!(values, ["discard", "tpm2-device=auto", "headless"]);
+
+        let p = param("foo=ä,ö");
+        let values: Vec<_> = p.values_multi(b",").collect();
+        assert_eq!(values, ["ä", "ö"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Separators must be ASCII")]
+    fn test_values_multi_non_ascii_separator() {
+        let p = param("foo=ä,ö");
+        let _ = p.values_multi(",\u{e4}".as_bytes());
+    }
+
     #[test]
     fn test_add() {
         let mut kargs = Cmdline::from("console=tty0 console=ttyS1");
@@ -787,6 +1774,57 @@ mod tests {
         assert_eq!(&*kargs, "foo");
     }
 
+    #[test]
+    fn test_set_push_switch() {
+        let mut kargs = Cmdline::from("root=/dev/sda1 quiet");
+
+        assert_eq!(kargs.set("root", "/dev/sda2"), Action::Modified);
+        assert_eq!(kargs.set("foo", "a b"), Action::Added);
+        assert_eq!(kargs.push_switch("rd.break"), Action::Added);
+
+        assert_eq!(&*kargs, "root=/dev/sda2 quiet foo=\"a b\" rd.break");
+        assert_eq!(kargs.value_of("foo"), Some("a b"));
+    }
+
+    #[test]
+    fn test_set_if_absent() {
+        let mut kargs = Cmdline::from("console=ttyS0 quiet");
+
+        assert_eq!(kargs.set_if_absent("console", "tty0"), Action::Existed);
+        assert_eq!(kargs.set_if_absent("foo", "a b"), Action::Added);
+
+        assert_eq!(&*kargs, "console=ttyS0 quiet foo=\"a b\"");
+        assert_eq!(kargs.value_of("foo"), Some("a b"));
+    }
+
+    #[test]
+    fn test_try_push_kv() {
+        let mut kargs = Cmdline::from("root=/dev/sda");
+
+        kargs.try_push_kv("console", "ttyS0", 27).unwrap();
+        assert!(kargs.try_push_kv("quiet", "1", 27).is_err());
+
+        let mut iter = kargs.iter();
+        assert_eq!(iter.next(), Some(param("root=/dev/sda")));
+        assert_eq!(iter.next(), Some(param("console=ttyS0")));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_insert_at() {
+        let mut kargs = Cmdline::from("root=/dev/sda1 quiet");
+
+        kargs.push_front(&param("BOOT_IMAGE=/vmlinuz"));
+        kargs.insert_at(2, &param("rw"));
+
+        let mut iter = kargs.iter();
+        assert_eq!(iter.next(), Some(param("BOOT_IMAGE=/vmlinuz")));
+        assert_eq!(iter.next(), Some(param("root=/dev/sda1")));
+        assert_eq!(iter.next(), Some(param("rw")));
+        assert_eq!(iter.next(), Some(param("quiet")));
+        assert_eq!(iter.next(), None);
+    }
+
     #[test]
     fn test_add_or_modify() {
         let mut kargs = Cmdline::from("foo=bar");
@@ -931,6 +1969,227 @@ mod tests {
         assert_eq!(params[2], param("wiz"));
     }
 
+    #[test]
+    fn test_diff() {
+        let old = Cmdline::from("root=/dev/sda1 quiet rhgb");
+        let new = Cmdline::from("root=/dev/sda2 quiet=1 nosmt");
+        let diff = old.diff(&new);
+
+        assert_eq!(diff.added, [param("nosmt")]);
+        assert_eq!(diff.removed, [param("rhgb")]);
+        assert_eq!(
+            diff.changed,
+            [
+                (param("root=/dev/sda1"), param("root=/dev/sda2")),
+                (param("quiet"), param("quiet=1")),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_minus() {
+        let baseline = Cmdline::from("ro quiet console=tty0");
+        let kargs = Cmdline::from("root=/dev/sda1 ro console=ttyS0 quiet");
+
+        let extra = kargs.minus(&baseline);
+        let mut iter = extra.iter();
+        assert_eq!(iter.next(), Some(param("root=/dev/sda1")));
+        assert_eq!(iter.next(), Some(param("console=ttyS0")));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        Cmdline::from("root=/dev/sda1 quiet\n").validate().unwrap();
+
+        let err = Cmdline::from("root=/dev/sda1\nquiet\0")
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Kernel command line contains control characters: newline at offset 14, NUL at offset 20"
+        );
+    }
+
+    #[test]
+    fn test_iter_checked() {
+        let kargs = Cmdline::from("root=/dev/sda1 =oops");
+        let checked: Vec<_> = kargs.iter_checked().collect();
+
+        assert_eq!(checked[0], (param("root=/dev/sda1"), vec![]));
+        assert_eq!(checked[1], (param("=oops"), vec![ParseWarning::EmptyKey]));
+    }
+
+    #[test]
+    fn test_stable_hash() {
+        let kargs = Cmdline::from("foo=a bar-baz=b");
+        assert_eq!(
+            kargs.stable_hash(),
+            Cmdline::from("bar_baz=b foo=a").stable_hash()
+        );
+        assert_eq!(
+            kargs.stable_hash(),
+            bytes::Cmdline::from("foo=a bar-baz=b").stable_hash()
+        );
+        assert_ne!(
+            kargs.stable_hash(),
+            Cmdline::from("foo=b bar-baz=b").stable_hash()
+        );
+    }
+
+    #[test]
+    fn test_split_first() {
+        let kargs = Cmdline::from("root=/dev/sda1 \"console=ttyS0 quiet\" rd.break");
+
+        let (first, rest) = kargs.split_first().unwrap();
+        assert_eq!(first, param("root=/dev/sda1"));
+
+        let rest: Vec<_> = rest.iter().collect();
+        assert_eq!(rest, kargs.iter().skip(1).collect::<Vec<_>>());
+        assert_eq!(rest.len(), 2);
+
+        assert!(Cmdline::new().split_first().is_none());
+    }
+
+    #[test]
+    fn test_param_count() {
+        let kargs = Cmdline::from("root=/dev/sda1 \"console=ttyS0 quiet\" rd.break");
+        assert_eq!(kargs.param_count(), 3);
+        assert_eq!(kargs.byte_len(), 45);
+
+        assert_eq!(Cmdline::new().param_count(), 0);
+        assert_eq!(Cmdline::from(" \t\n").param_count(), 0);
+    }
+
+    #[test]
+    fn test_overlay() {
+        let base = Cmdline::from("root=/dev/sda1 console=tty0 quiet");
+        let merged = base.overlay(&Cmdline::from("console=ttyS0 !quiet rd.break"));
+
+        let mut iter = merged.iter();
+        assert_eq!(iter.next(), Some(param("root=/dev/sda1")));
+        assert_eq!(iter.next(), Some(param("console=ttyS0")));
+        assert_eq!(iter.next(), Some(param("rd.break")));
+        assert_eq!(iter.next(), None);
+    }
+
+    #[test]
+    fn test_filtered() {
+        let kargs = Cmdline::from("root=/dev/sda1 rd.luks.key=/secret \"rootflags=a b\" quiet");
+
+        let filtered = kargs.filtered(|p| p.key() != "rd.luks.key".into());
+        assert_eq!(&*filtered, "root=/dev/sda1 \"rootflags=a b\" quiet");
+        assert_eq!(filtered.find("rootflags").unwrap().value(), Some("a b"));
+    }
+
+    #[test]
+    fn test_filter_keys() {
+        let kargs = Cmdline::from("root=/dev/sda quiet rd.break console=tty0");
+
+        let (filtered, rejected) = kargs.filter_keys(KeyPolicy::Allow(&["root", "console"]));
+        assert_eq!(&*filtered, "root=/dev/sda console=tty0");
+        assert_eq!(rejected, vec!["quiet".into(), "rd.break".into()]);
+
+        let (filtered, rejected) = kargs.filter_keys(KeyPolicy::Deny(&["rd.break"]));
+        assert_eq!(&*filtered, "root=/dev/sda quiet console=tty0");
+        assert_eq!(rejected, vec![ParameterKey::from("rd.break")]);
+    }
+
+    #[test]
+    fn test_boot_image() {
+        let kargs = Cmdline::from("BOOT_IMAGE=/vmlinuz-6.8.0 root=UUID=abc ro");
+        assert_eq!(
+            kargs.boot_image(),
+            Some(BootImage {
+                raw: "/vmlinuz-6.8.0",
+                device: None,
+                path: "/vmlinuz-6.8.0",
+            })
+        );
+
+        let kargs = Cmdline::from(
+            "BOOT_IMAGE=(hd0,gpt2)/ostree/default-abc/vmlinuz BOOT_IMAGE=/other root=UUID=abc",
+        );
+        assert_eq!(
+            kargs.boot_image(),
+            Some(BootImage {
+                raw: "(hd0,gpt2)/ostree/default-abc/vmlinuz",
+                device: Some("hd0,gpt2"),
+                path: "/ostree/default-abc/vmlinuz",
+            })
+        );
+
+        // Not a device prefix without the closing parenthesis
+        let kargs = Cmdline::from("BOOT_IMAGE=(hd0/vmlinuz");
+        let image = kargs.boot_image().unwrap();
+        assert_eq!(image.device, None);
+        assert_eq!(image.path, "(hd0/vmlinuz");
+
+        assert_eq!(Cmdline::from("root=UUID=abc").boot_image(), None);
+    }
+
+    #[test]
+    fn test_rootflags() {
+        let kargs = Cmdline::from("root=UUID=abc rootflags=subvol=root,compress=zstd:1,ro rw");
+        assert_eq!(
+            kargs.rootflags().unwrap(),
+            [
+                ("subvol", Some("root")),
+                ("compress", Some("zstd:1")),
+                ("ro", None)
+            ]
+        );
+
+        let kargs =
+            Cmdline::from("rootflags=ro,, rootflags=\"context=system_u:object_r:root_t:s0\"");
+        assert_eq!(
+            kargs.rootflags().unwrap(),
+            [("context", Some("system_u:object_r:root_t:s0"))]
+        );
+
+        assert_eq!(Cmdline::from("rootflags=").rootflags().unwrap(), []);
+        assert_eq!(Cmdline::from("root=/dev/sda rw").rootflags(), None);
+    }
+
+    #[test]
+    fn test_luks_config() {
+        let kargs = Cmdline::from(
+            "root=/dev/mapper/root rd.luks.uuid=luks-1111-aaaa rd.luks.uuid=2222-bbbb \
+             rd.luks.name=1111-aaaa=root rd.luks.options=2222-bbbb=discard,keyfile-timeout=10s \
+             rd.luks.options=tpm2-device=auto",
+        );
+
+        let devices = kargs.luks_config();
+        assert_eq!(
+            devices,
+            vec![
+                LuksDevice {
+                    uuid: "1111-aaaa".into(),
+                    name: Some("root".into()),
+                    options: Some("tpm2-device=auto".into()),
+                },
+                LuksDevice {
+                    uuid: "2222-bbbb".into(),
+                    name: None,
+                    options: Some("discard,keyfile-timeout=10s".into()),
+                },
+            ]
+        );
+
+        assert!(
+            Cmdline::from("root=/dev/sda rd.luks=0")
+                .luks_config()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_is_debug_boot() {
+        assert!(Cmdline::from("root=/dev/sda rd.break").is_debug_boot());
+        assert!(!Cmdline::from("root=/dev/sda quiet").is_debug_boot());
+        assert!(Cmdline::from("root=/dev/sda quiet").is_debug_boot_with(&["quiet"]));
+    }
+
     #[test]
     fn test_cmdline_eq() {
         // Ordering, quoting, and the whole dash-underscore
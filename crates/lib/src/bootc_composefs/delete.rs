@@ -8,7 +8,7 @@ use composefs_boot::bootloader::{EFI_ADDON_DIR_EXT, EFI_EXT};
 use crate::{
     bootc_composefs::{
         boot::{BootType, SYSTEMD_UKI_DIR, find_vmlinuz_initrd_duplicates, get_efi_uuid_source},
-        gc::composefs_gc,
+        gc::{GcOptions, GcRoots, composefs_gc},
         repo::open_composefs_repo,
         rollback::{composefs_rollback, rename_exchange_user_cfg},
         status::{get_composefs_status, get_sorted_grub_uki_boot_entries},
@@ -244,8 +244,12 @@ fn delete_depl_boot_entries(
     }
 }
 
+/// Collects the objects referenced by every EROFS image, except for those in `skip`
 #[fn_error_context::context("Getting image objects")]
-pub(crate) fn get_image_objects(sysroot: &Dir) -> Result<HashSet<Sha512HashValue>> {
+pub(crate) fn get_image_objects(
+    sysroot: &Dir,
+    skip: &[String],
+) -> Result<HashSet<Sha512HashValue>> {
     let repo = open_composefs_repo(&sysroot)?;
 
     let images_dir = sysroot
@@ -263,6 +267,10 @@ pub(crate) fn get_image_objects(sysroot: &Dir) -> Result<HashSet<Sha512HashValue
 
         let img_name = image.file_name().context("Getting image name")?;
 
+        if skip.contains(&img_name) {
+            continue;
+        }
+
         let objects = repo
             .objects_for_image(&img_name)
             .with_context(|| format!("Getting objects for image {img_name}"))?;
@@ -302,9 +310,13 @@ pub(crate) fn delete_staged(staged: &Option<BootEntry>) -> Result<()> {
 
     let file = Path::new(COMPOSEFS_TRANSIENT_STATE_DIR).join(COMPOSEFS_STAGED_DEPLOYMENT_FNAME);
     tracing::debug!("Deleting staged deployment file: {file:?}");
-    std::fs::remove_file(file).context("Removing staged file")?;
 
-    Ok(())
+    // Garbage collection might have already removed it
+    match std::fs::remove_file(file) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e).context("Removing staged file"),
+    }
 }
 
 #[fn_error_context::context("Deleting composefs deployment {}", deployment_id)]
@@ -312,6 +324,7 @@ pub(crate) async fn delete_composefs_deployment(
     deployment_id: &str,
     storage: &Storage,
     booted_cfs: &BootedComposefs,
+    gc_opts: GcOptions,
 ) -> Result<()> {
     let host = get_composefs_status(storage, booted_cfs).await?;
 
@@ -355,7 +368,9 @@ pub(crate) async fn delete_composefs_deployment(
 
     delete_depl_boot_entries(&depl_to_del, &storage, deleting_staged)?;
 
-    composefs_gc(storage, booted_cfs).await?;
+    // A missing or unresolvable config of the booted image and refused deletions are already
+    // logged by the garbage collection
+    composefs_gc(storage, GcRoots::Booted(booted_cfs), gc_opts).await?;
 
     Ok(())
 }
@@ -4,23 +4,474 @@
 //! - We delete the bootloader entry but fail to delete image
 //! - We delete bootloader + image but fail to delete the state/unrefenced objects etc
 
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
 use anyhow::{Context, Result};
-use cap_std_ext::{cap_std::fs::Dir, dirext::CapStdExtDirExt};
+use cap_std_ext::{
+    cap_std::fs::{Dir, File, OpenOptions},
+    dirext::CapStdExtDirExt,
+};
 use composefs::fsverity::{FsVerityHashValue, Sha512HashValue};
+use tokio_util::sync::CancellationToken;
 
 use crate::{
     bootc_composefs::{
         delete::{delete_image, delete_staged, delete_state_dir, get_image_objects},
+        repo::open_composefs_repo,
         status::{
-            get_bootloader, get_composefs_status, get_sorted_grub_uki_boot_entries,
-            get_sorted_type1_boot_entries,
+            ImgConfigManifest, get_bootloader, get_composefs_status, get_imginfo,
+            get_sorted_grub_uki_boot_entries, get_sorted_type1_boot_entries,
         },
     },
-    composefs_consts::{STATE_DIR_RELATIVE, USER_CFG},
+    composefs_consts::{STATE_DIR_RELATIVE, TYPE1_ENT_PATH_STAGED, USER_CFG, USER_CFG_STAGED},
     spec::Bootloader,
-    store::{BootedComposefs, Storage},
+    store::{BootedComposefs, ComposefsRepository, Storage},
 };
 
+/// Where orphaned deployments are moved to when [`QuarantinePolicy::Enabled`]
+const QUARANTINE_DIR: &str = "composefs/quarantine";
+/// File inside each quarantined deployment's directory holding the time it was quarantined, in
+/// seconds since the epoch
+const QUARANTINED_AT: &str = "quarantined-at";
+/// File next to [`QUARANTINED_AT`] holding the grace period the deployment was quarantined with,
+/// in seconds
+const QUARANTINE_GRACE: &str = "grace";
+/// Lock file held by [`composefs_gc`] while it runs, relative to the physical root
+const GC_LOCK: &str = "composefs/gc.lock";
+
+/// What [`composefs_gc`] does if another garbage collection is already running
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum GcLockMode {
+    /// Fail right away
+    FailFast,
+    /// Wait for the other garbage collection to finish
+    Wait,
+}
+
+/// A lock on [`GC_LOCK`], released when dropped
+#[derive(Debug)]
+struct GcLock {
+    /// `None` for a dry run in a store where garbage collection never ran
+    _file: Option<File>,
+}
+
+/// Takes the lock preventing concurrent garbage collection runs from racing on deleting the same
+/// images and state directories
+///
+/// A dry run only takes a shared lock, so that dry runs don't block each other, and doesn't
+/// create the lock file so that it doesn't write to the store. It still waits for or fails on a
+/// real run, as it would report what that run is deleting.
+#[fn_error_context::context("Locking {GC_LOCK}")]
+fn lock_gc(sysroot: &Dir, mode: GcLockMode, dry_run: bool) -> Result<GcLock> {
+    use rustix::fs::{FlockOperation, flock};
+
+    let file = match dry_run {
+        true => sysroot.open_optional(GC_LOCK),
+        false => sysroot
+            .open_with(GC_LOCK, OpenOptions::new().create(true).write(true))
+            .map(Some),
+    }
+    .context("Opening lock file")?;
+
+    let Some(file) = file else {
+        return Ok(GcLock { _file: None });
+    };
+
+    let op = match (mode, dry_run) {
+        (GcLockMode::FailFast, false) => FlockOperation::NonBlockingLockExclusive,
+        (GcLockMode::Wait, false) => FlockOperation::LockExclusive,
+        (GcLockMode::FailFast, true) => FlockOperation::NonBlockingLockShared,
+        (GcLockMode::Wait, true) => FlockOperation::LockShared,
+    };
+
+    match flock(&file, op) {
+        Ok(()) => Ok(GcLock { _file: Some(file) }),
+        Err(rustix::io::Errno::WOULDBLOCK) => {
+            anyhow::bail!("Another garbage collection is already running")
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// What [`composefs_gc`] does with orphaned EROFS images and state directories
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) enum QuarantinePolicy {
+    /// Delete orphans right away. Anything left in quarantine by earlier runs is still only deleted
+    /// once its grace period is over
+    #[default]
+    Disabled,
+    /// Move orphans to `composefs/quarantine/<verity>` and only delete them once they've been
+    /// there for longer than `grace`. The grace period is recorded with each deployment, so
+    /// later runs with a different policy still honor it.
+    Enabled { grace: Duration },
+}
+
+impl QuarantinePolicy {
+    /// What happens to orphans, for logging
+    fn action(&self) -> &'static str {
+        match self {
+            QuarantinePolicy::Disabled => "delete",
+            QuarantinePolicy::Enabled { .. } => "quarantine",
+        }
+    }
+}
+
+/// When a [`composefs_gc`] run should stop early, e.g. to bound the work done on a constrained
+/// system or as an upgrade was started
+///
+/// Garbage collection only stops in between deleting whole deployments or object directories, so
+/// the store is always left consistent, and the next run picks up the remaining work.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct GcStop {
+    /// Stop once this point in time is reached
+    pub(crate) deadline: Option<Instant>,
+    /// Stop once this is cancelled
+    pub(crate) cancel: Option<CancellationToken>,
+}
+
+impl GcStop {
+    /// Whether garbage collection should stop now
+    fn requested(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+            || self.cancel.as_ref().is_some_and(|c| c.is_cancelled())
+    }
+}
+
+/// Options for [`composefs_gc`]
+#[derive(Debug, Clone)]
+pub(crate) struct GcOptions {
+    pub(crate) quarantine: QuarantinePolicy,
+    pub(crate) lock: GcLockMode,
+    /// Orphans whose image or state directory were modified more recently than this are left
+    /// alone, as they might belong to a deployment which is still being written
+    pub(crate) min_age: Duration,
+    /// Only report what would be cleaned up, without deleting or quarantining anything
+    pub(crate) dry_run: bool,
+    pub(crate) stop: GcStop,
+    /// Deployments pinned by the operator. These are protected like the booted deployment and
+    /// never considered orphans, even without a bootloader entry.
+    pub(crate) pinned: Vec<String>,
+}
+
+/// A summary of what a [`composefs_gc`] run found and did
+///
+/// In a dry run, the deletions and counts are what a real run would have done, so the same fields
+/// can be checked either way.
+#[derive(Debug, Default)]
+pub(crate) struct GcReport {
+    /// Whether this was a dry run, see [`GcOptions::dry_run`]
+    pub(crate) dry_run: bool,
+    /// Name of the booted image's config splitstream, if it was missing from the repository.
+    /// This should never happen and is a sign of store corruption.
+    pub(crate) missing_booted_config: Option<String>,
+    /// Why the config of the booted image couldn't be resolved, if it couldn't. The booted
+    /// deployment is still protected, but its config splitstream isn't checked.
+    pub(crate) unresolved_booted_config: Option<String>,
+    /// How long each phase of the garbage collection took
+    pub(crate) timings: GcTimings,
+    /// Everything garbage collection kept as still in use: the booted deployment, the staged one
+    /// if any, every deployment with a bootloader entry and the booted image's config splitstream
+    pub(crate) protected_roots: Vec<String>,
+    /// Deletions which were skipped as the deployment turned out to still be in use. This should
+    /// never happen and points to a bug in how we list deployments
+    pub(crate) refused_deletions: Vec<String>,
+    /// Deployments whose EROFS image was orphaned, and which were deleted or quarantined
+    pub(crate) orphaned_images: Vec<String>,
+    /// Deployments with a state directory but no EROFS image, whose state directory was deleted
+    /// or quarantined
+    pub(crate) orphaned_state_dirs: Vec<String>,
+    /// The staged deployment, if it was cleaned up as staging it was interrupted before its image
+    /// or bootloader entries were written
+    pub(crate) staged_orphan: Option<String>,
+    /// Orphans which were left alone as they are younger than [`GcOptions::min_age`]
+    pub(crate) too_recent: Vec<String>,
+    /// Number of deployments left in quarantine
+    pub(crate) quarantined: usize,
+    /// What the object sweep did, or `None` if it was skipped
+    pub(crate) objects: Option<ObjectSweep>,
+    /// Total size of the EROFS images and objects deleted. State directories and deployments
+    /// purged from quarantine aren't counted.
+    pub(crate) bytes_freed: u64,
+    /// Whether the run stopped early as requested by [`GcOptions::stop`], leaving the rest to the
+    /// next run
+    pub(crate) interrupted: bool,
+}
+
+/// What the object sweep of [`composefs_gc`] did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ObjectSweep {
+    /// Number of objects in the repository before the sweep
+    pub(crate) scanned: usize,
+    /// Number of unreferenced objects deleted
+    pub(crate) removed: usize,
+    /// Total size of the deleted objects
+    pub(crate) bytes_freed: u64,
+    /// Whether the sweep stopped before going through every object
+    pub(crate) interrupted: bool,
+}
+
+impl std::fmt::Display for GcReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.dry_run {
+            write!(f, "[dry run] ")?;
+        }
+
+        write!(
+            f,
+            "Cleaned up {} orphaned image(s) and {} orphaned state dir(s)",
+            self.orphaned_images.len(),
+            self.orphaned_state_dirs.len()
+        )?;
+
+        match self.objects {
+            Some(objects) => write!(
+                f,
+                ", removed {} of {} object(s)",
+                objects.removed, objects.scanned
+            )?,
+            None if self.interrupted => write!(f, ", skipped object sweep")?,
+            None => write!(
+                f,
+                ", skipped object sweep with {} deployment(s) in quarantine",
+                self.quarantined
+            )?,
+        }
+
+        write!(f, ", freed {}", indicatif::HumanBytes(self.bytes_freed))?;
+
+        if self.interrupted {
+            write!(f, ", stopped early")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What [`composefs_gc`] protects, besides every deployment with a bootloader entry
+#[derive(Debug, Clone)]
+pub(crate) enum GcRoots<'a> {
+    /// The store of the booted system, protecting the booted and staged deployments
+    Booted(&'a BootedComposefs),
+    /// A store which isn't booted, e.g. when doing maintenance from a rescue environment.
+    ///
+    /// Only the given deployments are protected, and the given splitstreams are checked for
+    /// like the config splitstream of the booted image is.
+    Offline {
+        /// Bootloader of the store. This decides where its bootloader entries are read from, and
+        /// can't be detected from the running system as the store might not be its own.
+        bootloader: Bootloader,
+        verities: &'a [String],
+        streams: &'a [String],
+    },
+}
+
+/// Deployments which garbage collection must never delete
+#[derive(Debug)]
+struct InUse<'a> {
+    /// The booted deployment, unless collecting an offline store
+    booted: Option<&'a str>,
+    /// The staged deployment, as long as its staged bootloader entries exist
+    staged: Option<&'a str>,
+    bootloader_entries: &'a [String],
+    /// Deployments explicitly protected by [`GcRoots::Offline`]
+    explicit: &'a [String],
+    /// Deployments pinned through [`GcOptions::pinned`]
+    pinned: &'a [String],
+}
+
+impl InUse<'_> {
+    /// Returns what `verity` is still in use as, if anything
+    fn reason(&self, verity: &str) -> Option<&'static str> {
+        if self.booted == Some(verity) {
+            Some("booted deployment")
+        } else if self.explicit.iter().any(|e| e == verity) {
+            Some("protected root")
+        } else if self.pinned.iter().any(|p| p == verity) {
+            Some("pinned deployment")
+        } else if self.staged == Some(verity) {
+            Some("staged deployment")
+        } else if self.bootloader_entries.iter().any(|e| e == verity) {
+            Some("bootloader entry")
+        } else {
+            None
+        }
+    }
+
+    /// Everything protected from garbage collection, along with the protected splitstreams
+    fn roots(&self, streams: &[String]) -> Vec<String> {
+        self.booted
+            .into_iter()
+            .chain(self.explicit.iter().map(String::as_str))
+            .chain(self.pinned.iter().map(String::as_str))
+            .chain(self.staged)
+            .chain(self.bootloader_entries.iter().map(String::as_str))
+            .chain(streams.iter().map(String::as_str))
+            .fold(Vec::new(), |mut roots, root| {
+                if !roots.iter().any(|r| r == root) {
+                    roots.push(root.to_owned());
+                }
+                roots
+            })
+    }
+
+    /// Final check before deleting anything belonging to `verity`
+    ///
+    /// Returns `false`, recording the refusal in `refused`, if `verity` is still in use
+    fn ensure_unused(&self, verity: &str, refused: &mut Vec<String>) -> bool {
+        let Some(reason) = self.reason(verity) else {
+            return true;
+        };
+
+        let msg = format!("Refusing to delete {verity} as it is still in use as {reason}");
+        tracing::error!("{msg}");
+        refused.push(msg);
+
+        false
+    }
+}
+
+/// Whether the bootloader entries of a staged deployment exist
+fn has_staged_boot_entries(boot_dir: &Dir) -> Result<bool> {
+    Ok(boot_dir.try_exists(TYPE1_ENT_PATH_STAGED)?
+        || boot_dir.try_exists(Path::new("grub2").join(USER_CFG_STAGED))?)
+}
+
+/// Returns the verity of the staged deployment if it was orphaned, i.e. it has neither an EROFS
+/// image nor any bootloader entry
+///
+/// The booted deployment is never considered orphaned
+fn staged_orphan<'a>(
+    staged: Option<&'a str>,
+    has_staged_entries: bool,
+    bootloader_entries: &[String],
+    images: &[String],
+    booted: &str,
+) -> Option<&'a str> {
+    let staged = staged?;
+
+    if staged == booted
+        || has_staged_entries
+        || bootloader_entries.iter().any(|e| e == staged)
+        || images.iter().any(|i| i == staged)
+    {
+        return None;
+    }
+
+    Some(staged)
+}
+
+/// Wall-clock time spent in each phase of [`composefs_gc`]
+#[derive(Debug, Default)]
+pub(crate) struct GcTimings {
+    pub(crate) list_bootloader_entries: Duration,
+    pub(crate) list_images: Duration,
+    pub(crate) list_state_dirs: Duration,
+    pub(crate) deletions: Duration,
+    pub(crate) object_sweep: Duration,
+}
+
+/// Runs one phase of the garbage collection inside a tracing span, adding the time it took to
+/// `elapsed`
+fn timed<T>(
+    phase: &'static str,
+    elapsed: &mut Duration,
+    f: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let _span = tracing::info_span!("gc_phase", phase).entered();
+
+    let start = Instant::now();
+    let res = f();
+    *elapsed += start.elapsed();
+
+    tracing::debug!("GC phase '{phase}' took {elapsed:?}");
+
+    res
+}
+
+/// Name of the splitstream holding the OCI config with the given digest, e.g. "sha256:abc123"
+fn config_stream_name(config_digest: &impl std::fmt::Display) -> String {
+    format!("oci-config-{config_digest}")
+}
+
+/// Checks that the config splitstream of the booted image exists in the repository, recording
+/// it in the report if it doesn't
+#[fn_error_context::context("Checking booted config stream {stream}")]
+fn check_booted_config_stream(
+    repo: &ComposefsRepository,
+    stream: &str,
+    report: &mut GcReport,
+) -> Result<()> {
+    if repo
+        .has_stream(stream)
+        .context("Checking stream")?
+        .is_none()
+    {
+        tracing::warn!("Config stream '{stream}' of the booted image not found in repository");
+        report.missing_booted_config = Some(stream.to_owned());
+    }
+
+    Ok(())
+}
+
+/// Ensures every splitstream explicitly protected by [`GcRoots::Offline`] exists
+fn check_protected_streams(repo: &ComposefsRepository, streams: &[String]) -> Result<()> {
+    for stream in streams {
+        if repo
+            .has_stream(stream)
+            .with_context(|| format!("Checking stream {stream}"))?
+            .is_none()
+        {
+            anyhow::bail!("Protected stream '{stream}' not found in repository");
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves the name of the booted image's config splitstream from its image info
+///
+/// Failing to do so isn't fatal, as the booted deployment is protected by its verity regardless.
+/// We only warn, record why in the report and skip checking the config splitstream.
+fn booted_config_stream(
+    imginfo: Result<ImgConfigManifest>,
+    booted_verity: &str,
+    report: &mut GcReport,
+) -> Option<String> {
+    match imginfo {
+        Ok(imginfo) => Some(config_stream_name(imginfo.manifest.config().digest())),
+
+        Err(e) => {
+            tracing::warn!(
+                "Failed to resolve config of booted image {booted_verity}, \
+                protecting only the booted deployment: {e:#}"
+            );
+            report.unresolved_booted_config = Some(format!("{e:#}"));
+            None
+        }
+    }
+}
+
+/// Ensures every one of `verities`, which were found as `what`, is a digest of the algorithm the
+/// repository uses
+///
+/// Deployments are matched up by comparing these as strings, which would silently never match if
+/// some were using a different algorithm. We'd then go on to delete deployments which are still
+/// in use, so rather bail.
+fn validate_verities(verities: &[String], what: &str) -> Result<()> {
+    for verity in verities {
+        Sha512HashValue::from_hex(verity).map_err(|e| {
+            anyhow::anyhow!(
+                "Found {what} '{verity}' which is not a SHA512 fsverity digest ({e}), \
+                 refusing to garbage collect"
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
 #[fn_error_context::context("Listing EROFS images")]
 fn list_erofs_images(sysroot: &Dir) -> Result<Vec<String>> {
     let images_dir = sysroot
@@ -43,8 +494,7 @@ fn list_erofs_images(sysroot: &Dir) -> Result<Vec<String>> {
 /// # Returns
 /// The fsverity of EROFS images corresponding to boot entries
 #[fn_error_context::context("Listing bootloader entries")]
-fn list_bootloader_entries(storage: &Storage) -> Result<Vec<String>> {
-    let bootloader = get_bootloader()?;
+fn list_bootloader_entries(storage: &Storage, bootloader: &Bootloader) -> Result<Vec<String>> {
     let boot_dir = storage.require_boot_dir()?;
 
     let entries = match bootloader {
@@ -106,24 +556,397 @@ fn list_state_dirs(sysroot: &Dir) -> Result<Vec<String>> {
     Ok(dirs)
 }
 
+/// Lists the bootloader entries, EROFS images and state directories, in that order
+///
+/// Every listed deployment is validated to be named after a SHA512 fsverity digest.
+///
+/// These are independent of each other, so the images and state directories are listed on
+/// separate threads while the bootloader entries are listed on this one.
+///
+/// The state directories are listed before any orphaned image is deleted. That's fine, as only
+/// the state directories without an image are cleaned up based on this listing, and deleting an
+/// image always deletes its state directory as well.
+fn list_deployments(
+    storage: &Storage,
+    sysroot: &Dir,
+    bootloader: &Bootloader,
+    timings: &mut GcTimings,
+) -> Result<(Vec<String>, Vec<String>, Vec<String>)> {
+    fn join<T>(handle: std::thread::ScopedJoinHandle<'_, Result<T>>) -> Result<T> {
+        handle
+            .join()
+            .map_err(|e| anyhow::anyhow!("Listing thread panicked: {e:?}"))?
+    }
+
+    let (bootloader_entries, images, state_dirs) = std::thread::scope(|s| {
+        let images = s.spawn(|| {
+            timed("list-images", &mut timings.list_images, || {
+                list_erofs_images(sysroot)
+            })
+        });
+
+        let state_dirs = s.spawn(|| {
+            timed("list-state-dirs", &mut timings.list_state_dirs, || {
+                list_state_dirs(sysroot)
+            })
+        });
+
+        let bootloader_entries = timed(
+            "list-bootloader-entries",
+            &mut timings.list_bootloader_entries,
+            || list_bootloader_entries(storage, bootloader),
+        );
+
+        let images = join(images);
+        let state_dirs = join(state_dirs);
+
+        Ok((bootloader_entries?, images?, state_dirs?))
+    })?;
+
+    validate_verities(&bootloader_entries, "bootloader entry")?;
+    validate_verities(&images, "EROFS image")?;
+    validate_verities(&state_dirs, "state directory")?;
+
+    Ok((bootloader_entries, images, state_dirs))
+}
+
+/// Deployments which are left only partially on disk, e.g. by an interrupted deletion
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Orphans {
+    /// Deployments with an EROFS image but no bootloader entry
+    pub(crate) images: Vec<String>,
+    /// Deployments with a state directory but no EROFS image. These are left behind when
+    /// garbage collection is interrupted after deleting the image.
+    pub(crate) state_dirs: Vec<String>,
+}
+
+impl Orphans {
+    fn new(bootloader_entries: &[String], images: &[String], state_dirs: &[String]) -> Self {
+        Self {
+            images: images
+                .iter()
+                .filter(|i| !bootloader_entries.contains(i))
+                .cloned()
+                .collect(),
+            state_dirs: state_dirs
+                .iter()
+                .filter(|s| !images.contains(s))
+                .cloned()
+                .collect(),
+        }
+    }
+
+    /// Drops the `booted` deployment, which is never an orphan
+    ///
+    /// Fails if the image of the booted deployment was found to be orphaned, as that means the
+    /// bootloader entries weren't listed correctly and nothing else found can be trusted either.
+    fn without_booted(mut self, booted: Option<&str>) -> Result<Self> {
+        let Some(booted) = booted else {
+            return Ok(self);
+        };
+
+        if self.images.iter().any(|i| i == booted) {
+            anyhow::bail!("Inconsistent state. Booted entry '{booted}' found for cleanup")
+        }
+
+        self.state_dirs.retain(|s| s != booted);
+
+        Ok(self)
+    }
+
+    /// Drops the `pinned` deployments, which are never orphans
+    fn without_pinned(mut self, pinned: &[String]) -> Self {
+        self.images.retain(|i| !pinned.contains(i));
+        self.state_dirs.retain(|s| !pinned.contains(s));
+        self
+    }
+}
+
+/// The deployments [`find_orphans`] found
+#[derive(Debug, Default)]
+pub(crate) struct Deployments {
+    /// Deployments with a bootloader entry
+    pub(crate) bootloader_entries: Vec<String>,
+    /// Deployments with an EROFS image
+    pub(crate) images: Vec<String>,
+    pub(crate) orphans: Orphans,
+}
+
+/// Finds the orphaned deployments in `storage` without deleting anything
+///
+/// The `booted` deployment is never considered orphaned, see [`Orphans::without_booted`].
+/// Otherwise this doesn't know which deployments are in use, [`composefs_gc`] refuses to delete
+/// the staged deployment and protected roots even if they're listed here.
+#[fn_error_context::context("Finding orphaned deployments")]
+pub(crate) fn find_orphans(
+    storage: &Storage,
+    bootloader: &Bootloader,
+    booted: Option<&str>,
+    timings: &mut GcTimings,
+) -> Result<Deployments> {
+    let (bootloader_entries, images, state_dirs) =
+        list_deployments(storage, &storage.physical_root, bootloader, timings)?;
+
+    let orphans = Orphans::new(&bootloader_entries, &images, &state_dirs).without_booted(booted)?;
+
+    Ok(Deployments {
+        bootloader_entries,
+        images,
+        orphans,
+    })
+}
+
+/// Whether `path` was modified less than `min_age` before `now`
+#[fn_error_context::context("Checking age of {path:?}")]
+fn is_younger_than(sysroot: &Dir, path: &Path, min_age: Duration, now: SystemTime) -> Result<bool> {
+    if min_age.is_zero() {
+        return Ok(false);
+    }
+
+    let modified = sysroot.symlink_metadata(path)?.modified()?.into_std();
+
+    // A modification time in the future (clock went backwards) counts as just modified
+    Ok(now.duration_since(modified).unwrap_or_default() < min_age)
+}
+
+/// Size of the EROFS image of `verity`, or 0 if it doesn't exist
+fn image_size(sysroot: &Dir, verity: &str) -> Result<u64> {
+    let image = Path::new("composefs/images").join(verity);
+
+    Ok(sysroot
+        .symlink_metadata_optional(&image)
+        .with_context(|| format!("Getting metadata of {image:?}"))?
+        .map_or(0, |meta| meta.len()))
+}
+
+/// Deletes the EROFS image and state directory of an orphaned deployment
+///
+/// # Returns
+/// The size of the deleted image
+fn delete_deployment(sysroot: &Dir, verity: &str) -> Result<u64> {
+    let size = image_size(sysroot, verity)?;
+
+    delete_image(sysroot, verity)?;
+
+    // Not every deployment has one, e.g. if it was never booted
+    if sysroot.try_exists(Path::new(STATE_DIR_RELATIVE).join(verity))? {
+        delete_state_dir(sysroot, verity)?;
+    }
+
+    Ok(size)
+}
+
+/// Moves the EROFS image and state directory of an orphaned deployment into quarantine, to be
+/// deleted once it's been there for longer than `grace`
+#[fn_error_context::context("Quarantining deployment {verity}")]
+fn quarantine_deployment(
+    sysroot: &Dir,
+    verity: &str,
+    now: SystemTime,
+    grace: Duration,
+) -> Result<()> {
+    let quarantine_dir = Path::new(QUARANTINE_DIR).join(verity);
+
+    sysroot
+        .create_dir_all(&quarantine_dir)
+        .with_context(|| format!("Creating {quarantine_dir:?}"))?;
+
+    // Record when and for how long before moving anything, so an interrupted run never leaves
+    // an entry which can't be purged. Keep the original timestamp and grace period if this
+    // deployment was already (partially) quarantined.
+    let quarantine = sysroot
+        .open_dir(&quarantine_dir)
+        .with_context(|| format!("Opening {quarantine_dir:?}"))?;
+
+    if !quarantine.try_exists(QUARANTINED_AT)? {
+        write_quarantine_secs(&quarantine, QUARANTINE_GRACE, grace.as_secs())?;
+        write_quarantine_secs(
+            &quarantine,
+            QUARANTINED_AT,
+            now.duration_since(UNIX_EPOCH)?.as_secs(),
+        )?;
+    }
+
+    let to_move = [
+        (Path::new("composefs/images").join(verity), "image"),
+        (Path::new(STATE_DIR_RELATIVE).join(verity), "state"),
+    ];
+
+    for (src, name) in to_move {
+        if !sysroot.try_exists(&src)? {
+            continue;
+        }
+
+        let dest = quarantine_dir.join(name);
+
+        // Left over from an earlier run, e.g. if the deployment was restored and then orphaned
+        // again. rename() can't replace a non-empty directory.
+        sysroot
+            .remove_all_optional(&dest)
+            .with_context(|| format!("Removing {dest:?}"))?;
+
+        tracing::debug!("Quarantining {src:?} to {dest:?}");
+        sysroot
+            .rename(&src, sysroot, &dest)
+            .with_context(|| format!("Moving {src:?} to {dest:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Atomically writes a number of seconds into a quarantined deployment's directory
+fn write_quarantine_secs(dir: &Dir, name: &str, secs: u64) -> Result<()> {
+    dir.atomic_write(name, secs.to_string())
+        .with_context(|| format!("Writing {name}"))
+}
+
+/// Reads a number of seconds written by [`write_quarantine_secs`], or `None` if the file
+/// doesn't exist
+fn read_quarantine_secs(dir: &Dir, name: &str) -> Result<Option<u64>> {
+    let contents = match dir.read_to_string(name) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Reading {name}")),
+    };
+
+    let secs = contents
+        .trim()
+        .parse::<u64>()
+        .with_context(|| format!("Parsing {name}"))?;
+
+    Ok(Some(secs))
+}
+
+/// Deletes quarantined deployments which have been in quarantine for longer than the grace period
+/// they were quarantined with, or only counts them if `dry_run` is set
+///
+/// Entries missing their timestamp or grace period, e.g. if quarantining was interrupted right
+/// after creating the entry, are treated as just quarantined with no grace period: the missing files
+/// are written and the entry is kept until the next run.
+///
+/// # Returns
+/// The number of deployments still in quarantine
+#[fn_error_context::context("Purging quarantine")]
+fn purge_quarantine(sysroot: &Dir, now: SystemTime, dry_run: bool) -> Result<usize> {
+    let Some(quarantine) = sysroot
+        .open_dir_optional(QUARANTINE_DIR)
+        .context("Opening quarantine dir")?
+    else {
+        return Ok(0);
+    };
+
+    let mut remaining = 0;
+
+    for entry in quarantine.entries_utf8()? {
+        let entry = entry?;
+        let name = entry.file_name()?;
+
+        let entry_dir = quarantine
+            .open_dir(&name)
+            .with_context(|| format!("Opening quarantined deployment {name}"))?;
+
+        let quarantined_at = read_quarantine_secs(&entry_dir, QUARANTINED_AT)?;
+        let grace = read_quarantine_secs(&entry_dir, QUARANTINE_GRACE)?;
+
+        let (Some(quarantined_at), Some(grace)) = (quarantined_at, grace) else {
+            tracing::warn!("Quarantined deployment {name} is missing its timestamp, keeping it");
+
+            if !dry_run {
+                if grace.is_none() {
+                    write_quarantine_secs(&entry_dir, QUARANTINE_GRACE, 0)?;
+                }
+
+                if quarantined_at.is_none() {
+                    let secs = now.duration_since(UNIX_EPOCH)?.as_secs();
+                    write_quarantine_secs(&entry_dir, QUARANTINED_AT, secs)?;
+                }
+            }
+
+            remaining += 1;
+            continue;
+        };
+
+        let quarantined_at = UNIX_EPOCH + Duration::from_secs(quarantined_at);
+        let grace = Duration::from_secs(grace);
+
+        // A timestamp in the future (clock went backwards) counts as just quarantined
+        if now.duration_since(quarantined_at).unwrap_or_default() < grace {
+            remaining += 1;
+            continue;
+        }
+
+        if dry_run {
+            tracing::debug!("Would delete quarantined deployment: {name}");
+            continue;
+        }
+
+        tracing::debug!("Deleting quarantined deployment: {name}");
+        quarantine
+            .remove_dir_all(&name)
+            .with_context(|| format!("Removing quarantined deployment {name}"))?;
+    }
+
+    Ok(remaining)
+}
+
+/// Number of deployments in quarantine, without purging any
+fn count_quarantined(sysroot: &Dir) -> Result<usize> {
+    let Some(quarantine) = sysroot
+        .open_dir_optional(QUARANTINE_DIR)
+        .context("Opening quarantine dir")?
+    else {
+        return Ok(0);
+    };
+
+    let mut count = 0;
+
+    for entry in quarantine.entries()? {
+        entry?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
 /// Deletes objects in sysroot/composefs/objects that are not being referenced by any of the
-/// present EROFS images
+/// present EROFS images, other than `skip_images`
 ///
 /// We do not delete streams though
+///
+/// With `dry_run` nothing is deleted, and `skip_images` are the images a real run would have
+/// deleted beforehand.
+///
+/// Stops in between object directories once `stop` requests it.
+///
+/// # Returns
+/// How many objects there were and how many of them were (or would be) deleted
 #[fn_error_context::context("Garbage collecting objects")]
 // TODO(Johan-Liebert1): This will be moved to composefs-rs
-pub(crate) fn gc_objects(sysroot: &Dir) -> Result<()> {
+pub(crate) fn gc_objects(
+    sysroot: &Dir,
+    skip_images: &[String],
+    dry_run: bool,
+    stop: &GcStop,
+) -> Result<ObjectSweep> {
     tracing::debug!("Running garbage collection on unreferenced objects");
 
     // Get all the objects referenced by all available images
-    let obj_refs = get_image_objects(sysroot)?;
+    let obj_refs = get_image_objects(sysroot, skip_images)?;
 
     // List all objects in the objects directory
     let objects_dir = sysroot
         .open_dir("composefs/objects")
         .context("Opening objects dir")?;
 
+    let mut sweep = ObjectSweep::default();
+
     for dir_name in 0x0..=0xff {
+        if stop.requested() {
+            tracing::debug!("Stopping object sweep early");
+            sweep.interrupted = true;
+            break;
+        }
+
         let dir = objects_dir
             .open_dir_optional(dir_name.to_string())
             .with_context(|| format!("Opening {dir_name}"))?;
@@ -138,18 +961,30 @@ pub(crate) fn gc_objects(sysroot: &Dir) -> Result<()> {
 
             let id = Sha512HashValue::from_object_dir_and_basename(dir_name, filename.as_bytes())?;
 
+            sweep.scanned += 1;
+
             // If this object is not referenced by any image, delete it
             if !obj_refs.contains(&id) {
                 tracing::trace!("Deleting unreferenced object: {filename}");
 
-                entry
-                    .remove_file()
-                    .with_context(|| format!("Removing object {filename}"))?;
+                let size = entry
+                    .metadata()
+                    .with_context(|| format!("Getting metadata of object {filename}"))?
+                    .len();
+
+                if !dry_run {
+                    entry
+                        .remove_file()
+                        .with_context(|| format!("Removing object {filename}"))?;
+                }
+
+                sweep.removed += 1;
+                sweep.bytes_freed += size;
             }
         }
     }
 
-    Ok(())
+    Ok(sweep)
 }
 
 /// 1. List all bootloader entries
@@ -162,55 +997,1278 @@ pub(crate) fn gc_objects(sysroot: &Dir) -> Result<()> {
 ///
 /// Similarly if EROFS image B1 doesn't exist, but state dir does, then delete the state dir and
 /// perform GC
+///
+/// A staged deployment with neither an EROFS image nor bootloader entries, left behind by an
+/// interrupted staging, is cleaned up as well
+///
+/// With [`QuarantinePolicy::Enabled`] orphans are moved to quarantine instead of being deleted.
+/// As objects of quarantined images are not tracked, we skip garbage collecting objects while
+/// anything is in quarantine so that a quarantined deployment can still be restored.
+///
+/// We also verify that the config splitstream of the booted image is present, as its absence
+/// means the repository is corrupted
+///
+/// With [`GcRoots::Offline`] there is no booted or staged deployment. Only the given deployments
+/// are protected besides those with bootloader entries, and the checks specific to the booted
+/// deployment are skipped. The bootloader entries are read as the given bootloader keeps them
+/// rather than as the running system's does.
+///
+/// Orphans younger than [`GcOptions::min_age`] are skipped, so a deployment which is still
+/// being written, and thus has no bootloader entry yet, isn't collected from under its writer.
+///
+/// Deployments in [`GcOptions::pinned`] are never collected, so a rollback target the operator
+/// chose to keep survives even if its bootloader entry is gone.
+///
+/// Only one garbage collection can run at a time, [`GcOptions::lock`] decides whether to wait for
+/// or fail on one which is already running.
+///
+/// With [`GcOptions::dry_run`] nothing is touched, and the report holds what would have been
+/// cleaned up instead.
+///
+/// [`GcOptions::stop`] can stop the run early, in between deleting deployments or object
+/// directories. The quarantine isn't purged and objects aren't swept once it did.
 #[fn_error_context::context("Running composefs garbage collection")]
-pub(crate) async fn composefs_gc(storage: &Storage, booted_cfs: &BootedComposefs) -> Result<()> {
-    let host = get_composefs_status(storage, booted_cfs).await?;
-    let booted_cfs_status = host.require_composefs_booted()?;
+pub(crate) async fn composefs_gc(
+    storage: &Storage,
+    roots: GcRoots<'_>,
+    opts: GcOptions,
+) -> Result<GcReport> {
+    let GcOptions {
+        quarantine,
+        lock,
+        min_age,
+        dry_run,
+        stop,
+        pinned,
+    } = opts;
 
-    let sysroot = &storage.physical_root;
+    validate_verities(&pinned, "pinned deployment")?;
 
-    let bootloader_entries = list_bootloader_entries(&storage)?;
-    let images = list_erofs_images(&sysroot)?;
+    let _lock = lock_gc(&storage.physical_root, lock, dry_run)?;
 
-    // Collect the deployments that have an image but no bootloader entry
-    let img_bootloader_diff = images
-        .iter()
-        .filter(|i| !bootloader_entries.contains(i))
-        .collect::<Vec<_>>();
+    let mut report = GcReport {
+        dry_run,
+        ..Default::default()
+    };
+    let now = SystemTime::now();
 
-    let staged = &host.status.staged;
+    let (booted, staged, bootloader, explicit, streams) = match roots {
+        GcRoots::Booted(booted_cfs) => {
+            let host = get_composefs_status(storage, booted_cfs).await?;
+            let booted = host.require_composefs_booted()?.verity.clone();
 
-    if img_bootloader_diff.contains(&&booted_cfs_status.verity) {
-        anyhow::bail!(
-            "Inconsistent state. Booted entry '{}' found for cleanup",
-            booted_cfs_status.verity
-        )
-    }
+            let imginfo = get_imginfo(storage, &booted, None).await;
+            let config_stream = booted_config_stream(imginfo, &booted, &mut report);
 
-    for verity in &img_bootloader_diff {
-        tracing::debug!("Cleaning up orphaned image: {verity}");
+            if let Some(config_stream) = &config_stream {
+                check_booted_config_stream(&booted_cfs.repo, config_stream, &mut report)?;
+            }
 
-        delete_staged(staged)?;
-        delete_image(&sysroot, verity)?;
-        delete_state_dir(&sysroot, verity)?;
-    }
+            let streams = config_stream.into_iter().collect::<Vec<_>>();
 
-    let state_dirs = list_state_dirs(&sysroot)?;
+            (
+                Some(booted),
+                host.status.staged,
+                get_bootloader()?,
+                &[][..],
+                streams,
+            )
+        }
 
-    // Collect all the deployments that have no image but have a state dir
-    // This for the case where the gc was interrupted after deleting the image
-    let state_img_diff = state_dirs
-        .iter()
-        .filter(|s| !images.contains(s))
-        .collect::<Vec<_>>();
+        GcRoots::Offline {
+            bootloader,
+            verities,
+            streams,
+        } => {
+            validate_verities(verities, "protected root")?;
 
-    for verity in &state_img_diff {
-        delete_staged(staged)?;
-        delete_state_dir(&sysroot, verity)?;
-    }
+            let repo = open_composefs_repo(&storage.physical_root)?;
+            check_protected_streams(&repo, streams)?;
 
-    // Run garbage collection on objects after deleting images
-    gc_objects(&sysroot)?;
+            (None, None, bootloader, verities, streams.to_vec())
+        }
+    };
 
-    Ok(())
+    // Entered only now, as the guard can't be held across an await
+    let gc_span = tracing::info_span!(
+        "composefs_gc",
+        action = quarantine.action(),
+        min_age = ?min_age,
+        dry_run,
+        images = tracing::field::Empty,
+        state_dirs = tracing::field::Empty,
+        objects = tracing::field::Empty,
+        bytes_freed = tracing::field::Empty,
+        interrupted = tracing::field::Empty,
+    );
+    let _gc_span = gc_span.enter();
+
+    let sysroot = &storage.physical_root;
+
+    let timings = &mut report.timings;
+    let refused = &mut report.refused_deletions;
+    let orphaned_images = &mut report.orphaned_images;
+    let orphaned_state_dirs = &mut report.orphaned_state_dirs;
+    let too_recent = &mut report.too_recent;
+    let bytes_freed = &mut report.bytes_freed;
+
+    let Deployments {
+        bootloader_entries,
+        images,
+        orphans,
+    } = find_orphans(storage, &bootloader, booted.as_deref(), timings)?;
+
+    let orphans = orphans.without_pinned(&pinned);
+
+    let staged_cfs_verity = staged
+        .as_ref()
+        .and_then(|s| s.composefs.as_ref())
+        .map(|cfs| cfs.verity.as_str());
+
+    let has_staged_entries = has_staged_boot_entries(storage.require_boot_dir()?)?;
+
+    let staged_verity = match has_staged_entries {
+        true => staged_cfs_verity,
+        false => None,
+    };
+
+    let in_use = InUse {
+        booted: booted.as_deref(),
+        staged: staged_verity,
+        bootloader_entries: &bootloader_entries,
+        explicit,
+        pinned: &pinned,
+    };
+
+    report.protected_roots = in_use.roots(&streams);
+    tracing::debug!("Protected roots: {:?}", report.protected_roots);
+
+    // A staged deployment whose image was never written, e.g. as the system went down while
+    // staging it. Its state dir, if any, is cleaned up along with the other orphaned state dirs.
+    if let Some(verity) = booted.as_deref().and_then(|booted| {
+        staged_orphan(
+            staged_cfs_verity,
+            has_staged_entries,
+            &bootloader_entries,
+            &images,
+            booted,
+        )
+    }) {
+        timed("delete-staged-orphan", &mut timings.deletions, || {
+            if !dry_run {
+                delete_staged(&staged)?;
+            }
+
+            tracing::info!(verity = %verity, "Cleaned up orphaned staged deployment");
+
+            Ok(())
+        })?;
+
+        report.staged_orphan = Some(verity.to_owned());
+    }
+
+    let interrupted = &mut report.interrupted;
+
+    timed("delete-orphaned-images", &mut timings.deletions, || {
+        for verity in &orphans.images {
+            if stop.requested() {
+                *interrupted = true;
+                break;
+            }
+
+            if !in_use.ensure_unused(verity, refused) {
+                continue;
+            }
+
+            let image = Path::new("composefs/images").join(verity);
+
+            if is_younger_than(sysroot, &image, min_age, now)? {
+                tracing::debug!("Skipping recently modified orphaned image: {verity}");
+                too_recent.push(verity.to_string());
+                continue;
+            }
+
+            match (quarantine, dry_run) {
+                (QuarantinePolicy::Disabled, true) => *bytes_freed += image_size(&sysroot, verity)?,
+                (QuarantinePolicy::Enabled { .. }, true) => {}
+                (QuarantinePolicy::Disabled, false) => {
+                    *bytes_freed += delete_deployment(&sysroot, verity)?
+                }
+                (QuarantinePolicy::Enabled { grace }, false) => {
+                    quarantine_deployment(&sysroot, verity, now, grace)?
+                }
+            }
+
+            tracing::info!(
+                verity = %verity,
+                action = quarantine.action(),
+                "Cleaned up orphaned image"
+            );
+
+            orphaned_images.push(verity.to_string());
+        }
+
+        Ok(())
+    })?;
+
+    timed("delete-orphaned-state-dirs", &mut timings.deletions, || {
+        for verity in &orphans.state_dirs {
+            if *interrupted || stop.requested() {
+                *interrupted = true;
+                break;
+            }
+
+            if !in_use.ensure_unused(verity, refused) {
+                continue;
+            }
+
+            let state_dir = Path::new(STATE_DIR_RELATIVE).join(verity);
+
+            if is_younger_than(sysroot, &state_dir, min_age, now)? {
+                tracing::debug!("Skipping recently modified orphaned state dir: {verity}");
+                too_recent.push(verity.to_string());
+                continue;
+            }
+
+            if !dry_run {
+                match quarantine {
+                    QuarantinePolicy::Disabled => delete_state_dir(&sysroot, verity)?,
+                    QuarantinePolicy::Enabled { grace } => {
+                        quarantine_deployment(&sysroot, verity, now, grace)?
+                    }
+                }
+            }
+
+            tracing::info!(
+                verity = %verity,
+                action = quarantine.action(),
+                "Cleaned up orphaned state dir"
+            );
+
+            orphaned_state_dirs.push(verity.to_string());
+        }
+
+        Ok(())
+    })?;
+
+    if report.interrupted {
+        tracing::info!("Garbage collection stopped early, skipping object sweep");
+    } else {
+        let mut quarantined = timed("purge-quarantine", &mut timings.deletions, || {
+            purge_quarantine(&sysroot, now, dry_run)
+        })?;
+
+        // A real run would have just quarantined the orphans, and they'd still be within the
+        // grace period
+        let grace = match quarantine {
+            QuarantinePolicy::Disabled => Duration::ZERO,
+            QuarantinePolicy::Enabled { grace } => grace,
+        };
+
+        if dry_run && !grace.is_zero() {
+            quarantined += report.orphaned_images.len() + report.orphaned_state_dirs.len();
+        }
+
+        report.quarantined = quarantined;
+
+        if quarantined > 0 {
+            tracing::debug!("{quarantined} deployment(s) in quarantine, skipping object sweep");
+        } else {
+            // Run garbage collection on objects after deleting images
+            // In a dry run, the orphaned images are still around and would keep their objects
+            let deleted_images: &[String] = match dry_run {
+                true => &report.orphaned_images[..],
+                false => &[],
+            };
+
+            let objects = timed("object-sweep", &mut report.timings.object_sweep, || {
+                gc_objects(&sysroot, deleted_images, dry_run, &stop)
+            })?;
+
+            report.objects = Some(objects);
+            report.bytes_freed += objects.bytes_freed;
+            report.interrupted = objects.interrupted;
+            gc_span.record("objects", objects.removed);
+        }
+    }
+
+    gc_span.record("images", report.orphaned_images.len());
+    gc_span.record("state_dirs", report.orphaned_state_dirs.len());
+    gc_span.record("bytes_freed", report.bytes_freed);
+    gc_span.record("interrupted", report.interrupted);
+    tracing::info!("{report}");
+
+    Ok(report)
+}
+
+/// Ensures garbage collection can delete `verity`, as requested by [`composefs_gc_target`]
+fn check_gc_target(in_use: &InUse, verity: &str) -> Result<()> {
+    if let Some(reason) = in_use.reason(verity) {
+        anyhow::bail!("Refusing to collect {verity} as it is still in use as {reason}");
+    }
+
+    Ok(())
+}
+
+/// Deletes the EROFS image and state directory of the deployment `verity`, then garbage collects
+/// objects
+///
+/// Unlike [`composefs_gc`], this doesn't look for orphans, it's meant for reaping a deployment the
+/// caller knows is dead. The booted, staged and `pinned` deployments and any deployment which
+/// still has a bootloader entry are refused, the latter should be removed through
+/// [`delete_composefs_deployment`](super::delete::delete_composefs_deployment) instead.
+///
+/// As with [`composefs_gc`], objects are not garbage collected while anything is in quarantine.
+#[fn_error_context::context("Garbage collecting deployment {verity}")]
+pub(crate) async fn composefs_gc_target(
+    storage: &Storage,
+    booted_cfs: &BootedComposefs,
+    verity: &str,
+    lock: GcLockMode,
+    pinned: &[String],
+) -> Result<GcReport> {
+    validate_verities(&[verity.to_owned()], "deployment")?;
+    validate_verities(pinned, "pinned deployment")?;
+
+    let _lock = lock_gc(&storage.physical_root, lock, false)?;
+
+    let host = get_composefs_status(storage, booted_cfs).await?;
+    let booted = host.require_composefs_booted()?.verity.clone();
+    let staged = host
+        .status
+        .staged
+        .as_ref()
+        .and_then(|s| s.composefs.as_ref())
+        .map(|cfs| cfs.verity.as_str());
+
+    let sysroot = &storage.physical_root;
+    let mut report = GcReport::default();
+
+    let bootloader = get_bootloader()?;
+    let bootloader_entries = timed(
+        "list-bootloader-entries",
+        &mut report.timings.list_bootloader_entries,
+        || list_bootloader_entries(storage, &bootloader),
+    )?;
+    validate_verities(&bootloader_entries, "bootloader entry")?;
+
+    // Refuse the staged deployment even without staged bootloader entries, the operator might
+    // have raced with staging
+    let in_use = InUse {
+        booted: Some(&booted),
+        staged,
+        bootloader_entries: &bootloader_entries,
+        explicit: &[],
+        pinned,
+    };
+
+    report.protected_roots = in_use.roots(&[]);
+    check_gc_target(&in_use, verity)?;
+
+    let has_image = sysroot.try_exists(Path::new("composefs/images").join(verity))?;
+    let has_state_dir = sysroot.try_exists(Path::new(STATE_DIR_RELATIVE).join(verity))?;
+
+    timed("delete-target", &mut report.timings.deletions, || {
+        match (has_image, has_state_dir) {
+            (true, _) => {
+                report.bytes_freed += delete_deployment(sysroot, verity)?;
+                report.orphaned_images.push(verity.to_owned());
+            }
+            (false, true) => {
+                delete_state_dir(sysroot, verity)?;
+                report.orphaned_state_dirs.push(verity.to_owned());
+            }
+            (false, false) => anyhow::bail!("Deployment {verity} not found"),
+        }
+
+        tracing::info!(verity = %verity, "Cleaned up deployment");
+
+        Ok(())
+    })?;
+
+    report.quarantined = count_quarantined(sysroot)?;
+
+    if report.quarantined > 0 {
+        tracing::debug!(
+            "{} deployment(s) in quarantine, skipping object sweep",
+            report.quarantined
+        );
+    } else {
+        let objects = timed("object-sweep", &mut report.timings.object_sweep, || {
+            gc_objects(sysroot, &[], false, &GcStop::default())
+        })?;
+
+        report.objects = Some(objects);
+        report.bytes_freed += objects.bytes_freed;
+    }
+
+    tracing::info!("{report}");
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std_ext::cap_std;
+
+    use super::*;
+
+    #[test]
+    fn test_missing_booted_config_stream() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/streams")?;
+        let repo = open_composefs_repo(&td)?;
+
+        let mut report = GcReport::default();
+        let stream = config_stream_name(&"sha256:0123abcd");
+        check_booted_config_stream(&repo, &stream, &mut report)?;
+
+        assert_eq!(
+            report.missing_booted_config.as_deref(),
+            Some("oci-config-sha256:0123abcd")
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_protected_stream() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/streams")?;
+        let repo = open_composefs_repo(&td)?;
+
+        check_protected_streams(&repo, &[])?;
+
+        let err =
+            check_protected_streams(&repo, &["oci-config-sha256:0123abcd".to_owned()]).unwrap_err();
+        assert!(err.to_string().contains("oci-config-sha256:0123abcd"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unresolved_booted_config() {
+        let mut report = GcReport::default();
+
+        let imginfo = Err(anyhow::anyhow!("No imgref or imginfo file found"));
+        assert_eq!(booted_config_stream(imginfo, "booted", &mut report), None);
+
+        assert_eq!(
+            report.unresolved_booted_config.as_deref(),
+            Some("No imgref or imginfo file found")
+        );
+        assert_eq!(report.missing_booted_config, None);
+    }
+
+    #[test]
+    fn test_delete_deployment_size() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/images")?;
+        td.create_dir_all(Path::new(STATE_DIR_RELATIVE).join("orphan/etc"))?;
+        td.write("composefs/images/orphan", vec![0u8; 4096])?;
+        td.write(
+            Path::new(STATE_DIR_RELATIVE).join("orphan/etc/file"),
+            "state",
+        )?;
+
+        // Only the image counts towards the freed space
+        assert_eq!(delete_deployment(&td, "orphan")?, 4096);
+        assert!(!td.try_exists("composefs/images/orphan")?);
+        assert!(!td.try_exists(Path::new(STATE_DIR_RELATIVE).join("orphan"))?);
+
+        // An image without a state dir
+        td.write("composefs/images/orphan", "erofs")?;
+        assert_eq!(delete_deployment(&td, "orphan")?, 5);
+        assert!(!td.try_exists("composefs/images/orphan")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_objects_dry_run() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/images")?;
+        td.create_dir_all("composefs/streams")?;
+        td.create_dir_all("composefs/objects/12")?;
+
+        // Not referenced by any image
+        let objects = ["ab".repeat(63), "cd".repeat(63)];
+        for object in &objects {
+            td.write(Path::new("composefs/objects/12").join(object), "object")?;
+        }
+
+        let expected = ObjectSweep {
+            scanned: 2,
+            removed: 2,
+            bytes_freed: 12,
+            interrupted: false,
+        };
+
+        assert_eq!(gc_objects(&td, &[], true, &GcStop::default())?, expected);
+        for object in &objects {
+            assert!(td.try_exists(Path::new("composefs/objects/12").join(object))?);
+        }
+
+        // A real run does what the dry run said it would
+        assert_eq!(gc_objects(&td, &[], false, &GcStop::default())?, expected);
+        for object in &objects {
+            assert!(!td.try_exists(Path::new("composefs/objects/12").join(object))?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_objects_stopped() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/images")?;
+        td.create_dir_all("composefs/streams")?;
+
+        let objects = [("12", "ab".repeat(63)), ("34", "cd".repeat(63))];
+        for (dir, object) in &objects {
+            td.create_dir_all(Path::new("composefs/objects").join(dir))?;
+            td.write(
+                Path::new("composefs/objects").join(dir).join(object),
+                "object",
+            )?;
+        }
+
+        let cancel = CancellationToken::new();
+        let stop = GcStop {
+            deadline: None,
+            cancel: Some(cancel.clone()),
+        };
+        assert!(!stop.requested());
+
+        // Stopped right away, before touching anything
+        cancel.cancel();
+        let sweep = gc_objects(&td, &[], false, &stop)?;
+        assert_eq!(
+            sweep,
+            ObjectSweep {
+                interrupted: true,
+                ..Default::default()
+            }
+        );
+        for (dir, object) in &objects {
+            assert!(td.try_exists(Path::new("composefs/objects").join(dir).join(object))?);
+        }
+
+        // A past deadline stops as well
+        let stop = GcStop {
+            deadline: Some(Instant::now()),
+            cancel: None,
+        };
+        assert!(gc_objects(&td, &[], false, &stop)?.interrupted);
+
+        // The next run picks up the remaining work
+        let sweep = gc_objects(&td, &[], false, &GcStop::default())?;
+        assert_eq!(sweep.removed, 2);
+        assert!(!sweep.interrupted);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_purge_quarantine_dry_run() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/images")?;
+        td.write("composefs/images/orphan", "erofs")?;
+
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        quarantine_deployment(&td, "orphan", t0, Duration::from_secs(60))?;
+
+        // Past the grace period, but left alone
+        let later = t0 + Duration::from_secs(61);
+        assert_eq!(purge_quarantine(&td, later, true)?, 0);
+        assert!(td.try_exists("composefs/quarantine/orphan/image")?);
+
+        assert_eq!(purge_quarantine(&td, later, false)?, 0);
+        assert!(!td.try_exists("composefs/quarantine/orphan")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/images")?;
+        td.create_dir_all(Path::new(STATE_DIR_RELATIVE).join("orphan"))?;
+        td.write("composefs/images/orphan", "erofs")?;
+
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let grace = Duration::from_secs(60);
+
+        // Nothing in quarantine yet
+        assert_eq!(purge_quarantine(&td, t0, false)?, 0);
+        assert_eq!(count_quarantined(&td)?, 0);
+
+        quarantine_deployment(&td, "orphan", t0, grace)?;
+        assert_eq!(count_quarantined(&td)?, 1);
+
+        assert!(!td.try_exists("composefs/images/orphan")?);
+        assert!(!td.try_exists(Path::new(STATE_DIR_RELATIVE).join("orphan"))?);
+        assert_eq!(
+            td.read_to_string("composefs/quarantine/orphan/image")?,
+            "erofs"
+        );
+        assert!(td.is_dir("composefs/quarantine/orphan/state"));
+
+        // Still within the grace period
+        assert_eq!(
+            purge_quarantine(&td, t0 + Duration::from_secs(30), false)?,
+            1
+        );
+        assert!(td.try_exists("composefs/quarantine/orphan")?);
+
+        // Past the grace period
+        assert_eq!(
+            purge_quarantine(&td, t0 + Duration::from_secs(61), false)?,
+            0
+        );
+        assert!(!td.try_exists("composefs/quarantine/orphan")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_grace_recorded() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/images")?;
+        td.write("composefs/images/short", "erofs")?;
+        td.write("composefs/images/long", "erofs")?;
+
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        quarantine_deployment(&td, "short", t0, Duration::ZERO)?;
+        quarantine_deployment(&td, "long", t0, Duration::from_secs(60))?;
+
+        // Each deployment keeps the grace period it was quarantined with, whatever the policy of
+        // the run purging the quarantine
+        assert_eq!(
+            purge_quarantine(&td, t0 + Duration::from_secs(30), false)?,
+            1
+        );
+        assert!(!td.try_exists("composefs/quarantine/short")?);
+        assert!(td.try_exists("composefs/quarantine/long")?);
+
+        assert_eq!(
+            purge_quarantine(&td, t0 + Duration::from_secs(61), false)?,
+            0
+        );
+        assert!(!td.try_exists("composefs/quarantine/long")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_quarantine_missing_timestamp() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/quarantine/orphan")?;
+        td.write("composefs/quarantine/orphan/image", "erofs")?;
+
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_000_000);
+
+        // Left alone in a dry run, without writing anything
+        assert_eq!(purge_quarantine(&td, t0, true)?, 1);
+        assert!(!td.try_exists("composefs/quarantine/orphan/quarantined-at")?);
+
+        // Treated as just quarantined, with no grace period
+        assert_eq!(purge_quarantine(&td, t0, false)?, 1);
+        assert_eq!(
+            td.read_to_string("composefs/quarantine/orphan/quarantined-at")?,
+            "1000000"
+        );
+        assert_eq!(td.read_to_string("composefs/quarantine/orphan/grace")?, "0");
+        assert!(td.try_exists("composefs/quarantine/orphan/image")?);
+
+        assert_eq!(
+            purge_quarantine(&td, t0 + Duration::from_secs(1), false)?,
+            0
+        );
+        assert!(!td.try_exists("composefs/quarantine/orphan")?);
+
+        // A grace period written before the interruption is kept
+        td.create_dir_all("composefs/quarantine/orphan")?;
+        td.write("composefs/quarantine/orphan/grace", "60")?;
+        assert_eq!(purge_quarantine(&td, t0, false)?, 1);
+        assert_eq!(
+            td.read_to_string("composefs/quarantine/orphan/grace")?,
+            "60"
+        );
+        assert_eq!(
+            purge_quarantine(&td, t0 + Duration::from_secs(30), false)?,
+            1
+        );
+        assert_eq!(
+            purge_quarantine(&td, t0 + Duration::from_secs(61), false)?,
+            0
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_requarantine() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let state_dir = Path::new(STATE_DIR_RELATIVE).join("orphan");
+        td.create_dir_all("composefs/images")?;
+        td.create_dir_all(&state_dir)?;
+        td.write(state_dir.join("old"), "old")?;
+
+        let t0 = UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let grace = Duration::from_secs(60);
+        quarantine_deployment(&td, "orphan", t0, grace)?;
+
+        // The deployment got a new state dir after being restored by hand, and is orphaned again
+        td.create_dir_all(&state_dir)?;
+        td.write(state_dir.join("new"), "new")?;
+        quarantine_deployment(&td, "orphan", t0 + Duration::from_secs(30), grace)?;
+
+        assert!(!td.try_exists(&state_dir)?);
+        assert!(!td.try_exists("composefs/quarantine/orphan/state/old")?);
+        assert_eq!(
+            td.read_to_string("composefs/quarantine/orphan/state/new")?,
+            "new"
+        );
+
+        // The original timestamp is kept
+        assert_eq!(
+            purge_quarantine(&td, t0 + Duration::from_secs(61), false)?,
+            0
+        );
+        assert!(!td.try_exists("composefs/quarantine/orphan")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_refuse_deleting_in_use() -> Result<()> {
+        let bootloader_entries = ["rollback".to_owned()];
+        let in_use = InUse {
+            booted: Some("booted"),
+            staged: Some("staged"),
+            bootloader_entries: &bootloader_entries,
+            explicit: &[],
+            pinned: &[],
+        };
+
+        // Say listing went wrong and every deployment was found to be an orphan
+        let orphans = ["booted", "staged", "rollback", "orphan"];
+        let mut refused = vec![];
+
+        let deletable = orphans
+            .into_iter()
+            .filter(|verity| in_use.ensure_unused(verity, &mut refused))
+            .collect::<Vec<_>>();
+
+        assert_eq!(deletable, ["orphan"]);
+        assert_eq!(
+            refused,
+            [
+                "Refusing to delete booted as it is still in use as booted deployment",
+                "Refusing to delete staged as it is still in use as staged deployment",
+                "Refusing to delete rollback as it is still in use as bootloader entry",
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_target_in_use() -> Result<()> {
+        let bootloader_entries = ["booted".to_owned(), "rollback".to_owned()];
+        let pinned = ["pinned".to_owned()];
+        let in_use = InUse {
+            booted: Some("booted"),
+            staged: Some("staged"),
+            bootloader_entries: &bootloader_entries,
+            explicit: &[],
+            pinned: &pinned,
+        };
+
+        for (verity, reason) in [
+            ("booted", "booted deployment"),
+            ("staged", "staged deployment"),
+            ("rollback", "bootloader entry"),
+            ("pinned", "pinned deployment"),
+        ] {
+            let err = check_gc_target(&in_use, verity).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("Refusing to collect {verity} as it is still in use as {reason}")
+            );
+        }
+
+        check_gc_target(&in_use, "dead")?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_protected_roots() {
+        // The booted and staged deployments have bootloader entries as well
+        let bootloader_entries = [
+            "booted".to_owned(),
+            "staged".to_owned(),
+            "rollback".to_owned(),
+        ];
+
+        let mut in_use = InUse {
+            booted: Some("booted"),
+            staged: Some("staged"),
+            bootloader_entries: &bootloader_entries,
+            explicit: &[],
+            pinned: &[],
+        };
+
+        assert_eq!(
+            in_use.roots(&["oci-config-sha256:0123abcd".to_owned()]),
+            ["booted", "staged", "rollback", "oci-config-sha256:0123abcd"]
+        );
+
+        in_use.staged = None;
+        assert_eq!(in_use.roots(&[]), ["booted", "staged", "rollback"]);
+
+        in_use.bootloader_entries = &[];
+        assert_eq!(in_use.roots(&[]), ["booted"]);
+    }
+
+    #[test]
+    fn test_offline_roots() -> Result<()> {
+        let bootloader_entries = ["rollback".to_owned()];
+        let explicit = ["pinned".to_owned()];
+        let in_use = InUse {
+            booted: None,
+            staged: None,
+            bootloader_entries: &bootloader_entries,
+            explicit: &explicit,
+            pinned: &[],
+        };
+
+        assert_eq!(
+            in_use.roots(&["oci-config-sha256:0123abcd".to_owned()]),
+            ["pinned", "rollback", "oci-config-sha256:0123abcd"]
+        );
+
+        let orphans = ["pinned", "rollback", "orphan"];
+        let mut refused = vec![];
+
+        let deletable = orphans
+            .into_iter()
+            .filter(|verity| in_use.ensure_unused(verity, &mut refused))
+            .collect::<Vec<_>>();
+
+        assert_eq!(deletable, ["orphan"]);
+        assert_eq!(
+            refused,
+            [
+                "Refusing to delete pinned as it is still in use as protected root",
+                "Refusing to delete rollback as it is still in use as bootloader entry",
+            ]
+        );
+
+        // Protected roots are validated like everything else we list
+        let err = validate_verities(&explicit, "protected root").unwrap_err();
+        assert!(err.to_string().contains("protected root 'pinned'"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_pinned() {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        // "pinned" lost its bootloader entry, and "pinned-state" its image as well
+        let entries = strings(&["booted"]);
+        let images = strings(&["booted", "pinned", "orphan"]);
+        let state_dirs = strings(&["booted", "pinned", "orphan", "pinned-state"]);
+        let pinned = strings(&["pinned", "pinned-state"]);
+
+        let orphans = Orphans::new(&entries, &images, &state_dirs).without_pinned(&pinned);
+        assert_eq!(
+            orphans,
+            Orphans {
+                images: strings(&["orphan"]),
+                state_dirs: vec![],
+            }
+        );
+
+        let in_use = InUse {
+            booted: Some("booted"),
+            staged: None,
+            bootloader_entries: &entries,
+            explicit: &[],
+            pinned: &pinned,
+        };
+
+        assert_eq!(in_use.roots(&[]), ["booted", "pinned", "pinned-state"]);
+
+        // Even if listing went wrong, the pinned deployments are refused
+        let mut refused = vec![];
+        assert!(!in_use.ensure_unused("pinned", &mut refused));
+        assert!(in_use.ensure_unused("orphan", &mut refused));
+        assert_eq!(
+            refused,
+            ["Refusing to delete pinned as it is still in use as pinned deployment"]
+        );
+    }
+
+    #[test]
+    fn test_offline_bootloader_entries() -> Result<()> {
+        use crate::composefs_consts::TYPE1_ENT_PATH;
+
+        let verity = "ab".repeat(64);
+
+        let root = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let esp = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        let run = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+
+        esp.create_dir_all(TYPE1_ENT_PATH)?;
+        esp.write(
+            Path::new(TYPE1_ENT_PATH).join("bootc-1.conf"),
+            format!(
+                "title test\nversion 1\nlinux /{verity}/vmlinuz\n\
+                 initrd /{verity}/initrd\noptions rw composefs={verity}\n"
+            ),
+        )?;
+
+        let storage = Storage::new_composefs_offline(
+            root.try_clone()?,
+            "/target".into(),
+            esp.try_clone()?,
+            &run,
+        )?;
+
+        // The entries are read from the store's boot dir as its bootloader keeps them, whatever
+        // the running system booted with
+        assert_eq!(
+            list_bootloader_entries(&storage, &Bootloader::Systemd)?,
+            [verity]
+        );
+        assert!(list_bootloader_entries(&storage, &Bootloader::Grub).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let entries = strings(&["booted", "rollback"]);
+        let images = strings(&["booted", "rollback", "orphan"]);
+        // "interrupted" lost its image to an interrupted garbage collection
+        let state_dirs = strings(&["booted", "rollback", "orphan", "interrupted"]);
+
+        assert_eq!(
+            Orphans::new(&entries, &images, &state_dirs),
+            Orphans {
+                images: strings(&["orphan"]),
+                state_dirs: strings(&["interrupted"]),
+            }
+        );
+
+        // The state dir of an orphaned image isn't an orphan by itself, it goes with the image
+        assert_eq!(
+            Orphans::new(&entries, &images, &images),
+            Orphans {
+                images: strings(&["orphan"]),
+                state_dirs: vec![],
+            }
+        );
+
+        assert_eq!(
+            Orphans::new(&entries, &entries, &entries),
+            Orphans::default()
+        );
+
+        // A deployment still being written may have a state dir but no image or entry yet
+        assert_eq!(
+            Orphans::new(&[], &[], &strings(&["new"])),
+            Orphans {
+                images: vec![],
+                state_dirs: strings(&["new"]),
+            }
+        );
+    }
+
+    #[test]
+    fn test_orphans_without_booted() -> Result<()> {
+        let strings = |v: &[&str]| v.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+
+        let entries = strings(&["booted"]);
+        let images = strings(&["booted", "orphan"]);
+        let state_dirs = strings(&["booted", "orphan", "interrupted"]);
+
+        let orphans = Orphans::new(&entries, &images, &state_dirs);
+        assert_eq!(orphans.without_booted(Some("booted"))?.images, ["orphan"]);
+
+        // The booted deployment lost its image, its state dir is still kept
+        let orphans = Orphans::new(&entries, &strings(&["orphan"]), &state_dirs);
+        assert_eq!(
+            orphans.without_booted(Some("booted"))?,
+            Orphans {
+                images: strings(&["orphan"]),
+                state_dirs: strings(&["interrupted"]),
+            }
+        );
+
+        // The booted deployment lost its bootloader entry, the listing can't be trusted
+        let orphans = Orphans::new(&[], &images, &state_dirs);
+        let err = orphans.without_booted(Some("booted")).unwrap_err();
+        assert!(err.to_string().contains("Booted entry 'booted'"), "{err}");
+
+        // There's nothing to keep for an offline store
+        let orphans = Orphans::new(&[], &images, &state_dirs);
+        assert_eq!(orphans.without_booted(None)?.images, images);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_staged_orphan() {
+        let entries = ["rollback".to_owned()];
+        let images = [
+            "booted".to_owned(),
+            "rollback".to_owned(),
+            "staged".to_owned(),
+        ];
+
+        // Staging was interrupted before the image was written
+        assert_eq!(
+            staged_orphan(Some("interrupted"), false, &entries, &images, "booted"),
+            Some("interrupted")
+        );
+
+        // Staged bootloader entries, an existing image or another bootloader entry keep it
+        assert_eq!(
+            staged_orphan(Some("interrupted"), true, &entries, &images, "booted"),
+            None
+        );
+        assert_eq!(
+            staged_orphan(Some("staged"), false, &entries, &images, "booted"),
+            None
+        );
+        assert_eq!(
+            staged_orphan(Some("rollback"), false, &entries, &[], "booted"),
+            None
+        );
+
+        // The booted deployment is never touched, even if everything else went missing
+        assert_eq!(
+            staged_orphan(Some("booted"), false, &[], &[], "booted"),
+            None
+        );
+
+        assert_eq!(
+            staged_orphan(None, false, &entries, &images, "booted"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_has_staged_boot_entries() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        assert!(!has_staged_boot_entries(&td)?);
+
+        td.create_dir_all(TYPE1_ENT_PATH_STAGED)?;
+        assert!(has_staged_boot_entries(&td)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_lock() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs")?;
+
+        let first = lock_gc(&td, GcLockMode::FailFast, false)?;
+
+        let err = lock_gc(&td, GcLockMode::FailFast, false).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Another garbage collection is already running"),
+            "{err:#}"
+        );
+
+        // A waiting run goes ahead once the first one is done
+        let waiter = std::thread::scope(|s| {
+            let waiter = s.spawn(|| lock_gc(&td, GcLockMode::Wait, false).map(|_| ()));
+
+            std::thread::sleep(Duration::from_millis(50));
+            assert!(!waiter.is_finished());
+
+            drop(first);
+            waiter.join().unwrap()
+        });
+        waiter?;
+
+        lock_gc(&td, GcLockMode::FailFast, false)?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_gc_lock_dry_run() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs")?;
+
+        // A dry run doesn't create the lock file
+        lock_gc(&td, GcLockMode::FailFast, true)?;
+        assert!(!td.try_exists(GC_LOCK)?);
+
+        let real = lock_gc(&td, GcLockMode::FailFast, false)?;
+        let err = lock_gc(&td, GcLockMode::FailFast, true).unwrap_err();
+        assert!(
+            format!("{err:#}").contains("Another garbage collection is already running"),
+            "{err:#}"
+        );
+        drop(real);
+
+        // Dry runs only block real runs
+        let first = lock_gc(&td, GcLockMode::FailFast, true)?;
+        let _second = lock_gc(&td, GcLockMode::FailFast, true)?;
+        lock_gc(&td, GcLockMode::FailFast, false).unwrap_err();
+        drop(first);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_verities() -> Result<()> {
+        let sha512 = "ab".repeat(64);
+        let sha256 = "ab".repeat(32);
+
+        // What we'd find in the bootloader entries is what we'd write out
+        let parsed = Sha512HashValue::from_hex(&sha512)?;
+        assert_eq!(parsed.to_hex(), sha512);
+
+        validate_verities(&[sha512.clone()], "EROFS image")?;
+
+        for invalid in [sha256, "not-a-digest".into()] {
+            let err = validate_verities(&[sha512.clone(), invalid.clone()], "EROFS image")
+                .unwrap_err()
+                .to_string();
+            assert!(
+                err.starts_with(&format!("Found EROFS image '{invalid}'")),
+                "{err}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_min_age() -> Result<()> {
+        let td = cap_std_ext::cap_tempfile::tempdir(cap_std::ambient_authority())?;
+        td.create_dir_all("composefs/images")?;
+        td.write("composefs/images/orphan", "erofs")?;
+
+        let image = Path::new("composefs/images/orphan");
+        let modified = td.metadata(image)?.modified()?.into_std();
+        let min_age = Duration::from_secs(60);
+
+        // Freshly written, so kept
+        assert!(is_younger_than(&td, image, min_age, modified)?);
+        assert!(is_younger_than(
+            &td,
+            image,
+            min_age,
+            modified + Duration::from_secs(59)
+        )?);
+        // Modified "in the future"
+        assert!(is_younger_than(
+            &td,
+            image,
+            min_age,
+            modified - Duration::from_secs(10)
+        )?);
+
+        // Old enough to be collected
+        assert!(!is_younger_than(
+            &td,
+            image,
+            min_age,
+            modified + Duration::from_secs(61)
+        )?);
+
+        // No threshold means everything is old enough, without even looking at the file
+        assert!(!is_younger_than(
+            &td,
+            Path::new("composefs/images/missing"),
+            Duration::ZERO,
+            modified
+        )?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_report_display() {
+        let mut report = GcReport {
+            orphaned_images: vec!["a".into(), "b".into()],
+            orphaned_state_dirs: vec!["c".into()],
+            objects: Some(ObjectSweep {
+                scanned: 10,
+                removed: 5,
+                bytes_freed: 1024,
+                interrupted: false,
+            }),
+            bytes_freed: 3072,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "Cleaned up 2 orphaned image(s) and 1 orphaned state dir(s), removed 5 of 10 object(s), freed 3.00 KiB"
+        );
+
+        report.objects = None;
+        report.quarantined = 3;
+        report.bytes_freed = 0;
+
+        assert_eq!(
+            report.to_string(),
+            "Cleaned up 2 orphaned image(s) and 1 orphaned state dir(s), skipped object sweep with 3 deployment(s) in quarantine, freed 0 B"
+        );
+
+        // The same summary, so a dry run can be parsed like a real one
+        report.dry_run = true;
+
+        assert_eq!(
+            report.to_string(),
+            "[dry run] Cleaned up 2 orphaned image(s) and 1 orphaned state dir(s), skipped object sweep with 3 deployment(s) in quarantine, freed 0 B"
+        );
+
+        report.dry_run = false;
+        report.interrupted = true;
+
+        assert_eq!(
+            report.to_string(),
+            "Cleaned up 2 orphaned image(s) and 1 orphaned state dir(s), skipped object sweep, freed 0 B, stopped early"
+        );
+    }
+
+    #[test]
+    fn test_phase_timings() -> Result<()> {
+        let mut timings = GcTimings::default();
+
+        let v = timed("test", &mut timings.deletions, || {
+            std::thread::sleep(Duration::from_millis(1));
+            Ok(42)
+        })?;
+        assert_eq!(v, 42);
+        assert!(timings.deletions >= Duration::from_millis(1));
+
+        // Time spent in repeated runs of a phase accumulates
+        let first = timings.deletions;
+        timed("test", &mut timings.deletions, || {
+            std::thread::sleep(Duration::from_millis(1));
+            Ok(())
+        })?;
+        assert!(timings.deletions > first);
+
+        // Phases which didn't run stay at zero
+        assert_eq!(timings.object_sweep, Duration::ZERO);
+
+        Ok(())
+    }
 }
@@ -34,6 +34,9 @@ use schemars::schema_for;
 use serde::{Deserialize, Serialize};
 
 use crate::bootc_composefs::delete::delete_composefs_deployment;
+use crate::bootc_composefs::gc::{
+    GcLockMode, GcOptions, GcRoots, QuarantinePolicy, composefs_gc, composefs_gc_target,
+};
 use crate::bootc_composefs::soft_reboot::{prepare_soft_reboot_composefs, reset_soft_reboot};
 use crate::bootc_composefs::{
     digest::{compute_composefs_digest, new_temp_composefs_repo},
@@ -46,6 +49,7 @@ use crate::bootc_composefs::{
 use crate::deploy::{MergeState, RequiredHostSpec};
 use crate::podstorage::set_additional_image_store;
 use crate::progress_jsonl::{ProgressWriter, RawProgressFd};
+use crate::spec::Bootloader;
 use crate::spec::Host;
 use crate::spec::ImageReference;
 use crate::status::get_host;
@@ -614,6 +618,28 @@ pub(crate) enum InternalsOpts {
         #[clap(long)]
         merge: bool,
     },
+    /// Garbage collect the composefs store of a system which isn't booted, e.g. from a rescue
+    /// environment
+    ComposefsGc {
+        /// Path to the physical root of the system
+        sysroot: Utf8PathBuf,
+        /// Path to the directory holding the bootloader entries of the system: `boot` under the
+        /// physical root for grub, the ESP for systemd-boot
+        #[clap(long)]
+        boot_dir: Utf8PathBuf,
+        /// Bootloader of the system
+        #[clap(long, value_enum)]
+        bootloader: Bootloader,
+        /// Protect this deployment besides those with bootloader entries
+        #[clap(long = "protect")]
+        protect: Vec<String>,
+        /// Fail unless this splitstream exists in the repository
+        #[clap(long = "stream")]
+        streams: Vec<String>,
+        /// Only report what would be cleaned up
+        #[clap(long)]
+        dry_run: bool,
+    },
     #[cfg(feature = "docgen")]
     /// Dump CLI structure as JSON for documentation generation
     DumpCliJson,
@@ -761,6 +787,24 @@ pub(crate) enum Opt {
     #[clap(hide = true)]
     DeleteDeployment {
         depl_id: String,
+        /// Move orphaned images to a quarantine area instead of deleting them, and only delete
+        /// them once they have been quarantined for this many seconds
+        #[clap(long)]
+        quarantine_grace_secs: Option<u64>,
+        /// Fail instead of waiting if another garbage collection is already running
+        #[clap(long)]
+        no_wait: bool,
+        /// Leave orphaned deployments alone if they were modified less than this many seconds ago
+        #[clap(long, default_value_t = 0)]
+        min_age_secs: u64,
+        /// Delete the EROFS image and state directory of a deployment which has no bootloader
+        /// entry left, e.g. after an interrupted deletion, without looking for other orphans
+        #[clap(long, conflicts_with_all = ["quarantine_grace_secs", "min_age_secs"])]
+        reap: bool,
+        /// Never collect this deployment, even if it has no bootloader entry left, e.g. to keep a
+        /// rollback target around
+        #[clap(long = "pin")]
+        pinned: Vec<String>,
     },
 }
 
@@ -1839,6 +1883,44 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
 
                 Ok(())
             }
+            InternalsOpts::ComposefsGc {
+                sysroot,
+                boot_dir,
+                bootloader,
+                protect,
+                streams,
+                dry_run,
+            } => {
+                let physical_root = Dir::open_ambient_dir(&sysroot, cap_std::ambient_authority())
+                    .with_context(|| format!("Opening {sysroot}"))?;
+                let boot_dir = Dir::open_ambient_dir(&boot_dir, cap_std::ambient_authority())
+                    .with_context(|| format!("Opening {boot_dir}"))?;
+                let run = Dir::open_ambient_dir("/run", cap_std::ambient_authority())
+                    .context("Opening /run")?;
+
+                let storage =
+                    Storage::new_composefs_offline(physical_root, sysroot, boot_dir, &run)?;
+
+                let roots = GcRoots::Offline {
+                    bootloader,
+                    verities: &protect,
+                    streams: &streams,
+                };
+
+                let gc_opts = GcOptions {
+                    quarantine: QuarantinePolicy::Disabled,
+                    lock: GcLockMode::Wait,
+                    min_age: std::time::Duration::ZERO,
+                    dry_run,
+                    stop: Default::default(),
+                    pinned: vec![],
+                };
+
+                let report = composefs_gc(&storage, roots, gc_opts).await?;
+                println!("{report}");
+
+                Ok(())
+            }
             InternalsOpts::PrepSoftReboot {
                 deployment,
                 reboot,
@@ -1902,14 +1984,51 @@ async fn run_from_opt(opt: Opt) -> Result<()> {
             }
         }
 
-        Opt::DeleteDeployment { depl_id } => {
+        Opt::DeleteDeployment {
+            depl_id,
+            quarantine_grace_secs,
+            no_wait,
+            min_age_secs,
+            reap,
+            pinned,
+        } => {
             let storage = &get_storage().await?;
             match storage.kind()? {
                 BootedStorageKind::Ostree(_) => {
                     anyhow::bail!("DeleteDeployment is only supported for composefs backend")
                 }
                 BootedStorageKind::Composefs(booted_cfs) => {
-                    delete_composefs_deployment(&depl_id, storage, &booted_cfs).await
+                    let lock = match no_wait {
+                        true => GcLockMode::FailFast,
+                        false => GcLockMode::Wait,
+                    };
+
+                    if reap {
+                        let report =
+                            composefs_gc_target(storage, &booted_cfs, &depl_id, lock, &pinned)
+                                .await?;
+                        println!("{report}");
+
+                        return Ok(());
+                    }
+
+                    let quarantine = match quarantine_grace_secs {
+                        Some(secs) => QuarantinePolicy::Enabled {
+                            grace: std::time::Duration::from_secs(secs),
+                        },
+                        None => QuarantinePolicy::Disabled,
+                    };
+
+                    let gc_opts = GcOptions {
+                        quarantine,
+                        lock,
+                        min_age: std::time::Duration::from_secs(min_age_secs),
+                        dry_run: false,
+                        stop: Default::default(),
+                        pinned,
+                    };
+
+                    delete_composefs_deployment(&depl_id, storage, &booted_cfs, gc_opts).await
                 }
             }
         }
@@ -2058,6 +2177,77 @@ mod tests {
         assert_eq!(args.as_slice(), ["container", "image", "pull"]);
     }
 
+    #[test]
+    fn test_parse_composefs_gc() {
+        let o = Opt::parse_including_static([
+            "bootc",
+            "internals",
+            "composefs-gc",
+            "/mnt/sysroot",
+            "--boot-dir=/mnt/esp",
+            "--bootloader=systemd",
+            "--protect=abc",
+            "--protect=def",
+        ]);
+
+        let (sysroot, boot_dir, bootloader, protect, streams, dry_run) = match o {
+            Opt::Internals(InternalsOpts::ComposefsGc {
+                sysroot,
+                boot_dir,
+                bootloader,
+                protect,
+                streams,
+                dry_run,
+            }) => (sysroot, boot_dir, bootloader, protect, streams, dry_run),
+            o => panic!("unexpected {o:?}"),
+        };
+
+        assert_eq!(sysroot, "/mnt/sysroot");
+        assert_eq!(boot_dir, "/mnt/esp");
+        assert_eq!(bootloader, Bootloader::Systemd);
+        assert_eq!(protect, ["abc", "def"]);
+        assert!(streams.is_empty());
+        assert!(!dry_run);
+
+        // The bootloader of an offline store can't be detected from the running system
+        assert!(
+            Opt::try_parse_from([
+                "bootc",
+                "internals",
+                "composefs-gc",
+                "/mnt/sysroot",
+                "--boot-dir=/mnt/esp",
+            ])
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_parse_delete_deployment() {
+        assert!(matches!(
+            Opt::parse_including_static(["bootc", "delete-deployment", "abc", "--reap"]),
+            Opt::DeleteDeployment { depl_id, reap: true, .. } if depl_id == "abc"
+        ));
+
+        assert!(matches!(
+            Opt::parse_including_static([
+                "bootc",
+                "delete-deployment",
+                "abc",
+                "--pin=def",
+                "--pin=ghi",
+            ]),
+            Opt::DeleteDeployment { pinned, .. } if pinned == ["def", "ghi"]
+        ));
+
+        // Reaping a single deployment doesn't quarantine or look at orphans
+        for arg in ["--quarantine-grace-secs=60", "--min-age-secs=60"] {
+            assert!(
+                Opt::try_parse_from(["bootc", "delete-deployment", "abc", "--reap", arg]).is_err()
+            );
+        }
+    }
+
     #[test]
     fn test_generate_completion_scripts_contain_commands() {
         use clap_complete::aot::{Shell, generate};
@@ -2771,6 +2771,31 @@ mod tests {
         assert_eq!(c.block_opts.device, "/dev/vda");
     }
 
+    #[test]
+    fn install_opts_karg_roundtrip() {
+        // Kernel arguments are serialized as an ordered list of key/value entries
+        let c: InstallToDiskOpts = serde_json::from_value(serde_json::json!({
+            "device": "/dev/vda",
+            "karg": [
+                [{ "key": "nosmt", "value": null }],
+                [
+                    { "key": "console", "value": "ttyS0,115200n8" },
+                    { "key": "quiet", "value": null },
+                ],
+            ],
+        }))
+        .unwrap();
+
+        let kargs = c.config_opts.karg.as_deref().unwrap();
+        assert_eq!(kargs.len(), 2);
+        assert_eq!(&*kargs[0], "nosmt");
+        assert_eq!(&*kargs[1], "console=ttyS0,115200n8 quiet");
+
+        let json = serde_json::to_value(&c).unwrap();
+        let roundtrip: InstallToDiskOpts = serde_json::from_value(json).unwrap();
+        assert_eq!(roundtrip.config_opts, c.config_opts);
+    }
+
     #[test]
     fn test_mountspec() {
         let mut ms = MountSpec::new("/dev/vda4", "/boot");
@@ -338,6 +338,29 @@ impl Storage {
         })
     }
 
+    /// Create a new storage accessor for a composefs system which isn't booted, e.g. to do
+    /// maintenance on it from a rescue environment.
+    ///
+    /// `boot_dir` is where its bootloader entries are: `boot` under the physical root for grub,
+    /// or the ESP for systemd-boot.
+    pub(crate) fn new_composefs_offline(
+        physical_root: Dir,
+        physical_root_path: Utf8PathBuf,
+        boot_dir: Dir,
+        run: &Dir,
+    ) -> Result<Self> {
+        Ok(Self {
+            physical_root,
+            physical_root_path,
+            run: run.try_clone()?,
+            boot_dir: Some(boot_dir),
+            esp: None,
+            ostree: Default::default(),
+            composefs: Default::default(),
+            imgstore: Default::default(),
+        })
+    }
+
     /// Returns `boot_dir` if it exists
     pub(crate) fn require_boot_dir(&self) -> Result<&Dir> {
         self.boot_dir